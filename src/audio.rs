@@ -0,0 +1,360 @@
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+#[cfg(feature = "audio")]
+use std::collections::VecDeque;
+#[cfg(feature = "audio")]
+use std::sync::{Arc, Mutex};
+#[cfg(feature = "audio")]
+use std::sync::mpsc::{self, Sender};
+#[cfg(feature = "audio")]
+use std::thread::{self, JoinHandle};
+#[cfg(feature = "audio")]
+use cpal::{Stream, StreamConfig, SampleRate};
+#[cfg(feature = "audio")]
+use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use crate::config::AudioBackend;
+use crate::sound::{SAMPLE_RATE, CHANNELS};
+
+#[derive(Debug)]
+pub enum AudioError {
+    Io(io::Error),
+    NoDevice,
+    Stream(String),
+    Unsupported,
+}
+
+impl fmt::Display for AudioError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AudioError::Io(err) => write!(f, "{}", err),
+            AudioError::NoDevice => write!(f, "No audio output device available"),
+            AudioError::Stream(err) => write!(f, "Could not open the audio stream: {}", err),
+            AudioError::Unsupported => write!(f, "This build doesn't include audio output support"),
+        }
+    }
+}
+
+impl std::error::Error for AudioError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            AudioError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for AudioError {
+    fn from(err: io::Error) -> Self {
+        AudioError::Io(err)
+    }
+}
+
+// Receives the interleaved stereo samples generated by the APU
+pub trait AudioSink: Send {
+    // Rate the APU has to produce the samples at
+    fn sample_rate(&self) -> u32;
+
+    fn push_samples(&mut self, samples: &[f32]);
+
+    // Samples waiting to be played, for sinks that play in real time.
+    // The emulation speed can be synced to it instead of a timer
+    fn buffered(&self) -> Option<usize> {
+        None
+    }
+
+    // Fill level the emulator should try to keep the buffer at
+    fn target_buffered(&self) -> usize {
+        0
+    }
+
+    // Called when the emulator closes, for sinks that have to finalize their output
+    fn finish(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// How far the resampling ratio can be nudged to keep the buffer at its target,
+// small enough for the pitch change to be inaudible
+pub const MAX_RATE_DELTA: f32 = 0.005;
+// Past this many times the target, the oldest samples get dropped
+pub const MAX_BUFFERED_FACTOR: usize = 4;
+
+// Dynamic rate control: produce slightly more samples when the buffer is
+// under its target and slightly less when it's over, absorbing the drift
+// between the emulated clock and the audio device clock
+pub fn rate_adjustment(buffered: usize, target: usize) -> f32 {
+    if target == 0 {
+        return 1.0;
+    }
+    let fill = (buffered as f32 / (target as f32 * 2.0)).min(1.0);
+    1.0 + MAX_RATE_DELTA * (1.0 - 2.0 * fill)
+}
+
+pub fn create_sink(backend: &AudioBackend) -> Result<Box<dyn AudioSink>, AudioError> {
+    Ok(match backend {
+        AudioBackend::Null => Box::new(NullSink::new()),
+        AudioBackend::Wav(filename) => Box::new(WavSink::create(filename)?),
+        #[cfg(feature = "audio")]
+        AudioBackend::Cpal => Box::new(CpalSink::new()?),
+        #[cfg(not(feature = "audio"))]
+        AudioBackend::Cpal => return Err(AudioError::Unsupported),
+    })
+}
+
+pub struct NullSink {
+    sample_rate: u32,
+}
+
+impl NullSink {
+    pub fn new() -> Self {
+        Self {
+            sample_rate: SAMPLE_RATE,
+        }
+    }
+}
+
+impl Default for NullSink {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AudioSink for NullSink {
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn push_samples(&mut self, _samples: &[f32]) {}
+}
+
+const WAV_HEADER_SIZE: u32 = 44;
+
+// 16 bit PCM stereo, the sizes in the header get filled in by `finish`
+pub struct WavSink<W: Write + Seek + Send> {
+    writer: Option<W>,
+    data_size: u32,
+    finished: bool,
+}
+
+impl WavSink<BufWriter<File>> {
+    pub fn create(filename: &str) -> io::Result<Self> {
+        Self::new(BufWriter::new(File::create(filename)?))
+    }
+}
+
+impl<W: Write + Seek + Send> WavSink<W> {
+    pub fn new(mut writer: W) -> io::Result<Self> {
+        Self::write_header(&mut writer, 0)?;
+        Ok(Self {
+            writer: Some(writer),
+            data_size: 0,
+            finished: false,
+        })
+    }
+
+    fn write_header(writer: &mut W, data_size: u32) -> io::Result<()> {
+        let channels = CHANNELS as u16;
+        let block_align = channels * 2;
+        writer.write_all(b"RIFF")?;
+        writer.write_all(&(WAV_HEADER_SIZE - 8 + data_size).to_le_bytes())?;
+        writer.write_all(b"WAVEfmt ")?;
+        writer.write_all(&16u32.to_le_bytes())?;
+        // PCM
+        writer.write_all(&1u16.to_le_bytes())?;
+        writer.write_all(&channels.to_le_bytes())?;
+        writer.write_all(&SAMPLE_RATE.to_le_bytes())?;
+        writer.write_all(&(SAMPLE_RATE * block_align as u32).to_le_bytes())?;
+        writer.write_all(&block_align.to_le_bytes())?;
+        writer.write_all(&16u16.to_le_bytes())?;
+        writer.write_all(b"data")?;
+        writer.write_all(&data_size.to_le_bytes())?;
+        Ok(())
+    }
+
+    pub fn into_inner(mut self) -> io::Result<W> {
+        self.finish()?;
+        Ok(self.writer.take().unwrap())
+    }
+}
+
+impl<W: Write + Seek + Send> AudioSink for WavSink<W> {
+    fn sample_rate(&self) -> u32 {
+        SAMPLE_RATE
+    }
+
+    fn push_samples(&mut self, samples: &[f32]) {
+        let writer = match self.writer.as_mut() {
+            Some(writer) if !self.finished => writer,
+            _ => return,
+        };
+        for sample in samples {
+            let sample = (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+            if let Err(err) = writer.write_all(&sample.to_le_bytes()) {
+                eprintln!("Could not write audio samples: {}", err);
+                self.finished = true;
+                return;
+            }
+            self.data_size = self.data_size.saturating_add(2);
+        }
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        let writer = match self.writer.as_mut() {
+            Some(writer) if !self.finished => writer,
+            _ => return Ok(()),
+        };
+        self.finished = true;
+        writer.seek(SeekFrom::Start(0))?;
+        Self::write_header(writer, self.data_size)?;
+        writer.seek(SeekFrom::End(0))?;
+        writer.flush()
+    }
+}
+
+impl<W: Write + Seek + Send> Drop for WavSink<W> {
+    fn drop(&mut self) {
+        if let Err(err) = self.finish() {
+            eprintln!("Could not finish the WAV file: {}", err);
+        }
+    }
+}
+
+#[cfg(feature = "audio")]
+pub struct CpalSink {
+    buffer: Arc<Mutex<VecDeque<f32>>>,
+    sample_rate: u32,
+    target_buffered: usize,
+    // cpal streams can't be moved to another thread, so the stream lives in its
+    // own thread until the sink is dropped and the sink can be sent anywhere
+    stop: Option<Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+#[cfg(feature = "audio")]
+impl CpalSink {
+    pub fn new() -> Result<Self, AudioError> {
+        let buffer = Arc::new(Mutex::new(VecDeque::new()));
+        let stream_buffer = buffer.clone();
+        let (ready_sender, ready) = mpsc::channel();
+        let (stop, stopped) = mpsc::channel::<()>();
+        let thread = thread::spawn(move || {
+            let stream = match Self::open_stream(stream_buffer) {
+                Ok((stream, sample_rate)) => {
+                    let _ = ready_sender.send(Ok(sample_rate));
+                    stream
+                },
+                Err(err) => {
+                    let _ = ready_sender.send(Err(err));
+                    return;
+                },
+            };
+            // Keeps playing until the sink goes away
+            let _ = stopped.recv();
+            drop(stream);
+        });
+        let sample_rate = ready.recv()
+            .map_err(|_| AudioError::Stream("The audio thread stopped".to_string()))??;
+
+        Ok(Self {
+            buffer,
+            sample_rate,
+            // 50ms of latency
+            target_buffered: (sample_rate as usize / 20) * CHANNELS,
+            stop: Some(stop),
+            thread: Some(thread),
+        })
+    }
+
+    fn open_stream(buffer: Arc<Mutex<VecDeque<f32>>>) -> Result<(Stream, u32), AudioError> {
+        let host = cpal::default_host();
+        let device = host.default_output_device().ok_or(AudioError::NoDevice)?;
+        let supported_configs: Vec<_> = device.supported_output_configs()
+            .map_err(|err| AudioError::Stream(err.to_string()))?
+            .filter(|config| config.channels() as usize == CHANNELS)
+            .collect();
+        let supported_config = supported_configs.iter()
+            .find(|config| (config.min_sample_rate().0..=config.max_sample_rate().0).contains(&SAMPLE_RATE))
+            .or(supported_configs.first())
+            .ok_or(AudioError::NoDevice)?
+            .clone();
+        let sample_rate = SAMPLE_RATE.clamp(supported_config.min_sample_rate().0, supported_config.max_sample_rate().0);
+        let config: StreamConfig = supported_config.with_sample_rate(SampleRate(sample_rate)).into();
+
+        let stream = device.build_output_stream(&config, move |data: &mut [f32], _| {
+            let mut buffer = buffer.lock().unwrap();
+            for sample in data.iter_mut() {
+                // Play silence if the emulator falls behind
+                *sample = buffer.pop_front().unwrap_or(0.0);
+            }
+        }, |err| eprintln!("An error occurred on the audio stream: {}", err))
+            .map_err(|err| AudioError::Stream(err.to_string()))?;
+        stream.play().map_err(|err| AudioError::Stream(err.to_string()))?;
+
+        Ok((stream, sample_rate))
+    }
+}
+
+#[cfg(feature = "audio")]
+impl Drop for CpalSink {
+    fn drop(&mut self) {
+        self.stop.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(feature = "audio")]
+impl AudioSink for CpalSink {
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn push_samples(&mut self, samples: &[f32]) {
+        let mut buffer = self.buffer.lock().unwrap();
+        buffer.extend(samples);
+        // Drop the oldest samples if nobody is waiting for the buffer to drain
+        let max_buffered = self.target_buffered * MAX_BUFFERED_FACTOR;
+        if buffer.len() > max_buffered {
+            let excess = buffer.len() - max_buffered;
+            buffer.drain(..excess);
+        }
+    }
+
+    fn buffered(&self) -> Option<usize> {
+        Some(self.buffer.lock().unwrap().len())
+    }
+
+    fn target_buffered(&self) -> usize {
+        self.target_buffered
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_wav_sink() {
+        let mut sink = WavSink::new(Cursor::new(Vec::new())).unwrap();
+        sink.push_samples(&[0.0, 1.0, -1.0, 0.5]);
+        let data = sink.into_inner().unwrap().into_inner();
+        assert_eq!(data.len(), WAV_HEADER_SIZE as usize + 8);
+        assert_eq!(&data[0..4], b"RIFF");
+        assert_eq!(u32::from_le_bytes([data[4], data[5], data[6], data[7]]), 36 + 8);
+        assert_eq!(u32::from_le_bytes([data[40], data[41], data[42], data[43]]), 8);
+        assert_eq!(i16::from_le_bytes([data[46], data[47]]), i16::MAX);
+        assert_eq!(i16::from_le_bytes([data[48], data[49]]), -i16::MAX);
+    }
+
+    #[test]
+    fn test_rate_adjustment() {
+        assert_eq!(rate_adjustment(100, 100), 1.0);
+        assert_eq!(rate_adjustment(0, 100), 1.0 + MAX_RATE_DELTA);
+        assert_eq!(rate_adjustment(200, 100), 1.0 - MAX_RATE_DELTA);
+        assert_eq!(rate_adjustment(1000, 100), 1.0 - MAX_RATE_DELTA);
+        assert_eq!(rate_adjustment(1000, 0), 1.0);
+    }
+}
@@ -1,6 +1,31 @@
+use rmg_001::config::CliOptions;
+use rmg_001::disasm::print_rom;
+#[cfg(feature = "frontend")]
 use rmg_001::render::start_eventloop;
 
+#[cfg(feature = "frontend")]
+fn run(options: CliOptions) {
+    start_eventloop(options);
+}
+
+#[cfg(not(feature = "frontend"))]
+fn run(_options: CliOptions) {
+    eprintln!("This build doesn't include the frontend, only --disasm is available");
+    std::process::exit(1);
+}
+
 fn main() -> std::io::Result<()> {
-    start_eventloop();
+    let args: Vec<String> = std::env::args().collect();
+    let options = match CliOptions::parse(&args) {
+        Ok(options) => options,
+        Err(err) => {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        },
+    };
+    if let Some(range) = options.disasm {
+        return print_rom(&options.rom_path, &range);
+    }
+    run(options);
     Ok(())
 }
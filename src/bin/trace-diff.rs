@@ -0,0 +1,51 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::process;
+use rmg_001::trace::diff;
+
+const USAGE: &str = "Usage: trace-diff <trace> <reference>
+
+Compares an instruction trace against a reference log, like the ones from
+Gameboy Doctor, and reports the first line where they diverge.";
+
+fn open(filename: &str) -> BufReader<File> {
+    match File::open(filename) {
+        Ok(file) => BufReader::new(file),
+        Err(err) => {
+            eprintln!("Could not open {}: {}", filename, err);
+            process::exit(2);
+        },
+    }
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() != 3 {
+        eprintln!("{}", USAGE);
+        process::exit(2);
+    }
+    let divergence = match diff(open(&args[1]), open(&args[2])) {
+        Ok(divergence) => divergence,
+        Err(err) => {
+            eprintln!("Could not read the traces: {}", err);
+            process::exit(2);
+        },
+    };
+    let divergence = match divergence {
+        Some(divergence) => divergence,
+        None => {
+            println!("No differences");
+            return;
+        },
+    };
+    println!("First difference at line {}", divergence.line);
+    if let Some(previous) = divergence.previous {
+        println!("  previous:  {}", previous);
+    }
+    println!("  trace:     {}", divergence.trace.as_deref().unwrap_or("<end of file>"));
+    println!("  reference: {}", divergence.reference.as_deref().unwrap_or("<end of file>"));
+    if !divergence.fields.is_empty() {
+        println!("  different: {}", divergence.fields.join(", "));
+    }
+    process::exit(1);
+}
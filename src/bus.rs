@@ -1,7 +1,10 @@
-use std::env;
+use std::cell::Cell;
 use std::ops::RangeInclusive;
 use crate::utils::join_bytes;
-use crate::rom::{ROM, load_rom};
+use crate::config::Config;
+use crate::rom::{ROM, BootRom, load_rom};
+use crate::model::Model;
+use crate::palette::Palettes;
 use crate::ram::{RAM, DMGRAM, CGBRAM, WRAM_BANK_SELECT_ADDRESS};
 use crate::ppu::{
     PPU,
@@ -11,11 +14,14 @@ use crate::ppu::{
 use crate::timer::Timer;
 use crate::joypad::{Joypad, JOYPAD_ADDRESS};
 use crate::sound::Sound;
+use crate::serial::Serial;
+use crate::debugger::{Access, Watchpoint, WatchHit};
 use crate::interrupts::{
     Interrupts,
     INTERRUPT_ENABLE_ADDRESS,
     INTERRUPT_FLAG_ADDRESS,
 };
+use crate::savestate::{SaveState, StateWriter, StateReader, StateError};
 
 pub const BANK_ZERO: RangeInclusive<u16>                 = 0x0000..=0x3FFF;
 pub const BANK_SWITCHABLE: RangeInclusive<u16>           = 0x4000..=0x7FFF;
@@ -29,6 +35,7 @@ pub const NOT_USABLE: RangeInclusive<u16>                = 0xFEA0..=0xFEFF;
 pub const IO_REGISTERS: RangeInclusive<u16>              = 0xFF00..=0xFF7F;
 pub const HIGH_RAM: RangeInclusive<u16>                  = 0xFF80..=0xFFFE;
 pub const PREPARE_SPEED_SWITCH_ADDRESS: u16              = 0xFF4D;
+pub const BOOT_ROM_DISABLE_ADDRESS: u16                  = 0xFF50;
 
 enum MemoryMap {
     BankZero,
@@ -53,10 +60,19 @@ pub struct Bus {
     pub joypad: Joypad,
     pub timer: Timer,
     pub sound: Sound,
+    pub serial: Serial,
     pub interrupts: Interrupts,
+    pub model: Model,
+    // The game runs with the Gameboy Color features, only on CGB models
     pub cgb_mode: bool,
     pub double_speed_mode: bool,
     pub prepare_double_speed_mode: bool,
+    boot_rom: Option<BootRom>,
+    // Until the game writes to FF50
+    boot_rom_mapped: bool,
+    watchpoints: Vec<Watchpoint>,
+    // First watchpoint triggered since the last time it was taken
+    watch_hit: Cell<Option<WatchHit>>,
 }
 
 impl Bus {
@@ -67,50 +83,99 @@ impl Bus {
             eprintln!("Please, specify a ROM file");
             std::process::exit(1);
         }
-        let rom = match load_rom(&args.get(1).unwrap_or(&"".to_string())) {
+        let config = Config::from_env();
+        let rom = match load_rom(args.get(1).unwrap_or(&"".to_string()), &config) {
             Ok(rom) => rom,
             Err(err) => {
                 eprintln!("Could not read ROM: {}", err);
                 std::process::exit(1);
             },
         };
+        Self::with_rom(rom, &config)
+    }
+
+    pub fn with_rom(rom: Box<dyn ROM>, config: &Config) -> Self {
         let info = rom.info().clone();
-        let force_dmg_mode = !env::var("FORCE_DMG").is_err();
-        let cgb_mode = (info.cgb_features() || info.cgb_only()) && !force_dmg_mode;
+        let model = config.model.unwrap_or_else(|| Model::detect(&info));
+        let cgb_mode = model.is_cgb() && (info.cgb_features() || info.cgb_only());
+        let boot_rom = config.boot_rom.clone().filter(|boot_rom| {
+            if boot_rom.is_cgb() != model.is_cgb() {
+                eprintln!("The boot ROM is for a different Gameboy model, starting without it");
+            }
+            boot_rom.is_cgb() == model.is_cgb()
+        });
         let mut bus = Self {
             data: [0x00; 0x10000],
             rom,
-            ram: match cgb_mode {
-                true => Box::new(CGBRAM::new()),
+            ram: match model.is_cgb() {
+                true => Box::new(CGBRAM::new(cgb_mode)),
                 false => Box::new(DMGRAM::new()),
             },
-            ppu: PPU::new(cgb_mode),
+            ppu: PPU::new(model, cgb_mode),
             joypad: Joypad::new(),
             timer: Timer::new(),
-            sound: Sound::new(),
+            sound: Sound::new(config),
+            serial: Serial::new(cgb_mode),
             interrupts: Interrupts::new(),
+            model,
             cgb_mode,
             double_speed_mode: false,
             prepare_double_speed_mode: false,
+            boot_rom_mapped: boot_rom.is_some(),
+            boot_rom,
+            watchpoints: Vec::new(),
+            watch_hit: Cell::new(None),
         };
 
+        if let Some(palettes) = config.dmg_palettes {
+            bus.ppu.set_dmg_palettes(palettes);
+        }
+        bus.ppu.set_color_correction(config.color_correction);
+
+        // A CGB boot ROM picks the palette of DMG games by itself
+        if model.is_cgb() && !cgb_mode {
+            match bus.boot_rom_mapped {
+                true => bus.ppu.set_cram_unlocked(true),
+                false => {
+                    let palette = config.compat_palette.unwrap_or_else(|| Palettes::for_game(&info));
+                    bus.ppu.set_compat_palette(&palette);
+                },
+            };
+        }
+
+        // The boot ROM sets everything up by itself
+        if bus.boot_rom_mapped {
+            return bus;
+        }
+
         // Hardware registers after the bootrom
         bus.write(0xFF00, 0xCF);
         bus.write(0xFF01, 0x00);
-        bus.write(0xFF02, 0x7E);
+        bus.write(0xFF02, match model.is_cgb() {
+            true => 0x7F,
+            false => 0x7E,
+        });
         bus.write(0xFF04, 0x18);
+        // The boot ROM takes the same number of cycles every time on these
+        if matches!(model, Model::DMG | Model::MGB) {
+            bus.timer.set_div(0xABCC);
+        }
         bus.write(0xFF05, 0x00);
         bus.write(0xFF06, 0x00);
         bus.write(0xFF07, 0xF8);
         bus.write(0xFF0F, 0xE1);
 
+        // The APU has to be turned on before the rest of its registers can be written
+        bus.write(0xFF26, 0xF1);
+        bus.write(0xFF24, 0x77);
+        bus.write(0xFF25, 0xF3);
+
         bus.write(0xFF40, 0x91);
         bus.write(0xFF41, 0x81);
         bus.write(0xFF42, 0x00);
         bus.write(0xFF43, 0x00);
         bus.write(0xFF44, 0x91);
         bus.write(0xFF45, 0x00);
-        bus.write(0xFF46, 0xFF);
         bus.write(0xFF47, 0xFC);
 
         bus.write(0xFF4A, 0x00);
@@ -120,6 +185,10 @@ impl Bus {
         bus
     }
 
+    pub fn is_boot_rom_mapped(&self) -> bool {
+        self.boot_rom_mapped && self.boot_rom.is_some()
+    }
+
     fn map_address(address: u16) -> MemoryMap {
         match address {
             0x0000..=0x3FFF => MemoryMap::BankZero,
@@ -138,7 +207,20 @@ impl Bus {
     }
 
     pub fn read(&self, address: u16) -> u8 {
+        let data = self.peek(address);
+        if !self.watchpoints.is_empty() {
+            self.check_watchpoints(Access::Read, address, data);
+        }
+        data
+    }
+
+    // Same as `read` but invisible to the watchpoints, for debuggers and opcode fetches
+    pub fn peek(&self, address: u16) -> u8 {
         match Bus::map_address(address) {
+            MemoryMap::BankZero if self.boot_rom_mapped => match self.boot_rom.as_ref().and_then(|boot_rom| boot_rom.read(address)) {
+                Some(data) => data,
+                None => self.rom.read(address),
+            },
             MemoryMap::BankZero | MemoryMap::BankSwitchable | MemoryMap::ExternalRam => self.rom.read(address),
             MemoryMap::WorkRam1 | MemoryMap::WorkRam2 | MemoryMap::EchoRam => self.ram.read(address),
             MemoryMap::VideoRam => self.ppu.read_vram_external(address),
@@ -159,6 +241,8 @@ impl Bus {
                     return self.sound.get_register(address);
                 } else if Timer::is_io_register(address) {
                     return self.timer.get_register(address);
+                } else if Serial::is_io_register(address) {
+                    return self.serial.get_register(address);
                 } else if address == JOYPAD_ADDRESS {
                     return self.joypad.read(self.data[address as usize]);
                 }
@@ -169,11 +253,97 @@ impl Bus {
         }
     }
 
+    pub fn watchpoints(&self) -> &[Watchpoint] {
+        &self.watchpoints
+    }
+
+    pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) {
+        self.watchpoints.push(watchpoint);
+    }
+
+    pub fn remove_watchpoint(&mut self, index: usize) -> Option<Watchpoint> {
+        match index < self.watchpoints.len() {
+            true => Some(self.watchpoints.remove(index)),
+            false => None,
+        }
+    }
+
+    pub fn clear_watchpoints(&mut self) {
+        self.watchpoints.clear();
+        self.watch_hit.set(None);
+    }
+
+    pub fn take_watch_hit(&self) -> Option<WatchHit> {
+        self.watch_hit.take()
+    }
+
+    fn check_watchpoints(&self, access: Access, address: u16, value: u8) {
+        if self.watch_hit.get().is_some() {
+            return;
+        }
+        let index = self.watchpoints.iter()
+            .position(|watchpoint| watchpoint.matches(self, access, address, value));
+        if let Some(index) = index {
+            self.watch_hit.set(Some(WatchHit { index, access, address, value }));
+        }
+    }
+
+    // Bank currently mapped at the given address, 0 for unbanked memory
+    pub fn bank(&self, address: u16) -> u16 {
+        match Bus::map_address(address) {
+            MemoryMap::BankZero | MemoryMap::BankSwitchable | MemoryMap::ExternalRam => self.rom.bank(address),
+            MemoryMap::WorkRam2 => self.ram.bank() as u16,
+            MemoryMap::VideoRam => (self.ppu.get_vram_bank() & 1) as u16,
+            _ => 0,
+        }
+    }
+
+    // Reads `bank:address` whichever bank is mapped right now. The bank only matters for switchable ROM,
+    // cartridge RAM and the second half of the work RAM, anything else reads like `peek`.
+    // None if there is no such bank
+    pub fn peek_banked(&self, bank: u16, address: u16) -> Option<u8> {
+        let bank = bank as usize;
+        match Bus::map_address(address) {
+            MemoryMap::BankZero => self.rom.data().get(address as usize).copied(),
+            MemoryMap::BankSwitchable => self.rom.data().get(bank * 0x4000 + (address - 0x4000) as usize).copied(),
+            MemoryMap::ExternalRam => self.rom.ram().get(bank * 0x2000 + (address - 0xA000) as usize).copied(),
+            MemoryMap::WorkRam2 if bank == 0 => None,
+            MemoryMap::WorkRam2 => self.ram.data().get(bank * 0x1000 + (address - 0xD000) as usize).copied(),
+            _ => Some(self.peek(address)),
+        }
+    }
+
+    // Same as `peek_banked` for writing, the ROM can't be written.
+    // Returns false if there is no such bank
+    pub fn poke_banked(&mut self, bank: u16, address: u16, data: u8) -> bool {
+        let bank = bank as usize;
+        let byte = match Bus::map_address(address) {
+            MemoryMap::BankZero | MemoryMap::BankSwitchable => None,
+            MemoryMap::ExternalRam => self.rom.ram_mut().get_mut(bank * 0x2000 + (address - 0xA000) as usize),
+            MemoryMap::WorkRam2 if bank == 0 => None,
+            MemoryMap::WorkRam2 => self.ram.data_mut().get_mut(bank * 0x1000 + (address - 0xD000) as usize),
+            _ => {
+                self.write(address, data);
+                return true;
+            },
+        };
+        match byte {
+            Some(byte) => {
+                *byte = data;
+                true
+            },
+            None => false,
+        }
+    }
+
     pub fn read_16bit(&self, address: u16) -> u16 {
         join_bytes(self.read(address.wrapping_add(1)), self.read(address))
     }
 
     pub fn write(&mut self, address: u16, data: u8) {
+        if !self.watchpoints.is_empty() {
+            self.check_watchpoints(Access::Write, address, data);
+        }
         match Bus::map_address(address) {
             MemoryMap::BankZero | MemoryMap::BankSwitchable | MemoryMap::ExternalRam => self.rom.write(address, data),
             MemoryMap::WorkRam1 | MemoryMap::WorkRam2 | MemoryMap::EchoRam => self.ram.write(address, data),
@@ -206,9 +376,18 @@ impl Bus {
                     self.sound.set_register(address, data);
                 } else if Timer::is_io_register(address) {
                     self.timer.set_register(address, data);
+                } else if Serial::is_io_register(address) {
+                    self.serial.set_register(address, data);
                 } else if address == JOYPAD_ADDRESS {
                     let byte = self.data[address as usize];
                     self.data[address as usize] = (data & 0b11110000) | (byte & 0b00001111);
+                } else if address == BOOT_ROM_DISABLE_ADDRESS {
+                    // There is no way to map it back
+                    if data & 1 == 1 {
+                        self.boot_rom_mapped = false;
+                        self.ppu.set_cram_unlocked(false);
+                    }
+                    self.data[address as usize] = data;
                 } else {
                     self.data[address as usize] = data;
                 }
@@ -259,3 +438,40 @@ impl Bus {
         self.ppu.set_register(HDMA5_ADDRESS, 0xFF);
     }
 }
+
+impl SaveState for Bus {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_bool(self.cgb_mode);
+        state.write_bool(self.double_speed_mode);
+        state.write_bool(self.prepare_double_speed_mode);
+        state.write_bool(self.boot_rom_mapped);
+        state.write_bytes(&self.data);
+        self.rom.save_state(state);
+        self.ram.save_state(state);
+        self.ppu.save_state(state);
+        self.joypad.save_state(state);
+        self.timer.save_state(state);
+        self.sound.save_state(state);
+        self.serial.save_state(state);
+        self.interrupts.save_state(state);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        if state.read_bool()? != self.cgb_mode {
+            return Err(StateError::ModeMismatch);
+        }
+        self.double_speed_mode = state.read_bool()?;
+        self.prepare_double_speed_mode = state.read_bool()?;
+        self.boot_rom_mapped = state.read_bool()?;
+        state.read_bytes(&mut self.data)?;
+        self.rom.load_state(state)?;
+        self.ram.load_state(state)?;
+        self.ppu.load_state(state)?;
+        self.ppu.set_cram_unlocked(self.model.is_cgb() && self.is_boot_rom_mapped());
+        self.joypad.load_state(state)?;
+        self.timer.load_state(state)?;
+        self.sound.load_state(state)?;
+        self.serial.load_state(state)?;
+        self.interrupts.load_state(state)
+    }
+}
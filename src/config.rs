@@ -0,0 +1,407 @@
+use std::env;
+use crate::rtc::RtcMode;
+use crate::disasm::RomRange;
+use crate::debugger::parse_hex;
+use crate::trace::TraceOptions;
+use crate::rewind::RewindOptions;
+use crate::rom::BootRom;
+use crate::model::Model;
+use crate::palette::{Palettes, ColorCorrection, BUTTON_PALETTES, PRESETS, DEFAULT_COLORS, load_colors};
+
+pub const USAGE: &str = "Usage: rmg-001 [options] <rom file>
+
+Options:
+  --model <model>   Run on a `dmg`, `mgb`, `sgb`, `cgb` or `agb`, picked from the header by default
+  --force-dmg       Run Gameboy Color games in DMG mode, same as `--model dmg`
+  --palette <palette>
+                    Colors of DMG games: `default`, `green`, `grey`, `contrast` or a file
+                    with 4 RRGGBB hex colors, P switches between the presets while playing
+  --palette-bg <palette>, --palette-obj0 <palette>, --palette-obj1 <palette>
+                    Same, only for the background or one of the sprite palettes
+  --color-correction <mode>
+                    Gameboy Color colors as stored (`none`), mixed like on the `lcd`,
+                    or mixed with `gamma` correction
+  --compat-palette <buttons>
+                    Colors for DMG games on a CGB, like holding `up`, `left+a`, `right+b`...
+                    on the boot logo, picked from the game title by default
+  --boot-rom <file> Run a DMG, MGB, SGB or CGB boot ROM before the game
+  --audio <backend> Enable sound and play it through `cpal` or discard it with `null`
+  --wav <file>      Enable sound and record it to a WAV file
+  --sync <mode>     Pace the emulation with the `audio` output or a `timer`
+  --rtc-cycles      Advance the cartridge clock with the emulated cycles instead of the host clock
+  --cpu-logs        Trace every executed instruction to stdout, same as `--trace -`
+  --trace <file>    Write a trace of the executed instructions in Gameboy Doctor format
+  --trace-pc <start-end>
+                    Only trace instructions in this address range
+  --trace-bank <bank>
+                    Only trace instructions in this ROM bank
+  --trace-limit <count>
+                    Stop tracing after this many instructions
+  --trace-extra     Add the ROM bank, LY and the cycles into the line to the trace
+  --debug           Start paused in the debugger, F12 pauses into it while playing
+  --disasm <range>  Print the disassembly of [bank:]start[-end] from the ROM and exit
+  --gdb <port>      Wait for GDB connections on localhost:<port>
+  --play <file>     Play an input movie
+  --record <file>   Record an input movie from power on, or with --play, keep
+                    recording when the movie ends or a state slot is loaded
+  --rewind <seconds>
+                    How far back holding Backspace can rewind, 0 disables it (default 10)
+  --rewind-interval <frames>
+                    Frames between rewind snapshots (default 1)
+  --rewind-memory <MiB>
+                    Memory limit for the rewind snapshots (default 64)
+  --help            Show this message";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum AudioBackend {
+    Null,
+    Cpal,
+    Wav(String),
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum SyncMode {
+    // Follow the audio device clock, falls back to the timer if there is no audio output
+    Audio,
+    // Sleep for the exact duration of a frame
+    Timer,
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    // Detected from the ROM header when not set, `FORCE_DMG` sets it to DMG
+    pub model: Option<Model>,
+    // Colors for DMG games on a Gameboy Color instead of the ones the boot ROM would pick
+    pub compat_palette: Option<Palettes>,
+    // Colors for DMG games on the other models, depend on the model if not set
+    pub dmg_palettes: Option<Palettes>,
+    pub color_correction: ColorCorrection,
+    pub sound_enabled: bool,
+    pub audio_backend: AudioBackend,
+    pub sync_mode: SyncMode,
+    // Instruction trace, `CPU_LOGS` traces to stdout
+    pub trace: Option<TraceOptions>,
+    pub rtc_mode: RtcMode,
+    // Start paused in the debugger
+    pub debugger: bool,
+    // Without one the emulation starts in the state the boot ROM leaves behind
+    pub boot_rom: Option<BootRom>,
+}
+
+impl Config {
+    pub fn new() -> Self {
+        Self {
+            model: None,
+            compat_palette: None,
+            dmg_palettes: None,
+            color_correction: ColorCorrection::None,
+            sound_enabled: false,
+            audio_backend: AudioBackend::Null,
+            sync_mode: SyncMode::Audio,
+            trace: None,
+            rtc_mode: RtcMode::WallClock,
+            debugger: false,
+            boot_rom: None,
+        }
+    }
+
+    pub fn from_env() -> Self {
+        Self {
+            model: match env::var("FORCE_DMG").is_ok() {
+                true => Some(Model::DMG),
+                false => None,
+            },
+            compat_palette: None,
+            dmg_palettes: None,
+            color_correction: ColorCorrection::None,
+            sound_enabled: env::var("SOUND_ENABLE").is_ok(),
+            audio_backend: match env::var("SOUND_ENABLE").is_ok() {
+                true => AudioBackend::Cpal,
+                false => AudioBackend::Null,
+            },
+            sync_mode: SyncMode::Audio,
+            trace: match env::var("CPU_LOG").is_ok() || env::var("CPU_LOGS").is_ok() {
+                true => Some(TraceOptions::new("-")),
+                false => None,
+            },
+            rtc_mode: match env::var("RTC_CYCLES").is_ok() {
+                true => RtcMode::Cycles,
+                false => RtcMode::WallClock,
+            },
+            debugger: false,
+            boot_rom: None,
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub struct CliOptions {
+    pub rom_path: String,
+    pub config: Config,
+    pub disasm: Option<RomRange>,
+    pub gdb_port: Option<u16>,
+    pub play_movie: Option<String>,
+    pub record_movie: Option<String>,
+    pub rewind: RewindOptions,
+}
+
+impl CliOptions {
+    // The environment variables are still read, the arguments take precedence over them
+    pub fn parse(args: &[String]) -> Result<Self, String> {
+        let mut config = Config::from_env();
+        let mut rom_path = None;
+        let mut disasm = None;
+        let mut gdb_port = None;
+        let mut play_movie = None;
+        let mut record_movie = None;
+        let mut rewind = RewindOptions::new();
+        let mut trace_filename = config.trace.as_ref().map(|trace| trace.filename.clone());
+        let mut trace = TraceOptions::new("-");
+        let mut args = args.iter().skip(1);
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--force-dmg" => config.model = Some(Model::DMG),
+                "--model" => {
+                    let model = args.next().ok_or("--model needs a Gameboy model")?;
+                    config.model = Some(Model::parse(model).ok_or(format!("Unknown Gameboy model: {}", model))?);
+                },
+                "--palette" | "--palette-bg" | "--palette-obj0" | "--palette-obj1" => {
+                    let name = args.next().ok_or(format!("{} needs a preset or a file name", arg))?;
+                    let colors = load_colors(name).map_err(|err| {
+                        let presets: Vec<&str> = PRESETS.iter().map(|(preset, _)| *preset).collect();
+                        format!("Could not load the palette {}: {} (the presets are {})", name, err, presets.join(", "))
+                    })?;
+                    let palettes = config.dmg_palettes.get_or_insert(Palettes::uniform(DEFAULT_COLORS));
+                    match arg.as_str() {
+                        "--palette-bg" => palettes.bg = colors,
+                        "--palette-obj0" => palettes.obj0 = colors,
+                        "--palette-obj1" => palettes.obj1 = colors,
+                        _ => *palettes = Palettes::uniform(colors),
+                    };
+                },
+                "--color-correction" => {
+                    let mode = args.next().ok_or("--color-correction needs a mode")?;
+                    config.color_correction = ColorCorrection::parse(mode).ok_or(format!("Unknown color correction: {}", mode))?;
+                },
+                "--compat-palette" => {
+                    let buttons = args.next().ok_or("--compat-palette needs a button combination")?;
+                    config.compat_palette = Some(Palettes::from_buttons(buttons).ok_or_else(|| {
+                        let names: Vec<&str> = BUTTON_PALETTES.iter().map(|(name, _)| *name).collect();
+                        format!("Unknown palette: {}, expected one of {}", buttons, names.join(", "))
+                    })?);
+                },
+                "--boot-rom" => {
+                    let filename = args.next().ok_or("--boot-rom needs a file name")?;
+                    let boot_rom = BootRom::load(filename).map_err(|err| format!("Could not read the boot ROM {}: {}", filename, err))?;
+                    config.boot_rom = Some(boot_rom);
+                },
+                "--rtc-cycles" => config.rtc_mode = RtcMode::Cycles,
+                "--cpu-logs" => trace_filename = Some("-".to_string()),
+                "--trace" => trace_filename = Some(args.next().ok_or("--trace needs a file name")?.to_string()),
+                "--trace-pc" => {
+                    let range = args.next().ok_or("--trace-pc needs an address range")?;
+                    let (start, end) = range.split_once('-').ok_or(format!("Invalid range: {}", range))?;
+                    trace.pc_range = Some((parse_hex(start)?, parse_hex(end)?));
+                },
+                "--trace-bank" => trace.bank = Some(parse_hex(args.next().ok_or("--trace-bank needs a bank")?)?),
+                "--trace-limit" => {
+                    let limit = args.next().ok_or("--trace-limit needs a count")?;
+                    trace.limit = Some(limit.parse().map_err(|_| format!("Invalid count: {}", limit))?);
+                },
+                "--trace-extra" => trace.extra = true,
+                "--debug" => config.debugger = true,
+                "--audio" => {
+                    config.audio_backend = match args.next().map(|backend| backend.as_str()) {
+                        Some("cpal") => AudioBackend::Cpal,
+                        Some("null") => AudioBackend::Null,
+                        Some(backend) => return Err(format!("Unknown audio backend: {}", backend)),
+                        None => return Err("--audio needs a backend".to_string()),
+                    };
+                    config.sound_enabled = true;
+                },
+                "--sync" => {
+                    config.sync_mode = match args.next().map(|mode| mode.as_str()) {
+                        Some("audio") => SyncMode::Audio,
+                        Some("timer") => SyncMode::Timer,
+                        Some(mode) => return Err(format!("Unknown sync mode: {}", mode)),
+                        None => return Err("--sync needs a mode".to_string()),
+                    };
+                },
+                "--disasm" => {
+                    let range = args.next().ok_or("--disasm needs an address range")?;
+                    disasm = Some(RomRange::parse(range)?);
+                },
+                "--gdb" => {
+                    let port = args.next().ok_or("--gdb needs a port")?;
+                    gdb_port = Some(port.parse().map_err(|_| format!("Invalid port: {}", port))?);
+                },
+                "--play" => play_movie = Some(args.next().ok_or("--play needs a file name")?.to_string()),
+                "--record" => record_movie = Some(args.next().ok_or("--record needs a file name")?.to_string()),
+                "--rewind" => {
+                    let seconds = args.next().ok_or("--rewind needs a number of seconds")?;
+                    rewind.seconds = seconds.parse().map_err(|_| format!("Invalid number of seconds: {}", seconds))?;
+                },
+                "--rewind-interval" => {
+                    let frames = args.next().ok_or("--rewind-interval needs a number of frames")?;
+                    rewind.interval = match frames.parse() {
+                        Ok(frames) if frames > 0 => frames,
+                        _ => return Err(format!("Invalid number of frames: {}", frames)),
+                    };
+                },
+                "--rewind-memory" => {
+                    let size = args.next().ok_or("--rewind-memory needs a size in MiB")?;
+                    let size: usize = size.parse().map_err(|_| format!("Invalid size: {}", size))?;
+                    rewind.memory_limit = size.checked_mul(1024 * 1024).ok_or(format!("Size too large: {}", size))?;
+                },
+                "--wav" => {
+                    let filename = args.next().ok_or("--wav needs a file name")?;
+                    config.audio_backend = AudioBackend::Wav(filename.to_string());
+                    config.sound_enabled = true;
+                },
+                "--help" | "-h" => return Err(USAGE.to_string()),
+                option if option.starts_with('-') => return Err(format!("Unknown option: {}\n\n{}", option, USAGE)),
+                path => {
+                    if rom_path.is_some() {
+                        return Err(format!("Unexpected argument: {}\n\n{}", path, USAGE));
+                    }
+                    rom_path = Some(path.to_string());
+                },
+            };
+        }
+
+        // Movies only replay the same way with a clock that follows the emulation
+        if play_movie.is_some() || record_movie.is_some() {
+            config.rtc_mode = RtcMode::Cycles;
+        }
+
+        config.trace = trace_filename.map(|filename| TraceOptions {
+            filename,
+            ..trace
+        });
+
+        Ok(Self {
+            rom_path: rom_path.ok_or(format!("Please, specify a ROM file\n\n{}", USAGE))?,
+            config,
+            disasm,
+            gdb_port,
+            play_movie,
+            record_movie,
+            rewind,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn test_parse_cli_options() {
+        let options = CliOptions::parse(&args(&["rmg-001", "--wav", "out.wav", "--rtc-cycles", "game.gb"])).unwrap();
+        assert_eq!(options.rom_path, "game.gb");
+        assert_eq!(options.config.audio_backend, AudioBackend::Wav("out.wav".to_string()));
+        assert!(options.config.sound_enabled);
+        assert_eq!(options.config.rtc_mode, RtcMode::Cycles);
+        assert_eq!(options.config.sync_mode, SyncMode::Audio);
+
+        let options = CliOptions::parse(&args(&["rmg-001", "game.gb", "--sync", "timer"])).unwrap();
+        assert_eq!(options.config.sync_mode, SyncMode::Timer);
+
+        assert!(CliOptions::parse(&args(&["rmg-001"])).is_err());
+        assert!(CliOptions::parse(&args(&["rmg-001", "--audio", "alsa", "game.gb"])).is_err());
+        assert!(CliOptions::parse(&args(&["rmg-001", "--unknown", "game.gb"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_disasm_option() {
+        let options = CliOptions::parse(&args(&["rmg-001", "--disasm", "01:4000-40FF", "game.gb"])).unwrap();
+        assert_eq!(options.disasm, Some(RomRange { bank: 1, start: 0x4000, end: 0x40FF }));
+        assert_eq!(options.gdb_port, None);
+    }
+
+    #[test]
+    fn test_parse_gdb_option() {
+        let options = CliOptions::parse(&args(&["rmg-001", "--gdb", "2345", "game.gb"])).unwrap();
+        assert_eq!(options.gdb_port, Some(2345));
+        assert!(CliOptions::parse(&args(&["rmg-001", "--gdb", "gdb", "game.gb"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_trace_options() {
+        let options = CliOptions::parse(&args(&["rmg-001", "game.gb"])).unwrap();
+        assert_eq!(options.config.trace, None);
+        let options = CliOptions::parse(&args(&["rmg-001", "--trace", "out.log", "--trace-pc", "0150-$01FF", "--trace-limit", "1000", "game.gb"])).unwrap();
+        assert_eq!(options.config.trace, Some(TraceOptions {
+            pc_range: Some((0x0150, 0x01FF)),
+            limit: Some(1000),
+            ..TraceOptions::new("out.log")
+        }));
+    }
+
+    #[test]
+    fn test_parse_movie_options() {
+        let options = CliOptions::parse(&args(&["rmg-001", "--play", "in.rmgm", "--record", "out.rmgm", "game.gb"])).unwrap();
+        assert_eq!(options.play_movie, Some("in.rmgm".to_string()));
+        assert_eq!(options.record_movie, Some("out.rmgm".to_string()));
+        assert_eq!(options.config.rtc_mode, RtcMode::Cycles);
+    }
+
+    #[test]
+    fn test_parse_rewind_options() {
+        let options = CliOptions::parse(&args(&["rmg-001", "game.gb"])).unwrap();
+        assert_eq!(options.rewind, RewindOptions::new());
+        let options = CliOptions::parse(&args(&["rmg-001", "--rewind", "30", "--rewind-interval", "4", "--rewind-memory", "16", "game.gb"])).unwrap();
+        assert_eq!(options.rewind, RewindOptions {
+            seconds: 30,
+            interval: 4,
+            memory_limit: 16 * 1024 * 1024,
+        });
+        assert!(CliOptions::parse(&args(&["rmg-001", "--rewind-interval", "0", "game.gb"])).is_err());
+        let size = usize::MAX.to_string();
+        assert!(matches!(CliOptions::parse(&args(&["rmg-001", "--rewind-memory", &size, "game.gb"])), Err(err) if err.starts_with("Size too large")));
+    }
+
+    #[test]
+    fn test_parse_model_options() {
+        let options = CliOptions::parse(&args(&["rmg-001", "--model", "AGB", "game.gb"])).unwrap();
+        assert_eq!(options.config.model, Some(Model::AGB));
+        let options = CliOptions::parse(&args(&["rmg-001", "--force-dmg", "game.gb"])).unwrap();
+        assert_eq!(options.config.model, Some(Model::DMG));
+        assert!(CliOptions::parse(&args(&["rmg-001", "--model", "gba", "game.gb"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_compat_palette_option() {
+        let options = CliOptions::parse(&args(&["rmg-001", "--compat-palette", "left+b", "game.gb"])).unwrap();
+        assert_eq!(options.config.compat_palette, Palettes::from_buttons("left+b"));
+        assert!(CliOptions::parse(&args(&["rmg-001", "--compat-palette", "start", "game.gb"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_palette_options() {
+        let options = CliOptions::parse(&args(&["rmg-001", "--palette", "green", "--palette-obj1", "contrast", "game.gb"])).unwrap();
+        let palettes = options.config.dmg_palettes.unwrap();
+        assert_eq!(palettes.bg, [0x9BBC0F, 0x8BAC0F, 0x306230, 0x0F380F]);
+        assert_eq!(palettes.bg, palettes.obj0);
+        assert_eq!(palettes.obj1, [0xFFFFFF, 0xAAAAAA, 0x555555, 0x000000]);
+        let options = CliOptions::parse(&args(&["rmg-001", "--palette-bg", "grey", "game.gb"])).unwrap();
+        assert_eq!(options.config.dmg_palettes.unwrap().obj0, DEFAULT_COLORS);
+        assert!(CliOptions::parse(&args(&["rmg-001", "--palette-bg", "no-such-palette.hex", "game.gb"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_color_correction_option() {
+        let options = CliOptions::parse(&args(&["rmg-001", "--color-correction", "gamma", "game.gb"])).unwrap();
+        assert_eq!(options.config.color_correction, ColorCorrection::Gamma);
+        assert!(CliOptions::parse(&args(&["rmg-001", "--color-correction", "srgb", "game.gb"])).is_err());
+    }
+}
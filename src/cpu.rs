@@ -1,4 +1,6 @@
-use std::env;
+use log::{debug, warn};
+use crate::model::Model;
+use crate::rom::ROMInfo;
 use crate::utils::{
     BitIndex,
     get_bit,
@@ -17,6 +19,7 @@ use crate::interrupts::{
     INTERRUPT_ENABLE_ADDRESS,
     INTERRUPT_FLAG_ADDRESS,
 };
+use crate::savestate::{SaveState, StateWriter, StateReader, StateError};
 
 #[derive(Debug, Copy, Clone)]
 pub enum Register {
@@ -90,21 +93,77 @@ impl Registers {
         }
     }
 
-    pub fn new_cgb() -> Self {
+    // Everything starts at 0 when there is a boot ROM to run
+    pub fn power_on() -> Self {
         Self {
-            a: 0x11,
+            a: 0x00,
             f: 0x00,
             b: 0x00,
             c: 0x00,
-            d: 0xFF,
-            e: 0x56,
+            d: 0x00,
+            e: 0x00,
             h: 0x00,
-            l: 0x0D,
-            sp: 0xFFFE,
-            pc: 0x0100,
+            l: 0x00,
+            sp: 0x0000,
+            pc: 0x0000,
         }
     }
 
+    // What the boot ROM of each model leaves behind, games use A and B to tell them apart
+    pub fn for_model(model: Model, cgb_mode: bool, info: &ROMInfo) -> Self {
+        let mut registers = Self::new();
+        match model {
+            Model::DMG | Model::MGB => {
+                registers.a = match model {
+                    Model::MGB => 0xFF,
+                    _ => 0x01,
+                };
+                // Half carry and carry depend on the header checksum
+                registers.f = match info.header_checksum() {
+                    0x00 => 0x80,
+                    _ => 0xB0,
+                };
+            },
+            Model::SGB => {
+                registers.set(Register::AF, 0x0100);
+                registers.set(Register::BC, 0x0014);
+                registers.set(Register::DE, 0x0000);
+                registers.set(Register::HL, 0xC060);
+            },
+            Model::CGB | Model::AGB => {
+                registers.set(Register::AF, 0x1180);
+                match cgb_mode {
+                    true => {
+                        registers.set(Register::BC, 0x0000);
+                        registers.set(Register::DE, 0xFF56);
+                        registers.set(Register::HL, 0x000D);
+                    },
+                    false => {
+                        // Left over from picking the colors for the game
+                        registers.b = match info.nintendo_licensee() {
+                            true => info.title_checksum(),
+                            false => 0x00,
+                        };
+                        registers.c = 0x00;
+                        registers.set(Register::DE, 0x0008);
+                        registers.set(Register::HL, match registers.b {
+                            0x43 | 0x58 => 0x991A,
+                            _ => 0x007C,
+                        });
+                    },
+                };
+                // The AGB boot ROM ends with an extra INC B
+                if model == Model::AGB {
+                    registers.b = registers.b.wrapping_add(1);
+                    registers.f = 0x00;
+                    registers.set_flag(FlagRegister::Zero, registers.b == 0x00);
+                    registers.set_flag(FlagRegister::HalfCarry, registers.b & 0x0F == 0x00);
+                }
+            },
+        };
+        registers
+    }
+
     pub fn get(&self, register: Register) -> u16 {
         match register {
             Register::A => self.a as u16,
@@ -224,19 +283,39 @@ pub enum OpcodeParameter {
 }
 
 #[derive(Debug, Copy, Clone)]
-pub struct OpcodeParameterBytes(u8, u8, u8, u8);
+pub struct OpcodeParameterBytes(u8, u8, u8);
 
 impl OpcodeParameterBytes {
 
     pub fn from_address(address: u16, bus: &Bus)-> OpcodeParameterBytes {
         OpcodeParameterBytes(
-            bus.read(address),
-            bus.read(address.wrapping_add(1)),
-            bus.read(address.wrapping_add(2)),
-            bus.read(address.wrapping_add(3)),
+            bus.peek(address),
+            bus.peek(address.wrapping_add(1)),
+            bus.peek(address.wrapping_add(2)),
         )
     }
 
+    // Missing bytes past the end of the slice read as 0
+    pub fn from_bytes(bytes: &[u8]) -> OpcodeParameterBytes {
+        let byte = |index: usize| bytes.get(index).copied().unwrap_or(0);
+        OpcodeParameterBytes(byte(0), byte(1), byte(2))
+    }
+
+    // Size of the instruction in bytes, including the opcode and the CB prefix
+    pub fn length(&self) -> u16 {
+        match self.0 {
+            0x01 | 0x08 | 0x11 | 0x21 | 0x31 |
+            0xC2 | 0xC3 | 0xC4 | 0xCA | 0xCC | 0xCD |
+            0xD2 | 0xD4 | 0xDA | 0xDC |
+            0xEA | 0xFA => 3,
+            0x06 | 0x0E | 0x16 | 0x1E | 0x26 | 0x2E | 0x36 | 0x3E |
+            0x10 | 0x18 | 0x20 | 0x28 | 0x30 | 0x38 |
+            0xC6 | 0xCE | 0xD6 | 0xDE | 0xE6 | 0xEE | 0xF6 | 0xFE |
+            0xE0 | 0xE8 | 0xF0 | 0xF8 | 0xCB => 2,
+            _ => 1,
+        }
+    }
+
     pub fn parse_opcode(&self) -> (Opcode, Cycles) {
         let opcode = self.0;
         let two_byte_param = join_bytes(self.2, self.1);
@@ -832,7 +911,6 @@ pub struct CPU {
     is_halted: bool,
     ime: bool, // Interrupt Master Enable
     ei_delay: bool,
-    enable_logs: bool,
     is_cgb: bool,
     double_speed_mode: bool,
 }
@@ -847,27 +925,45 @@ impl CPU {
             is_halted: false,
             ei_delay: false,
             ime: true,
-            enable_logs: !env::var("CPU_LOG").is_err() || !env::var("CPU_LOGS").is_err(),
             is_cgb: false,
             double_speed_mode: false,
         }
     }
 
-    pub fn new_cgb() -> Self {
+    pub fn for_model(model: Model, cgb_mode: bool, info: &ROMInfo) -> Self {
         Self {
-            registers: Registers::new_cgb(),
-            cycles: Cycles(0.0),
-            last_op_cycles: Cycles(0.0),
-            exec_calls_count: 0,
-            is_halted: false,
-            ei_delay: false,
-            ime: true,
-            enable_logs: !env::var("CPU_LOG").is_err() || !env::var("CPU_LOGS").is_err(),
-            is_cgb: true,
-            double_speed_mode: false,
+            registers: Registers::for_model(model, cgb_mode, info),
+            is_cgb: cgb_mode,
+            ..Self::new()
         }
     }
 
+    // Right before running the boot ROM
+    pub fn power_on(is_cgb: bool) -> Self {
+        Self {
+            registers: Registers::power_on(),
+            ime: false,
+            is_cgb,
+            ..Self::new()
+        }
+    }
+
+    pub fn registers(&self) -> &Registers {
+        &self.registers
+    }
+
+    pub fn registers_mut(&mut self) -> &mut Registers {
+        &mut self.registers
+    }
+
+    pub fn ime(&self) -> bool {
+        self.ime
+    }
+
+    pub fn is_halted(&self) -> bool {
+        self.is_halted
+    }
+
     pub fn get_exec_calls_count(&self) -> usize {
         self.exec_calls_count
     }
@@ -900,25 +996,6 @@ impl CPU {
         self.last_op_cycles
     }
 
-    fn log(&self, parameter_bytes: OpcodeParameterBytes) {
-        println!("A: {:02X} F: {:02X} B: {:02X} C: {:02X} D: {:02X} E: {:02X} H: {:02X} L: {:02X} SP: {:04X} PC: 00:{:04X} ({:02X} {:02X} {:02X} {:02X})",
-            self.registers.get(Register::A),
-            self.registers.get(Register::F),
-            self.registers.get(Register::B),
-            self.registers.get(Register::C),
-            self.registers.get(Register::D),
-            self.registers.get(Register::E),
-            self.registers.get(Register::H),
-            self.registers.get(Register::L),
-            self.registers.get(Register::SP),
-            self.registers.get(Register::PC),
-            parameter_bytes.0,
-            parameter_bytes.1,
-            parameter_bytes.2,
-            parameter_bytes.3,
-        );
-    }
-
     pub fn handle_interrupt(&mut self, bus: &mut Bus, interrupt: Interrupt) {
         bus.interrupts.set(interrupt, false);
         self.ime = false;
@@ -926,8 +1003,14 @@ impl CPU {
         self.exec(Opcode::CALL(OpcodeParameter::U16(interrupt.get_vector())), bus);
     }
 
+    // Whether the next `run` executes an instruction, instead of dispatching an interrupt or staying halted
+    pub fn will_execute(&self, bus: &Bus) -> bool {
+        let pending = (bus.peek(INTERRUPT_ENABLE_ADDRESS) & bus.peek(INTERRUPT_FLAG_ADDRESS) & 0b00011111) != 0;
+        !(self.ime && pending) && (!self.is_halted || pending)
+    }
+
     pub fn check_interrupts(&mut self, bus: &mut Bus) -> Option<Interrupt> {
-        let interrupts = (bus.read(INTERRUPT_ENABLE_ADDRESS) & 0b00011111) & (bus.read(INTERRUPT_FLAG_ADDRESS) & 0b00011111);
+        let interrupts = (bus.peek(INTERRUPT_ENABLE_ADDRESS) & 0b00011111) & (bus.peek(INTERRUPT_FLAG_ADDRESS) & 0b00011111);
         if interrupts != 0 {
             self.is_halted = false;
         }
@@ -966,10 +1049,7 @@ impl CPU {
             let program_counter = self.registers.get(Register::PC);
             let parameter_bytes = OpcodeParameterBytes::from_address(program_counter, bus);
             let (opcode, cycles) = parameter_bytes.parse_opcode();
-            if self.enable_logs {
-                self.log(parameter_bytes);
-                self.increment_exec_calls_count();
-            }
+            self.increment_exec_calls_count();
             self.increment_cycles(cycles);
             self.exec(opcode, bus);
             self.ei_delay(bus);
@@ -1800,13 +1880,13 @@ impl CPU {
                     let speed_switch_register = bus.read(PREPARE_SPEED_SWITCH_ADDRESS);
                     // Disable prepare speed switch
                     bus.write(PREPARE_SPEED_SWITCH_ADDRESS, speed_switch_register & 0xFE);
-                    println!("Switching speed mode to: {}", !self.double_speed_mode);
+                    debug!("Switching speed mode to: {}", !self.double_speed_mode);
                     self.double_speed_mode = !self.double_speed_mode;
                 }
             },
             Opcode::NOP => self.registers.increment(Register::PC, 1),
             Opcode::IllegalInstruction => {
-                println!("Illegal instruction!");
+                warn!("Illegal instruction!");
                 self.registers.increment(Register::PC, 1);
             },
             _ => unreachable!(),
@@ -1814,10 +1894,78 @@ impl CPU {
     }
 }
 
+impl SaveState for Registers {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_bytes(&[self.a, self.f, self.b, self.c, self.d, self.e, self.h, self.l]);
+        state.write_u16(self.sp);
+        state.write_u16(self.pc);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        let mut bytes = [0; 8];
+        state.read_bytes(&mut bytes)?;
+        let [a, f, b, c, d, e, h, l] = bytes;
+        self.a = a;
+        // The low nibble of F is always 0, like after POP AF
+        self.f = f & 0xF0;
+        self.b = b;
+        self.c = c;
+        self.d = d;
+        self.e = e;
+        self.h = h;
+        self.l = l;
+        self.sp = state.read_u16()?;
+        self.pc = state.read_u16()?;
+        Ok(())
+    }
+}
+
+impl SaveState for CPU {
+    fn save_state(&self, state: &mut StateWriter) {
+        self.registers.save_state(state);
+        state.write_f32(self.cycles.0);
+        state.write_f32(self.last_op_cycles.0);
+        state.write_u64(self.exec_calls_count as u64);
+        state.write_bool(self.is_halted);
+        state.write_bool(self.ime);
+        state.write_bool(self.ei_delay);
+        state.write_bool(self.is_cgb);
+        state.write_bool(self.double_speed_mode);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.registers.load_state(state)?;
+        self.cycles = Cycles(state.read_f32()?);
+        self.last_op_cycles = Cycles(state.read_f32()?);
+        self.exec_calls_count = state.read_u64()? as usize;
+        self.is_halted = state.read_bool()?;
+        self.ime = state.read_bool()?;
+        self.ei_delay = state.read_bool()?;
+        if state.read_bool()? != self.is_cgb {
+            return Err(StateError::ModeMismatch);
+        }
+        self.double_speed_mode = state.read_bool()?;
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_registers_load_state_masks_flags() {
+        let mut registers = Registers::new();
+        registers.set(Register::AF, 0x12FF);
+        let mut state = StateWriter::new();
+        registers.save_state(&mut state);
+        let data = state.into_bytes();
+
+        let mut loaded = Registers::new();
+        loaded.load_state(&mut StateReader::new(&data)).unwrap();
+        assert_eq!(loaded.get(Register::AF), 0x12F0);
+    }
+
     #[test]
     fn test_registers_setters_getters() {
         // Test 8 bit setters and getters
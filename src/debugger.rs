@@ -0,0 +1,626 @@
+use std::fmt;
+use std::io::{self, BufRead, Write};
+use crate::bus::Bus;
+use crate::cpu::{CPU, Register, FlagRegister};
+use crate::disasm::{Instruction, decode};
+use crate::symbols::Symbols;
+
+const HELP: &str = "Commands:
+  c, continue            Resume the emulation
+  s, step [count]        Execute one or more instructions
+  n, next                Execute one instruction, stepping over CALL and RST
+  f, finish              Run until the current function returns
+  b, break <[bank:]addr> Add a breakpoint, the bank is optional. Labels from
+                         the ROM's .sym file work too, e.g. `break Main.loop`
+  d, delete [index]      Remove a breakpoint, or all of them
+  bl, breakpoints        List the breakpoints
+  w, watch <r|w|rw> <[bank:]addr[-end]> [value]
+                         Break on reads and/or writes to an address or a range,
+                         only when the value matches if given
+  uw, unwatch [index]    Remove a watchpoint, or all of them
+  wl, watchpoints        List the watchpoints
+  r, regs                Print the CPU registers
+  x, mem <addr> [len]    Dump memory
+  l, disasm [addr] [n]   Disassemble n instructions, at PC by default
+  q, quit                Close the emulator
+  h, help                Show this message
+
+Addresses and banks are hexadecimal, counts are decimal. Anywhere an address
+is expected, a label can be used instead.";
+
+// Parses `0150`, `$0150` or `0x0150`
+pub fn parse_hex(text: &str) -> Result<u16, String> {
+    let digits = text.trim_start_matches('$').trim_start_matches("0x");
+    u16::from_str_radix(digits, 16).map_err(|_| format!("Invalid hexadecimal value: {}", text))
+}
+
+// A label, or a hexadecimal address. Labels in switchable memory keep their bank
+fn parse_address(text: &str, symbols: &Symbols) -> Result<(Option<u16>, u16), String> {
+    if let Some((bank, address)) = symbols.address(text) {
+        let bank = match address {
+            0x4000..=0xBFFF | 0xD000..=0xDFFF => Some(bank),
+            _ => None,
+        };
+        return Ok((bank, address));
+    }
+    match parse_hex(text) {
+        Ok(address) => Ok((None, address)),
+        Err(_) => Err(format!("Unknown label or address: {}", text)),
+    }
+}
+
+fn parse_count(text: Option<&str>, default: usize) -> Result<usize, String> {
+    match text {
+        Some(text) => text.parse().map_err(|_| format!("Invalid count: {}", text)),
+        None => Ok(default),
+    }
+}
+
+fn instruction_at(bus: &Bus, address: u16) -> Instruction {
+    let bytes: Vec<u8> = (0..3).map(|offset| bus.peek(address.wrapping_add(offset))).collect();
+    decode(&bytes, address)
+}
+
+fn is_call(opcode: u8) -> bool {
+    matches!(opcode, 0xC4 | 0xCC | 0xCD | 0xD4 | 0xDC) || opcode & 0xC7 == 0xC7
+}
+
+fn is_return(opcode: u8) -> bool {
+    matches!(opcode, 0xC0 | 0xC8 | 0xC9 | 0xD0 | 0xD8 | 0xD9)
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Breakpoint {
+    // Only break when this bank is mapped at the address
+    pub bank: Option<u16>,
+    pub address: u16,
+}
+
+impl Breakpoint {
+    pub fn parse(text: &str, symbols: &Symbols) -> Result<Self, String> {
+        Ok(match text.split_once(':') {
+            Some((bank, address)) => Self {
+                bank: Some(parse_hex(bank)?),
+                address: parse_hex(address)?,
+            },
+            None => {
+                let (bank, address) = parse_address(text, symbols)?;
+                Self { bank, address }
+            },
+        })
+    }
+
+    fn matches(&self, bus: &Bus, pc: u16) -> bool {
+        self.address == pc && self.bank.is_none_or(|bank| bank == bus.bank(pc))
+    }
+}
+
+impl fmt::Display for Breakpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.bank {
+            Some(bank) => write!(f, "{:02X}:{:04X}", bank, self.address),
+            None => write!(f, "{:04X}", self.address),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Access {
+    Read,
+    Write,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Watchpoint {
+    pub read: bool,
+    pub write: bool,
+    pub start: u16,
+    pub end: u16,
+    // Only trigger when this bank is mapped at the address
+    pub bank: Option<u16>,
+    // Only trigger when this value is read or written
+    pub value: Option<u8>,
+}
+
+impl Watchpoint {
+    // `kind` is r, w or rw, `range` is `[bank:]start[-end]`
+    pub fn parse(kind: &str, range: &str, value: Option<&str>, symbols: &Symbols) -> Result<Self, String> {
+        let (read, write) = match kind {
+            "r" => (true, false),
+            "w" => (false, true),
+            "rw" => (true, true),
+            _ => return Err(format!("Unknown watchpoint kind: {}, use r, w or rw", kind)),
+        };
+        let (bank, range) = match range.split_once(':') {
+            Some((bank, range)) => (Some(parse_hex(bank)?), range),
+            None => (None, range),
+        };
+        let (start, end) = match range.split_once('-') {
+            Some((start, end)) => (parse_address(start, symbols)?, parse_address(end, symbols)?.1),
+            None => (parse_address(range, symbols)?, parse_address(range, symbols)?.1),
+        };
+        let (bank, start) = (bank.or(start.0), start.1);
+        if end < start {
+            return Err(format!("Invalid range: {}", range));
+        }
+        Ok(Self {
+            read,
+            write,
+            start,
+            end,
+            bank,
+            value: match value {
+                Some(value) => Some(u8::try_from(parse_hex(value)?).map_err(|_| format!("Invalid byte value: {}", value))?),
+                None => None,
+            },
+        })
+    }
+
+    pub fn matches(&self, bus: &Bus, access: Access, address: u16, value: u8) -> bool {
+        let kind = match access {
+            Access::Read => self.read,
+            Access::Write => self.write,
+        };
+        kind &&
+            (self.start..=self.end).contains(&address) &&
+            self.value.is_none_or(|expected| expected == value) &&
+            self.bank.is_none_or(|bank| bank == bus.bank(address))
+    }
+}
+
+impl fmt::Display for Watchpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let kind = match (self.read, self.write) {
+            (true, true) => "rw",
+            (true, false) => "r",
+            _ => "w",
+        };
+        write!(f, "{} ", kind)?;
+        if let Some(bank) = self.bank {
+            write!(f, "{:02X}:", bank)?;
+        }
+        write!(f, "{:04X}", self.start)?;
+        if self.end != self.start {
+            write!(f, "-{:04X}", self.end)?;
+        }
+        if let Some(value) = self.value {
+            write!(f, " = {:02X}", value)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct WatchHit {
+    pub index: usize,
+    pub access: Access,
+    pub address: u16,
+    pub value: u8,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum RunMode {
+    Paused,
+    Running,
+    // Instructions left before pausing again
+    Step(usize),
+    // Pause when coming back to the instruction after the call, with the same stack
+    StepOver { address: u16, sp: u16 },
+    // Pause after a return pops the current stack frame
+    StepOut { sp: u16 },
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum DebuggerAction {
+    // Keep reading commands
+    Stay,
+    Resume,
+    Quit,
+}
+
+pub struct Debugger {
+    breakpoints: Vec<Breakpoint>,
+    mode: RunMode,
+    // Don't break again at the instruction we are resuming from
+    resume_pc: Option<u16>,
+    returning: bool,
+    // Why the emulation stopped, shown when the prompt opens
+    pause_reason: Option<String>,
+    symbols: Symbols,
+}
+
+impl Debugger {
+    pub fn new() -> Self {
+        Self {
+            breakpoints: Vec::new(),
+            mode: RunMode::Running,
+            resume_pc: None,
+            returning: false,
+            pause_reason: None,
+            symbols: Symbols::new(),
+        }
+    }
+
+    pub fn set_symbols(&mut self, symbols: Symbols) {
+        self.symbols = symbols;
+    }
+
+    pub fn symbols(&self) -> &Symbols {
+        &self.symbols
+    }
+
+    // `00:0153 (Main.loop+3)`, without the label if there isn't one before the address
+    fn location(&self, bus: &Bus, address: u16) -> String {
+        let bank = bus.bank(address);
+        match self.symbols.describe(bank, address) {
+            Some(label) => format!("{:02X}:{:04X} ({})", bank, address, label),
+            None => format!("{:02X}:{:04X}", bank, address),
+        }
+    }
+
+    pub fn pause(&mut self) {
+        self.mode = RunMode::Paused;
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.mode == RunMode::Paused
+    }
+
+    pub fn pause_reason(&self) -> Option<&str> {
+        self.pause_reason.as_deref()
+    }
+
+    // A watchpoint triggered during the instruction at `pc`
+    pub fn watch_hit(&mut self, hit: WatchHit, pc: u16, bus: &Bus) {
+        let access = match hit.access {
+            Access::Read => "read",
+            Access::Write => "write",
+        };
+        self.mode = RunMode::Paused;
+        self.pause_reason = Some(format!("Watchpoint {}: {} {:04X} = {:02X} by {} {}",
+            hit.index,
+            access,
+            hit.address,
+            hit.value,
+            self.location(bus, pc),
+            instruction_at(bus, pc).text_with_labels(&self.symbols, bus.bank(pc)),
+        ));
+    }
+
+    pub fn breakpoints(&self) -> &[Breakpoint] {
+        &self.breakpoints
+    }
+
+    pub fn add_breakpoint(&mut self, breakpoint: Breakpoint) {
+        if !self.breakpoints.contains(&breakpoint) {
+            self.breakpoints.push(breakpoint);
+        }
+    }
+
+    // Returns false if there was no such breakpoint
+    pub fn remove_breakpoint(&mut self, breakpoint: &Breakpoint) -> bool {
+        let length = self.breakpoints.len();
+        self.breakpoints.retain(|other| other != breakpoint);
+        self.breakpoints.len() != length
+    }
+
+    // When this is false there is nothing to check before each instruction
+    pub fn is_active(&self) -> bool {
+        self.mode != RunMode::Running || !self.breakpoints.is_empty()
+    }
+
+    // Called before executing each instruction, pauses the emulation if it has to stop there
+    pub fn should_break(&mut self, cpu: &CPU, bus: &Bus) -> bool {
+        let pc = cpu.registers().get(Register::PC);
+        let sp = cpu.registers().get(Register::SP);
+        let stop = match self.mode {
+            RunMode::Paused => return true,
+            RunMode::Running => false,
+            RunMode::Step(0) => true,
+            RunMode::Step(count) => {
+                self.mode = RunMode::Step(count - 1);
+                false
+            },
+            RunMode::StepOver { address, sp: call_sp } => pc == address && sp >= call_sp,
+            RunMode::StepOut { sp: frame_sp } => {
+                let returned = self.returning && sp > frame_sp;
+                self.returning = is_return(bus.peek(pc));
+                returned
+            },
+        };
+        let resuming = match self.resume_pc {
+            Some(resume_pc) if resume_pc == pc => true,
+            Some(_) => {
+                self.resume_pc = None;
+                false
+            },
+            None => false,
+        };
+        if stop || (!resuming && self.breakpoints.iter().any(|breakpoint| breakpoint.matches(bus, pc))) {
+            self.mode = RunMode::Paused;
+            return true;
+        }
+        false
+    }
+
+    fn resume(&mut self, mode: RunMode, cpu: &CPU) -> DebuggerAction {
+        self.mode = mode;
+        self.resume_pc = Some(cpu.registers().get(Register::PC));
+        self.returning = false;
+        self.pause_reason = None;
+        DebuggerAction::Resume
+    }
+
+    // Same as `continue` and `step`, for frontends that don't go through commands
+    pub fn resume_running(&mut self, cpu: &CPU) {
+        self.resume(RunMode::Running, cpu);
+    }
+
+    pub fn resume_step(&mut self, cpu: &CPU) {
+        self.resume(RunMode::Step(1), cpu);
+    }
+
+    pub fn execute(&mut self, line: &str, cpu: &CPU, bus: &mut Bus, out: &mut dyn Write) -> io::Result<DebuggerAction> {
+        let mut args = line.split_whitespace();
+        let command = match args.next() {
+            Some(command) => command,
+            None => return Ok(DebuggerAction::Stay),
+        };
+        let args: Vec<&str> = args.collect();
+        let result = self.execute_command(command, &args, cpu, bus, out);
+        match result {
+            Ok(action) => Ok(action),
+            Err(err) => {
+                writeln!(out, "{}", err)?;
+                Ok(DebuggerAction::Stay)
+            },
+        }
+    }
+
+    fn execute_command(&mut self, command: &str, args: &[&str], cpu: &CPU, bus: &mut Bus, out: &mut dyn Write) -> Result<DebuggerAction, String> {
+        let arg1 = args.first().copied();
+        let arg2 = args.get(1).copied();
+        let registers = cpu.registers();
+        let pc = registers.get(Register::PC);
+        let io_error = |err: io::Error| err.to_string();
+        match command {
+            "c" | "continue" => return Ok(self.resume(RunMode::Running, cpu)),
+            "s" | "step" => {
+                let count = parse_count(arg1, 1)?;
+                return Ok(self.resume(RunMode::Step(count), cpu));
+            },
+            "n" | "next" => {
+                let mode = match is_call(bus.peek(pc)) {
+                    true => RunMode::StepOver {
+                        address: pc.wrapping_add(instruction_at(bus, pc).length),
+                        sp: registers.get(Register::SP),
+                    },
+                    false => RunMode::Step(1),
+                };
+                return Ok(self.resume(mode, cpu));
+            },
+            "f" | "finish" => return Ok(self.resume(RunMode::StepOut { sp: registers.get(Register::SP) }, cpu)),
+            "b" | "break" => {
+                let breakpoint = Breakpoint::parse(arg1.ok_or("break needs an address")?, &self.symbols)?;
+                self.add_breakpoint(breakpoint);
+                writeln!(out, "Breakpoint {} at {}", self.breakpoints.len() - 1, self.describe_breakpoint(&breakpoint)).map_err(io_error)?;
+            },
+            "d" | "delete" => match arg1 {
+                Some(index) => {
+                    let index = parse_count(Some(index), 0)?;
+                    if index >= self.breakpoints.len() {
+                        return Err(format!("No breakpoint {}", index));
+                    }
+                    self.breakpoints.remove(index);
+                },
+                None => self.breakpoints.clear(),
+            },
+            "bl" | "breakpoints" => {
+                for (index, breakpoint) in self.breakpoints.iter().enumerate() {
+                    writeln!(out, "{}: {}", index, self.describe_breakpoint(breakpoint)).map_err(io_error)?;
+                }
+            },
+            "w" | "watch" => {
+                let kind = arg1.ok_or("watch needs a kind and an address")?;
+                let range = arg2.ok_or("watch needs an address")?;
+                let watchpoint = Watchpoint::parse(kind, range, args.get(2).copied(), &self.symbols)?;
+                bus.add_watchpoint(watchpoint);
+                writeln!(out, "Watchpoint {}: {}", bus.watchpoints().len() - 1, watchpoint).map_err(io_error)?;
+            },
+            "uw" | "unwatch" => match arg1 {
+                Some(index) => {
+                    let index = parse_count(Some(index), 0)?;
+                    if bus.remove_watchpoint(index).is_none() {
+                        return Err(format!("No watchpoint {}", index));
+                    }
+                },
+                None => bus.clear_watchpoints(),
+            },
+            "wl" | "watchpoints" => {
+                for (index, watchpoint) in bus.watchpoints().iter().enumerate() {
+                    writeln!(out, "{}: {}", index, watchpoint).map_err(io_error)?;
+                }
+            },
+            "r" | "regs" => self.print_registers(cpu, bus, out).map_err(io_error)?,
+            "x" | "mem" => {
+                let (_, address) = parse_address(arg1.ok_or("mem needs an address")?, &self.symbols)?;
+                let length = parse_count(arg2, 64)?.min(u16::MAX as usize) as u16;
+                Debugger::print_memory(bus, address, length, out).map_err(io_error)?;
+            },
+            "l" | "disasm" => {
+                let address = match arg1 {
+                    Some(address) => parse_address(address, &self.symbols)?.1,
+                    None => pc,
+                };
+                let count = parse_count(arg2, 10)?;
+                self.print_disassembly(bus, pc, address, count, out).map_err(io_error)?;
+            },
+            "q" | "quit" => return Ok(DebuggerAction::Quit),
+            "h" | "help" => writeln!(out, "{}", HELP).map_err(io_error)?,
+            _ => return Err(format!("Unknown command: {}, type `help` to list them", command)),
+        };
+        Ok(DebuggerAction::Stay)
+    }
+
+    // The address, and the label when the breakpoint points right at one
+    fn describe_breakpoint(&self, breakpoint: &Breakpoint) -> String {
+        let bank = breakpoint.bank.unwrap_or(0);
+        match self.symbols.label(bank, breakpoint.address) {
+            Some(label) => format!("{} ({})", breakpoint, label),
+            None => breakpoint.to_string(),
+        }
+    }
+
+    pub fn print_registers(&self, cpu: &CPU, bus: &Bus, out: &mut dyn Write) -> io::Result<()> {
+        let registers = cpu.registers();
+        let flag = |flag: FlagRegister, name: char| match registers.get_flag(flag) {
+            true => name,
+            false => '-',
+        };
+        let pc = registers.get(Register::PC);
+        writeln!(out, "A: {:02X}  F: {:02X}  [{}{}{}{}]",
+            registers.get(Register::A),
+            registers.get(Register::F),
+            flag(FlagRegister::Zero, 'Z'),
+            flag(FlagRegister::Substract, 'N'),
+            flag(FlagRegister::HalfCarry, 'H'),
+            flag(FlagRegister::Carry, 'C'),
+        )?;
+        writeln!(out, "BC: {:04X}  DE: {:04X}  HL: {:04X}",
+            registers.get(Register::BC),
+            registers.get(Register::DE),
+            registers.get(Register::HL),
+        )?;
+        writeln!(out, "SP: {:04X}  PC: {}  IME: {}  HALT: {}",
+            registers.get(Register::SP),
+            self.location(bus, pc),
+            cpu.ime() as u8,
+            cpu.is_halted() as u8,
+        )
+    }
+
+    pub fn print_memory(bus: &Bus, address: u16, length: u16, out: &mut dyn Write) -> io::Result<()> {
+        let mut row = address & 0xFFF0;
+        let end = address as u32 + length as u32;
+        while (row as u32) < end {
+            write!(out, "{:04X}:", row)?;
+            for offset in 0..16 {
+                let byte_address = row.wrapping_add(offset);
+                match (byte_address as u32) >= address as u32 && (byte_address as u32) < end {
+                    true => write!(out, " {:02X}", bus.peek(byte_address))?,
+                    false => write!(out, "   ")?,
+                };
+            }
+            writeln!(out)?;
+            row = match row.checked_add(16) {
+                Some(row) => row,
+                None => break,
+            };
+        }
+        Ok(())
+    }
+
+    pub fn print_disassembly(&self, bus: &Bus, pc: u16, address: u16, count: usize, out: &mut dyn Write) -> io::Result<()> {
+        let mut address = address;
+        for _ in 0..count {
+            let bank = bus.bank(address);
+            if let Some(label) = self.symbols.label(bank, address) {
+                writeln!(out, "{}:", label)?;
+            }
+            let instruction = instruction_at(bus, address);
+            let length = instruction.length;
+            let bytes: Vec<String> = (0..length)
+                .map(|offset| format!("{:02X}", bus.peek(address.wrapping_add(offset))))
+                .collect();
+            let marker = match address == pc {
+                true => '>',
+                false => ' ',
+            };
+            writeln!(out, "{} {:02X}:{:04X}  {:<9} {}", marker, bank, address, bytes.join(" "), instruction.text_with_labels(&self.symbols, bank))?;
+            address = address.wrapping_add(length);
+        }
+        Ok(())
+    }
+
+    // Reads commands from the terminal until one of them resumes the emulation
+    pub fn prompt(&mut self, cpu: &CPU, bus: &mut Bus) -> DebuggerAction {
+        let stdin = io::stdin();
+        let mut stdout = io::stdout();
+        if let Some(reason) = self.pause_reason.take() {
+            println!("{}", reason);
+        }
+        let pc = cpu.registers().get(Register::PC);
+        if let Err(err) = self.print_disassembly(bus, pc, pc, 1, &mut stdout) {
+            eprintln!("Could not write to the terminal: {}", err);
+        }
+        loop {
+            print!("(rmg) ");
+            let _ = stdout.flush();
+            let mut line = String::new();
+            match stdin.lock().read_line(&mut line) {
+                Ok(0) | Err(_) => {
+                    // Nobody to talk to, get out of the way
+                    eprintln!("No terminal input, leaving the debugger");
+                    self.breakpoints.clear();
+                    return self.resume(RunMode::Running, cpu);
+                },
+                Ok(_) => {},
+            };
+            match self.execute(&line, cpu, bus, &mut stdout) {
+                Ok(DebuggerAction::Stay) => {},
+                Ok(action) => return action,
+                Err(err) => eprintln!("Could not write to the terminal: {}", err),
+            };
+        }
+    }
+}
+
+impl Default for Debugger {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_breakpoint() {
+        let symbols = Symbols::parse("00:0150 Main\n00:0158 Main.loop\n02:4123 LoadLevel\n00:0ADD Add\n");
+        assert_eq!(Breakpoint::parse("0150", &symbols), Ok(Breakpoint { bank: None, address: 0x0150 }));
+        assert_eq!(Breakpoint::parse("$1F:4000", &symbols), Ok(Breakpoint { bank: Some(0x1F), address: 0x4000 }));
+        assert_eq!(Breakpoint::parse("0x4abc", &symbols).unwrap().address, 0x4ABC);
+        assert!(Breakpoint::parse("main", &symbols).is_err());
+        assert_eq!(Breakpoint::parse("02:4000", &symbols).unwrap().to_string(), "02:4000");
+
+        // Labels win over hexadecimal numbers with the same spelling
+        assert_eq!(Breakpoint::parse("Main.loop", &symbols), Ok(Breakpoint { bank: None, address: 0x0158 }));
+        assert_eq!(Breakpoint::parse("LoadLevel", &symbols), Ok(Breakpoint { bank: Some(2), address: 0x4123 }));
+        assert_eq!(Breakpoint::parse("Add", &symbols), Ok(Breakpoint { bank: None, address: 0x0ADD }));
+        assert_eq!(Breakpoint::parse("ADD", &symbols), Ok(Breakpoint { bank: None, address: 0x0ADD }));
+    }
+
+    #[test]
+    fn test_parse_watchpoint() {
+        let symbols = Symbols::parse("00:C000 wBuffer\n00:C0FF wBufferEnd\n03:D000 wBankedCounter\n");
+        let watchpoint = Watchpoint::parse("rw", "02:C000-C0FF", Some("42"), &symbols).unwrap();
+        assert_eq!(watchpoint, Watchpoint { read: true, write: true, start: 0xC000, end: 0xC0FF, bank: Some(2), value: Some(0x42) });
+        assert_eq!(watchpoint.to_string(), "rw 02:C000-C0FF = 42");
+        assert_eq!(Watchpoint::parse("w", "FF40", None, &symbols).unwrap().to_string(), "w FF40");
+        assert!(Watchpoint::parse("x", "FF40", None, &symbols).is_err());
+        assert!(Watchpoint::parse("r", "C0FF-C000", None, &symbols).is_err());
+        assert!(Watchpoint::parse("w", "C000", Some("1FF"), &symbols).is_err());
+        assert_eq!(Watchpoint::parse("r", "wBuffer-wBufferEnd", None, &symbols).unwrap().to_string(), "r C000-C0FF");
+        assert_eq!(Watchpoint::parse("w", "wBankedCounter", None, &symbols).unwrap().to_string(), "w 03:D000");
+    }
+
+    #[test]
+    fn test_call_and_return_opcodes() {
+        assert!(is_call(0xCD));
+        assert!(is_call(0xFF));
+        assert!(!is_call(0xC3));
+        assert!(is_return(0xD9));
+        assert!(!is_return(0xE9));
+    }
+}
@@ -0,0 +1,476 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::io::{self, Write};
+use crate::cpu::{
+    Register,
+    FlagRegister,
+    Opcode,
+    CBOpcode,
+    OpcodeParameter,
+    OpcodeParameterBytes,
+};
+use crate::debugger::parse_hex;
+use crate::symbols::Symbols;
+use crate::utils::get_bit_index;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Instruction {
+    // RGBDS syntax
+    pub text: String,
+    pub length: u16,
+    // Address used as an operand, rendered as `$XXXX` in the text
+    pub reference: Option<u16>,
+    // Where a jump, call or rst goes to
+    pub jump: Option<u16>,
+    // The next instruction doesn't get executed after this one (unconditional jumps and returns)
+    pub ends_flow: bool,
+}
+
+impl Instruction {
+    // Replaces the referenced address with its label, if there is one
+    pub fn text_with_labels(&self, symbols: &Symbols, bank: u16) -> String {
+        let reference = match self.reference {
+            Some(reference) => reference,
+            None => return self.text.clone(),
+        };
+        // Addresses in the switchable area point to the bank the instruction is in
+        let reference_bank = match reference {
+            0x4000..=0x7FFF => bank,
+            _ => 0,
+        };
+        match symbols.label(reference_bank, reference) {
+            Some(label) => self.text.replace(&format!("${:04X}", reference), label),
+            None => self.text.clone(),
+        }
+    }
+}
+
+fn register_name(register: Register) -> &'static str {
+    match register {
+        Register::A => "a",
+        Register::F => "f",
+        Register::B => "b",
+        Register::C => "c",
+        Register::D => "d",
+        Register::E => "e",
+        Register::H => "h",
+        Register::L => "l",
+        Register::AF => "af",
+        Register::BC => "bc",
+        Register::DE => "de",
+        Register::HL => "hl",
+        Register::SP => "sp",
+        Register::PC => "pc",
+    }
+}
+
+// 16 bit registers in 8 bit operations are memory accesses through them
+fn register_8bit(register: Register) -> String {
+    match register.is_16bit() {
+        true => format!("[{}]", register_name(register)),
+        false => register_name(register).to_string(),
+    }
+}
+
+fn condition(flag: FlagRegister, set: bool) -> &'static str {
+    match (flag, set) {
+        (FlagRegister::Zero, true) => "z",
+        (FlagRegister::Zero, false) => "nz",
+        (FlagRegister::Carry, true) => "c",
+        (FlagRegister::Carry, false) => "nc",
+        // There are no conditions on the other flags
+        (_, true) => "?",
+        (_, false) => "n?",
+    }
+}
+
+fn signed(value: i8) -> String {
+    match value < 0 {
+        true => format!("-{}", (value as i16).abs()),
+        false => format!("+{}", value),
+    }
+}
+
+fn operands(parameter: OpcodeParameter, next: u16, reference: &mut Option<u16>) -> String {
+    let mut address = |value: u16| {
+        *reference = Some(value);
+        format!("${:04X}", value)
+    };
+    let relative = |offset: i8| next.wrapping_add(offset as i16 as u16);
+    match parameter {
+        OpcodeParameter::Register(register) => register_name(register).to_string(),
+        OpcodeParameter::Register_U8(register, value) => format!("{}, ${:02X}", register_8bit(register), value),
+        OpcodeParameter::Register_U16(register, value) => match register.is_8bit() {
+            true => format!("{}, [{}]", register_name(register), address(value)),
+            false => format!("{}, ${:04X}", register_name(register), value),
+        },
+        OpcodeParameter::Register_I8(register, value) => format!("{}, {}", register_name(register), value),
+        OpcodeParameter::U16_Register(value, register) => format!("[{}], {}", address(value), register_name(register)),
+        OpcodeParameter::Register_Register(register1, register2) => match (register1.is_8bit(), register2.is_8bit()) {
+            // ld a, [hl] and ld [hl], a
+            (true, false) => format!("{}, [{}]", register_name(register1), register_name(register2)),
+            (false, true) => format!("[{}], {}", register_name(register1), register_name(register2)),
+            _ => format!("{}, {}", register_name(register1), register_name(register2)),
+        },
+        OpcodeParameter::Register_RegisterDecrement(register1, register2) => format!("{}, [{}-]", register_name(register1), register_name(register2)),
+        OpcodeParameter::RegisterDecrement_Register(register1, register2) => format!("[{}-], {}", register_name(register1), register_name(register2)),
+        OpcodeParameter::Register_RegisterIncrement(register1, register2) => format!("{}, [{}+]", register_name(register1), register_name(register2)),
+        OpcodeParameter::RegisterIncrement_Register(register1, register2) => format!("[{}+], {}", register_name(register1), register_name(register2)),
+        OpcodeParameter::Register_FF00plusRegister(register1, register2) => format!("{}, [{}]", register_name(register1), register_name(register2)),
+        OpcodeParameter::FF00plusRegister_Register(register1, register2) => format!("[{}], {}", register_name(register1), register_name(register2)),
+        OpcodeParameter::Register_FF00plusU8(register, value) => format!("{}, [{}]", register_name(register), address(0xFF00 | value as u16)),
+        OpcodeParameter::FF00plusU8_Register(value, register) => format!("[{}], {}", address(0xFF00 | value as u16), register_name(register)),
+        OpcodeParameter::Register_RegisterPlusI8(register1, register2, value) => format!("{}, {}{}", register_name(register1), register_name(register2), signed(value)),
+        OpcodeParameter::U8(value) => format!("${:02X}", value),
+        OpcodeParameter::U16(value) => address(value),
+        OpcodeParameter::I8(value) => address(relative(value)),
+        OpcodeParameter::FlagRegisterReset(flag) => condition(flag, false).to_string(),
+        OpcodeParameter::FlagRegisterSet(flag) => condition(flag, true).to_string(),
+        OpcodeParameter::FlagRegisterReset_U16(flag, value) => format!("{}, {}", condition(flag, false), address(value)),
+        OpcodeParameter::FlagRegisterSet_U16(flag, value) => format!("{}, {}", condition(flag, true), address(value)),
+        OpcodeParameter::FlagRegisterReset_I8(flag, value) => format!("{}, {}", condition(flag, false), address(relative(value))),
+        OpcodeParameter::FlagRegisterSet_I8(flag, value) => format!("{}, {}", condition(flag, true), address(relative(value))),
+        OpcodeParameter::NoParam => String::new(),
+        // Not produced by the opcode table
+        parameter => format!("{:?}", parameter),
+    }
+}
+
+// SUB, AND, XOR, OR and CP always work on A, so it isn't written
+fn accumulator_operands(parameter: OpcodeParameter, next: u16, reference: &mut Option<u16>) -> String {
+    match parameter {
+        OpcodeParameter::Register_Register(Register::A, register) => register_8bit(register),
+        OpcodeParameter::Register_U8(Register::A, value) => format!("${:02X}", value),
+        parameter => operands(parameter, next, reference),
+    }
+}
+
+fn inc_dec(mnemonic: &str, memory: bool, register: Register) -> String {
+    match memory {
+        true => format!("{} [{}]", mnemonic, register_name(register)),
+        false => format!("{} {}", mnemonic, register_name(register)),
+    }
+}
+
+fn cb_text(opcode: CBOpcode) -> String {
+    match opcode {
+        CBOpcode::SWAP(register) => format!("swap {}", register_8bit(register)),
+        CBOpcode::RLC(register) => format!("rlc {}", register_8bit(register)),
+        CBOpcode::RL(register) => format!("rl {}", register_8bit(register)),
+        CBOpcode::RRC(register) => format!("rrc {}", register_8bit(register)),
+        CBOpcode::RR(register) => format!("rr {}", register_8bit(register)),
+        CBOpcode::SLA(register) => format!("sla {}", register_8bit(register)),
+        CBOpcode::SRA(register) => format!("sra {}", register_8bit(register)),
+        CBOpcode::SRL(register) => format!("srl {}", register_8bit(register)),
+        CBOpcode::BIT(index, register) => format!("bit {}, {}", get_bit_index(index), register_8bit(register)),
+        CBOpcode::SET(index, register) => format!("set {}, {}", get_bit_index(index), register_8bit(register)),
+        CBOpcode::RES(index, register) => format!("res {}, {}", get_bit_index(index), register_8bit(register)),
+    }
+}
+
+// Decodes the instruction at the start of `bytes`, located at `address`
+pub fn decode(bytes: &[u8], address: u16) -> Instruction {
+    let parameter_bytes = OpcodeParameterBytes::from_bytes(bytes);
+    let length = parameter_bytes.length();
+    let next = address.wrapping_add(length);
+    let mut reference = None;
+    let mut jump = false;
+    let mut ends_flow = false;
+    let with_operands = |mnemonic: &str, parameter: OpcodeParameter, reference: &mut Option<u16>| {
+        match operands(parameter, next, reference) {
+            operands if operands.is_empty() => mnemonic.to_string(),
+            operands => format!("{} {}", mnemonic, operands),
+        }
+    };
+    let text = match parameter_bytes.parse_opcode().0 {
+        Opcode::LD(parameter) => {
+            let ldh = matches!(parameter,
+                OpcodeParameter::Register_FF00plusRegister(..) |
+                OpcodeParameter::FF00plusRegister_Register(..) |
+                OpcodeParameter::Register_FF00plusU8(..) |
+                OpcodeParameter::FF00plusU8_Register(..)
+            );
+            match ldh {
+                true => with_operands("ldh", parameter, &mut reference),
+                false => with_operands("ld", parameter, &mut reference),
+            }
+        },
+        Opcode::LDD(parameter) | Opcode::LDI(parameter) | Opcode::LDHL(parameter) => with_operands("ld", parameter, &mut reference),
+        Opcode::PUSH(register) => format!("push {}", register_name(register)),
+        Opcode::POP(register) => format!("pop {}", register_name(register)),
+        Opcode::ADD(parameter) => with_operands("add", parameter, &mut reference),
+        Opcode::ADC(parameter) => with_operands("adc", parameter, &mut reference),
+        Opcode::SBC(parameter) => with_operands("sbc", parameter, &mut reference),
+        Opcode::SUB(parameter) => format!("sub {}", accumulator_operands(parameter, next, &mut reference)),
+        Opcode::AND(parameter) => format!("and {}", accumulator_operands(parameter, next, &mut reference)),
+        Opcode::OR(parameter) => format!("or {}", accumulator_operands(parameter, next, &mut reference)),
+        Opcode::XOR(parameter) => format!("xor {}", accumulator_operands(parameter, next, &mut reference)),
+        Opcode::CP(parameter) => format!("cp {}", accumulator_operands(parameter, next, &mut reference)),
+        Opcode::INC(_, memory, register) => inc_dec("inc", memory, register),
+        Opcode::DEC(_, memory, register) => inc_dec("dec", memory, register),
+        Opcode::DAA => "daa".to_string(),
+        Opcode::CPL => "cpl".to_string(),
+        Opcode::CCF => "ccf".to_string(),
+        Opcode::SCF => "scf".to_string(),
+        Opcode::NOP => "nop".to_string(),
+        Opcode::HALT => "halt".to_string(),
+        Opcode::STOP => "stop".to_string(),
+        Opcode::DI => "di".to_string(),
+        Opcode::EI => "ei".to_string(),
+        Opcode::RLCA => "rlca".to_string(),
+        Opcode::RLA => "rla".to_string(),
+        Opcode::RRCA => "rrca".to_string(),
+        Opcode::RRA => "rra".to_string(),
+        Opcode::JP(parameter) => {
+            ends_flow = matches!(parameter, OpcodeParameter::U16(_) | OpcodeParameter::Register(_));
+            jump = true;
+            with_operands("jp", parameter, &mut reference)
+        },
+        Opcode::JR(parameter) => {
+            ends_flow = matches!(parameter, OpcodeParameter::I8(_));
+            jump = true;
+            with_operands("jr", parameter, &mut reference)
+        },
+        Opcode::CALL(parameter) => {
+            jump = true;
+            with_operands("call", parameter, &mut reference)
+        },
+        Opcode::RST(vector) => {
+            jump = true;
+            reference = Some(vector as u16);
+            format!("rst ${:02X}", vector)
+        },
+        Opcode::RET(parameter) => {
+            ends_flow = matches!(parameter, OpcodeParameter::NoParam);
+            with_operands("ret", parameter, &mut reference)
+        },
+        Opcode::RETI => {
+            ends_flow = true;
+            "reti".to_string()
+        },
+        Opcode::PrefixCB(opcode) => cb_text(opcode),
+        Opcode::IllegalInstruction => {
+            ends_flow = true;
+            format!("db ${:02X}", bytes.first().copied().unwrap_or(0))
+        },
+    };
+    Instruction {
+        text,
+        length,
+        reference,
+        // `jp hl` goes somewhere we can't know
+        jump: match jump {
+            true => reference,
+            false => None,
+        },
+        ends_flow,
+    }
+}
+
+// Text and length of the instruction at the start of `bytes`, located at `address`
+pub fn disassemble(bytes: &[u8], address: u16) -> (String, u16) {
+    let instruction = decode(bytes, address);
+    (instruction.text, instruction.length)
+}
+
+// `[bank:]start[-end]` inside the ROM area
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct RomRange {
+    pub bank: u16,
+    pub start: u16,
+    pub end: u16,
+}
+
+impl RomRange {
+    // Without an end, 256 bytes get disassembled
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let (bank, range) = match text.split_once(':') {
+            Some((bank, range)) => (Some(parse_hex(bank)?), range),
+            None => (None, text),
+        };
+        let (start, end) = match range.split_once('-') {
+            Some((start, end)) => (parse_hex(start)?, Some(parse_hex(end)?)),
+            None => (parse_hex(range)?, None),
+        };
+        let area_end = match start {
+            0x0000..=0x3FFF => 0x3FFF,
+            0x4000..=0x7FFF => 0x7FFF,
+            _ => return Err(format!("{:04X} is not a ROM address", start)),
+        };
+        let end = end.unwrap_or(start.saturating_add(0xFF)).min(area_end);
+        if end < start {
+            return Err(format!("Invalid range: {}", text));
+        }
+        Ok(Self {
+            // Bank 0 is always mapped at 0x0000-0x3FFF
+            bank: match start {
+                0x0000..=0x3FFF => 0,
+                _ => bank.unwrap_or(1),
+            },
+            start,
+            end,
+        })
+    }
+
+    fn contains(&self, address: u16) -> bool {
+        (self.start..=self.end).contains(&address)
+    }
+
+    fn rom_offset(&self, address: u16) -> usize {
+        match address {
+            0x0000..=0x3FFF => address as usize,
+            _ => (self.bank as usize * 0x4000) + (address as usize - 0x4000),
+        }
+    }
+}
+
+// Follows the code from the start of the range through every jump, call and rst
+// landing inside the range. Bytes that aren't reached that way are written as data
+pub fn disassemble_rom(rom: &[u8], range: &RomRange, symbols: &Symbols, out: &mut dyn Write) -> io::Result<()> {
+    let mut instructions = BTreeMap::new();
+    let mut targets = BTreeSet::new();
+    let mut pending = vec![range.start];
+    while let Some(mut address) = pending.pop() {
+        while range.contains(address) && !instructions.contains_key(&address) {
+            let offset = range.rom_offset(address);
+            if offset >= rom.len() {
+                break;
+            }
+            let instruction = decode(&rom[offset..], address);
+            if let Some(target) = instruction.jump {
+                if range.contains(target) {
+                    targets.insert(target);
+                    pending.push(target);
+                }
+            }
+            let ends_flow = instruction.ends_flow;
+            let next = address.checked_add(instruction.length);
+            instructions.insert(address, instruction);
+            match (ends_flow, next) {
+                (false, Some(next)) => address = next,
+                _ => break,
+            };
+        }
+    }
+
+    let mut address = range.start as u32;
+    let mut data = Vec::new();
+    while address <= range.end as u32 {
+        let current = address as u16;
+        let offset = range.rom_offset(current);
+        if offset >= rom.len() {
+            break;
+        }
+        let instruction = instructions.get(&current);
+        let label = symbols.label(range.bank, current);
+        // Flush the data bytes before the next instruction or label
+        if !data.is_empty() && (instruction.is_some() || label.is_some() || data.len() == 8) {
+            write_data(range, address as u16 - data.len() as u16, &data, out)?;
+            data.clear();
+        }
+        match label {
+            Some(label) => writeln!(out, "{}:", label)?,
+            None if targets.contains(&current) => writeln!(out, ".L{:04X}:", current)?,
+            None => {},
+        };
+        match instruction {
+            Some(instruction) => {
+                let end = (offset + instruction.length as usize).min(rom.len());
+                let bytes: Vec<String> = rom[offset..end].iter().map(|byte| format!("{:02X}", byte)).collect();
+                writeln!(out, "    {:02X}:{:04X}  {:<9} {}", range.bank, current, bytes.join(" "), instruction.text_with_labels(symbols, range.bank))?;
+                address += instruction.length as u32;
+            },
+            None => {
+                data.push(rom[offset]);
+                address += 1;
+            },
+        };
+    }
+    if !data.is_empty() {
+        write_data(range, address as u16 - data.len() as u16, &data, out)?;
+    }
+    Ok(())
+}
+
+// Prints a range of a ROM file, labeled with the .sym file next to it if there is one
+pub fn print_rom(rom_filename: &str, range: &RomRange) -> io::Result<()> {
+    let rom = fs::read(rom_filename)?;
+    let symbols = Symbols::for_rom(rom_filename);
+    disassemble_rom(&rom, range, &symbols, &mut io::stdout().lock())
+}
+
+fn write_data(range: &RomRange, address: u16, data: &[u8], out: &mut dyn Write) -> io::Result<()> {
+    let bytes: Vec<String> = data.iter().map(|byte| format!("${:02X}", byte)).collect();
+    writeln!(out, "    {:02X}:{:04X}  db {}", range.bank, address, bytes.join(", "))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_disassemble() {
+        assert_eq!(disassemble(&[0x00], 0x0100), ("nop".to_string(), 1));
+        assert_eq!(disassemble(&[0x3E, 0x12], 0x0100), ("ld a, $12".to_string(), 2));
+        assert_eq!(disassemble(&[0x36, 0x12], 0x0100), ("ld [hl], $12".to_string(), 2));
+        assert_eq!(disassemble(&[0x7E], 0x0100), ("ld a, [hl]".to_string(), 1));
+        assert_eq!(disassemble(&[0x2A], 0x0100), ("ld a, [hl+]".to_string(), 1));
+        assert_eq!(disassemble(&[0x32], 0x0100), ("ld [hl-], a".to_string(), 1));
+        assert_eq!(disassemble(&[0x01, 0x34, 0x12], 0x0100), ("ld bc, $1234".to_string(), 3));
+        assert_eq!(disassemble(&[0xFA, 0x00, 0xC0], 0x0100), ("ld a, [$C000]".to_string(), 3));
+        assert_eq!(disassemble(&[0x08, 0x00, 0xC0], 0x0100), ("ld [$C000], sp".to_string(), 3));
+        assert_eq!(disassemble(&[0xE0, 0x40], 0x0100), ("ldh [$FF40], a".to_string(), 2));
+        assert_eq!(disassemble(&[0xF2], 0x0100), ("ldh a, [c]".to_string(), 1));
+        assert_eq!(disassemble(&[0xF8, 0xFE], 0x0100), ("ld hl, sp-2".to_string(), 2));
+        assert_eq!(disassemble(&[0xE8, 0x02], 0x0100), ("add sp, 2".to_string(), 2));
+        assert_eq!(disassemble(&[0x09], 0x0100), ("add hl, bc".to_string(), 1));
+        assert_eq!(disassemble(&[0x86], 0x0100), ("add a, [hl]".to_string(), 1));
+        assert_eq!(disassemble(&[0xAF], 0x0100), ("xor a".to_string(), 1));
+        assert_eq!(disassemble(&[0xFE, 0x90], 0x0100), ("cp $90".to_string(), 2));
+        assert_eq!(disassemble(&[0x34], 0x0100), ("inc [hl]".to_string(), 1));
+        assert_eq!(disassemble(&[0x0B], 0x0100), ("dec bc".to_string(), 1));
+        assert_eq!(disassemble(&[0x18, 0xFE], 0x0100), ("jr $0100".to_string(), 2));
+        assert_eq!(disassemble(&[0x20, 0x05], 0x0100), ("jr nz, $0107".to_string(), 2));
+        assert_eq!(disassemble(&[0xDA, 0x50, 0x01], 0x0100), ("jp c, $0150".to_string(), 3));
+        assert_eq!(disassemble(&[0xE9], 0x0100), ("jp hl".to_string(), 1));
+        assert_eq!(disassemble(&[0xCD, 0x50, 0x01], 0x0100), ("call $0150".to_string(), 3));
+        assert_eq!(disassemble(&[0xC8], 0x0100), ("ret z".to_string(), 1));
+        assert_eq!(disassemble(&[0xEF], 0x0100), ("rst $28".to_string(), 1));
+        assert_eq!(disassemble(&[0xF5], 0x0100), ("push af".to_string(), 1));
+        assert_eq!(disassemble(&[0xCB, 0x7E], 0x0100), ("bit 7, [hl]".to_string(), 2));
+        assert_eq!(disassemble(&[0xCB, 0x37], 0x0100), ("swap a".to_string(), 2));
+        assert_eq!(disassemble(&[0x10, 0x00], 0x0100), ("stop".to_string(), 2));
+        assert_eq!(disassemble(&[0xD3], 0x0100), ("db $D3".to_string(), 1));
+    }
+
+    #[test]
+    fn test_disassemble_rom() {
+        let mut rom = vec![0; 0x8000];
+        // call 0x0108, jr to itself, 4 bytes of data, then inc a and ret
+        rom[0x0100..0x010A].copy_from_slice(&[0xCD, 0x08, 0x01, 0x18, 0xFE, 0xDE, 0xAD, 0xBE, 0x3C, 0xC9]);
+        let mut symbols = Symbols::new();
+        symbols.insert(0, 0x0108, "Increment");
+        let range = RomRange::parse("0100-0109").unwrap();
+        let mut out = Vec::new();
+        disassemble_rom(&rom, &range, &symbols, &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(lines, vec![
+            "    00:0100  CD 08 01  call Increment",
+            ".L0103:",
+            "    00:0103  18 FE     jr $0103",
+            "    00:0105  db $DE, $AD, $BE",
+            "Increment:",
+            "    00:0108  3C        inc a",
+            "    00:0109  C9        ret",
+        ]);
+    }
+
+    #[test]
+    fn test_parse_rom_range() {
+        assert_eq!(RomRange::parse("0150"), Ok(RomRange { bank: 0, start: 0x0150, end: 0x024F }));
+        assert_eq!(RomRange::parse("02:4000-4010"), Ok(RomRange { bank: 2, start: 0x4000, end: 0x4010 }));
+        assert_eq!(RomRange::parse("7FF0").unwrap().end, 0x7FFF);
+        assert!(RomRange::parse("C000").is_err());
+    }
+}
@@ -1,123 +1,524 @@
 // use std::{thread, time};
+use std::io;
+use std::thread;
+use std::time::Duration;
+use log::debug;
+#[cfg(feature = "frontend")]
 use winit_input_helper::WinitInputHelper;
+#[cfg(feature = "frontend")]
 use winit::event::VirtualKeyCode;
 
-use crate::cpu::{CPU, Cycles};
+use crate::cpu::{CPU, Cycles, Register, Registers};
 use crate::interrupts::Interrupt;
 use crate::bus::Bus;
+#[cfg(feature = "frontend")]
 use crate::joypad::Button;
+use crate::joypad::ButtonMask;
+use crate::config::{Config, SyncMode};
+use crate::serial::SerialDevice;
+use crate::debugger::{Debugger, DebuggerAction};
+use crate::trace::Tracer;
+use crate::movie::{Movie, MovieError, MovieMode};
+use crate::rtc::RtcMode;
+use crate::symbols::Symbols;
+use crate::audio::{AudioSink, NullSink, create_sink, rate_adjustment};
+use crate::rom::{ROMInfo, RomError, load_rom, load_rom_from_bytes};
+use crate::model::Model;
+use crate::palette::{Palettes, ColorCorrection};
+use crate::savestate::{
+    SaveState,
+    StateWriter,
+    StateReader,
+    StateError,
+    STATE_MAGIC,
+    STATE_VERSION,
+};
 #[cfg(not(test))]
 use crate::rom::{save_file};
 
+#[cfg(feature = "frontend")]
+const KEY_BINDINGS: [(VirtualKeyCode, Button); 8] = [
+    (VirtualKeyCode::K, Button::A),
+    (VirtualKeyCode::J, Button::B),
+    (VirtualKeyCode::W, Button::Up),
+    (VirtualKeyCode::S, Button::Down),
+    (VirtualKeyCode::A, Button::Left),
+    (VirtualKeyCode::D, Button::Right),
+    (VirtualKeyCode::N, Button::Start),
+    (VirtualKeyCode::B, Button::Select),
+];
+
+// magic + version + header checksum + global checksum + model + cgb flag
+const STATE_HEADER_LENGTH: usize = 4 + 2 + 1 + 2 + 1 + 1;
+
 pub struct Emulator {
     bus: Bus,
     cpu: CPU,
+    audio_sink: Box<dyn AudioSink>,
+    sync_mode: SyncMode,
+    debugger: Debugger,
+    tracer: Option<Tracer>,
+    // Frames completed since power on, kept in the save states
+    frame_count: u64,
+    movie: Option<(Movie, MovieMode)>,
 }
 
 impl Emulator {
     pub fn new() -> Self {
-        let bus = Bus::new();
-        let cpu = match bus.cgb_mode {
-            true => CPU::new_cgb(),
-            false => CPU::new(),
+        Self::with_bus(Bus::new(), &Config::from_env())
+    }
+
+    pub fn from_rom_bytes(data: Vec<u8>, config: Config) -> Result<Self, RomError> {
+        let rom = load_rom_from_bytes(data, &config)?;
+        Ok(Self::with_bus(Bus::with_rom(rom, &config), &config))
+    }
+
+    pub fn from_file(filename: &str, config: Config) -> Result<Self, RomError> {
+        let rom = load_rom(filename, &config)?;
+        Ok(Self::with_bus(Bus::with_rom(rom, &config), &config))
+    }
+
+    fn with_bus(mut bus: Bus, config: &Config) -> Self {
+        let cpu = match bus.is_boot_rom_mapped() {
+            true => CPU::power_on(bus.cgb_mode),
+            false => CPU::for_model(bus.model, bus.cgb_mode, bus.rom.info()),
+        };
+        // Labels from `game.sym` next to `game.gb`, if there is one
+        let symbols = match bus.rom.info().filename() {
+            "" => Symbols::new(),
+            filename => Symbols::for_rom(filename),
+        };
+        let mut tracer = config.trace.clone().and_then(|options| {
+            let filename = options.filename.clone();
+            match Tracer::create(options) {
+                Ok(tracer) => Some(tracer),
+                Err(err) => {
+                    eprintln!("Could not create the trace file {}: {}", filename, err);
+                    None
+                },
+            }
+        });
+        let audio_sink = match create_sink(&config.audio_backend) {
+            Ok(sink) => sink,
+            Err(err) => {
+                eprintln!("Could not create the audio output, sound will be muted: {}", err);
+                Box::new(NullSink::new())
+            },
         };
+        bus.sound.set_sample_rate(audio_sink.sample_rate());
+        let mut debugger = Debugger::new();
+        if config.debugger {
+            debugger.pause();
+        }
+        if let Some(tracer) = tracer.as_mut() {
+            tracer.set_symbols(symbols.clone());
+        }
+        debugger.set_symbols(symbols);
         Self {
             bus,
             cpu,
+            audio_sink,
+            sync_mode: config.sync_mode,
+            debugger,
+            tracer,
+            frame_count: 0,
+            movie: None,
         }
     }
 
-    pub fn close(&self) {
-        println!("closing emulator");
+    // Also enables the sound emulation, since there is somewhere to send it now
+    pub fn set_audio_sink(&mut self, audio_sink: Box<dyn AudioSink>) {
+        if let Err(err) = self.audio_sink.finish() {
+            eprintln!("Could not finish the previous audio output: {}", err);
+        }
+        self.bus.sound.set_sample_rate(audio_sink.sample_rate());
+        self.bus.sound.set_enabled(true);
+        self.audio_sink = audio_sink;
+    }
 
-        #[cfg(not(test))]
-        match save_file(self.bus.rom.ram(), self.bus.rom.info()) {
-            Err(err) => eprintln!("Could not save file: {}", err),
-            _ => {},
-        };
+    // The tracer gets the same labels as the debugger
+    pub fn set_tracer(&mut self, mut tracer: Option<Tracer>) {
+        if let Some(tracer) = tracer.as_mut() {
+            tracer.set_symbols(self.debugger.symbols().clone());
+        }
+        self.tracer = tracer;
     }
 
-    pub fn handle_input(&mut self, input: &WinitInputHelper) {
-        let mut change = false;
-        if input.key_pressed(VirtualKeyCode::K) {
-            change = true;
-            self.bus.joypad.press(Button::A);
+    // Replaces the labels loaded from the ROM's .sym file
+    pub fn set_symbols(&mut self, symbols: Symbols) {
+        if let Some(tracer) = self.tracer.as_mut() {
+            tracer.set_symbols(symbols.clone());
+        }
+        self.debugger.set_symbols(symbols);
+    }
+
+    pub fn set_serial_device(&mut self, device: Box<dyn SerialDevice>) {
+        self.bus.serial.set_device(device);
+    }
+
+    // Whether the frontend should leave the pacing to `sync_audio` instead of a timer
+    pub fn is_audio_synced(&self) -> bool {
+        self.sync_mode == SyncMode::Audio &&
+            self.bus.sound.is_enabled() &&
+            self.audio_sink.buffered().is_some()
+    }
+
+    // Blocks while the audio output has plenty of samples left to play, and
+    // adjusts the resampling ratio to keep the buffer around its target
+    pub fn sync_audio(&mut self) {
+        let target = self.audio_sink.target_buffered();
+        while let Some(buffered) = self.audio_sink.buffered() {
+            if buffered <= target * 2 {
+                self.bus.sound.set_rate_adjustment(rate_adjustment(buffered, target));
+                break;
+            }
+            thread::sleep(Duration::from_millis(1));
         }
-        if input.key_pressed(VirtualKeyCode::J) {
-            change = true;
-            self.bus.joypad.press(Button::B);
+    }
+
+    fn flush_audio(&mut self) {
+        let samples = self.bus.sound.take_samples();
+        if !samples.is_empty() {
+            self.audio_sink.push_samples(&samples);
         }
-        if input.key_pressed(VirtualKeyCode::W) {
-            change = true;
-            self.bus.joypad.press(Button::Up);
+    }
+
+    // The emulation stops at the next instruction, until the debugger resumes it
+    pub fn pause(&mut self) {
+        self.debugger.pause();
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.debugger.is_paused()
+    }
+
+    pub fn resume(&mut self) {
+        self.debugger.resume_running(&self.cpu);
+    }
+
+    // Resumes for a single instruction, then pauses again
+    pub fn resume_step(&mut self) {
+        self.debugger.resume_step(&self.cpu);
+    }
+
+    pub fn debugger_mut(&mut self) -> &mut Debugger {
+        &mut self.debugger
+    }
+
+    pub fn debug_command(&mut self, line: &str, out: &mut dyn io::Write) -> io::Result<DebuggerAction> {
+        self.debugger.execute(line, &self.cpu, &mut self.bus, out)
+    }
+
+    // Interactive debugger on the terminal, returns false if it was asked to quit
+    pub fn debugger_prompt(&mut self) -> bool {
+        self.debugger.prompt(&self.cpu, &mut self.bus) != DebuggerAction::Quit
+    }
+
+    fn should_break(&mut self) -> bool {
+        self.debugger.is_active() && self.debugger.should_break(&self.cpu, &self.bus)
+    }
+
+    pub fn rom_info(&self) -> &ROMInfo {
+        self.bus.rom.info()
+    }
+
+    pub fn save_state(&self) -> Vec<u8> {
+        let info = self.bus.rom.info();
+        let mut state = StateWriter::new();
+        state.write_bytes(&STATE_MAGIC);
+        state.write_u16(STATE_VERSION);
+        state.write_u8(info.header_checksum());
+        state.write_u16(info.global_checksum());
+        state.write_u8(self.bus.model.to_u8());
+        state.write_bool(self.bus.cgb_mode);
+        self.bus.save_state(&mut state);
+        self.cpu.save_state(&mut state);
+        state.write_u64(self.frame_count);
+        state.into_bytes()
+    }
+
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), StateError> {
+        let mut state = StateReader::new(data);
+        let mut magic = [0; 4];
+        state.read_bytes(&mut magic).map_err(|_| StateError::BadMagic)?;
+        if magic != STATE_MAGIC {
+            return Err(StateError::BadMagic);
         }
-        if input.key_pressed(VirtualKeyCode::S) {
-            change = true;
-            self.bus.joypad.press(Button::Down);
+        let version = state.read_u16()?;
+        if version != STATE_VERSION {
+            return Err(StateError::UnsupportedVersion(version));
         }
-        if input.key_pressed(VirtualKeyCode::A) {
-            change = true;
-            self.bus.joypad.press(Button::Left);
+        let info = self.bus.rom.info();
+        if state.read_u8()? != info.header_checksum() || state.read_u16()? != info.global_checksum() {
+            return Err(StateError::RomMismatch);
         }
-        if input.key_pressed(VirtualKeyCode::D) {
-            change = true;
-            self.bus.joypad.press(Button::Right);
+        if state.read_u8()? != self.bus.model.to_u8() || state.read_bool()? != self.bus.cgb_mode {
+            return Err(StateError::ModeMismatch);
         }
-        if input.key_pressed(VirtualKeyCode::N) {
-            change = true;
-            self.bus.joypad.press(Button::Start);
+
+        // Keep a copy of the current state in case the file turns out to be corrupted halfway through
+        let backup = self.save_state();
+        let mut result = self.load_machine_state(&mut state);
+        // Movies can only go back to their own frames
+        if result.is_ok() && self.movie.as_ref().is_some_and(|(movie, _)| !movie.contains_frame(self.frame_count)) {
+            result = Err(StateError::Invalid("the state is not part of the current movie"));
         }
-        if input.key_pressed(VirtualKeyCode::B) {
-            change = true;
-            self.bus.joypad.press(Button::Select);
+        if result.is_err() {
+            self.load_machine_state(&mut StateReader::new(&backup[STATE_HEADER_LENGTH..]))
+                .expect("Could not restore the previous emulator state");
+            return result;
         }
+        self.seek_movie();
+        Ok(())
+    }
 
-        if input.key_released(VirtualKeyCode::K) {
-            change = true;
-            self.bus.joypad.release(Button::A);
+    fn load_machine_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.bus.load_state(state)?;
+        self.cpu.load_state(state)?;
+        self.frame_count = state.read_u64()?;
+        if !state.is_empty() {
+            return Err(StateError::Invalid("unexpected data at the end of the file"));
         }
-        if input.key_released(VirtualKeyCode::J) {
-            change = true;
-            self.bus.joypad.release(Button::B);
+        Ok(())
+    }
+
+    pub fn close(&mut self) {
+        debug!("closing emulator");
+
+        self.flush_audio();
+        if let Err(err) = self.audio_sink.finish() {
+            eprintln!("Could not finish the audio output: {}", err);
         }
-        if input.key_released(VirtualKeyCode::W) {
-            change = true;
-            self.bus.joypad.release(Button::Up);
+        if let Some(tracer) = self.tracer.as_mut() {
+            if let Err(err) = tracer.flush() {
+                eprintln!("Could not write the trace: {}", err);
+            }
         }
-        if input.key_released(VirtualKeyCode::S) {
-            change = true;
-            self.bus.joypad.release(Button::Down);
+
+        #[cfg(not(test))]
+        if let Err(err) = save_file(self.bus.rom.as_ref()) {
+            eprintln!("Could not save file: {}", err);
         }
-        if input.key_released(VirtualKeyCode::A) {
-            change = true;
-            self.bus.joypad.release(Button::Left);
+    }
+
+    pub fn model(&self) -> Model {
+        self.bus.model
+    }
+
+    // Running with the Gameboy Color features
+    pub fn cgb_mode(&self) -> bool {
+        self.bus.cgb_mode
+    }
+
+    pub fn frame_count(&self) -> u64 {
+        self.frame_count
+    }
+
+    fn check_movie_rtc(&self) -> Result<(), MovieError> {
+        match self.bus.rom.rtc().is_some_and(|rtc| rtc.mode() == RtcMode::WallClock) {
+            true => Err(MovieError::WallClockRtc),
+            false => Ok(()),
         }
-        if input.key_released(VirtualKeyCode::D) {
-            change = true;
-            self.bus.joypad.release(Button::Right);
+    }
+
+    // Records the buttons of every frame run with `run_frame` from the current state on
+    pub fn record_movie(&mut self) -> Result<(), MovieError> {
+        self.check_movie_rtc()?;
+        let info = self.bus.rom.info();
+        let movie = Movie {
+            header_checksum: info.header_checksum(),
+            global_checksum: info.global_checksum(),
+            model: self.bus.model,
+            from_power_on: self.frame_count == 0,
+            rerecords: 0,
+            start_frame: self.frame_count,
+            state: self.save_state(),
+            frames: Vec::new(),
+        };
+        self.movie = Some((movie, MovieMode::Recording));
+        Ok(())
+    }
+
+    // Goes back to the start of the movie and takes the input from it from now on
+    pub fn play_movie(&mut self, movie: Movie) -> Result<(), MovieError> {
+        let info = self.bus.rom.info();
+        if movie.header_checksum != info.header_checksum() || movie.global_checksum != info.global_checksum() {
+            return Err(MovieError::RomMismatch);
         }
-        if input.key_released(VirtualKeyCode::N) {
-            change = true;
-            self.bus.joypad.release(Button::Start);
+        if movie.model != self.bus.model {
+            return Err(MovieError::ModelMismatch);
+        }
+        self.check_movie_rtc()?;
+        self.movie = None;
+        self.load_state(&movie.state).map_err(MovieError::State)?;
+        self.movie = Some((movie, MovieMode::Playing));
+        self.seek_movie();
+        Ok(())
+    }
+
+    // Records from the current frame of the movie being played, replacing the rest of it
+    pub fn rerecord_movie(&mut self) -> bool {
+        match self.movie.as_mut() {
+            Some((movie, mode)) if *mode != MovieMode::Recording => {
+                movie.truncate(self.frame_count);
+                movie.rerecords += 1;
+                *mode = MovieMode::Recording;
+                true
+            },
+            _ => false,
         }
-        if input.key_released(VirtualKeyCode::B) {
-            change = true;
-            self.bus.joypad.release(Button::Select);
+    }
+
+    pub fn stop_movie(&mut self) -> Option<Movie> {
+        self.movie.take().map(|(movie, _)| movie)
+    }
+
+    pub fn movie(&self) -> Option<&Movie> {
+        self.movie.as_ref().map(|(movie, _)| movie)
+    }
+
+    pub fn movie_mode(&self) -> Option<MovieMode> {
+        self.movie.as_ref().map(|(_, mode)| *mode)
+    }
+
+    // After jumping to another frame: recordings drop what came after it, playback continues from there
+    fn seek_movie(&mut self) {
+        let frame = self.frame_count;
+        if let Some((movie, mode)) = self.movie.as_mut() {
+            match mode {
+                MovieMode::Recording => {
+                    movie.truncate(frame);
+                    movie.rerecords += 1;
+                },
+                _ => *mode = match frame < movie.end_frame() {
+                    true => MovieMode::Playing,
+                    false => MovieMode::Finished,
+                },
+            };
+        }
+    }
+
+    // Called before running a frame
+    fn movie_input(&mut self) {
+        let frame = self.frame_count;
+        let buttons = self.buttons();
+        let input = match self.movie.as_mut() {
+            Some((movie, MovieMode::Recording)) => {
+                movie.record(frame, buttons);
+                None
+            },
+            Some((movie, MovieMode::Playing)) => movie.input(frame),
+            _ => None,
+        };
+        if let Some(input) = input {
+            self.apply_buttons(input);
+        }
+    }
+
+    fn end_frame(&mut self) {
+        self.frame_count += 1;
+        if let Some((movie, mode)) = self.movie.as_mut() {
+            if *mode == MovieMode::Playing && self.frame_count >= movie.end_frame() {
+                *mode = MovieMode::Finished;
+            }
+        }
+    }
+
+    // 2 bit shade of each pixel of the last frame, 0 is the lightest
+    pub fn frame_shades(&self) -> &[u8] {
+        self.bus.ppu.shades()
+    }
+
+    // The shades aren't part of the save states, this puts back the ones saved next to one
+    pub fn set_frame_shades(&mut self, shades: &[u8]) {
+        self.bus.ppu.set_shades(shades);
+    }
+
+    // Only used in DMG mode, Gameboy Color colors come from the game or the boot ROM
+    pub fn dmg_palettes(&self) -> Palettes {
+        self.bus.ppu.dmg_palettes()
+    }
+
+    pub fn set_dmg_palettes(&mut self, palettes: Palettes) {
+        self.bus.ppu.set_dmg_palettes(palettes);
+    }
+
+    // Applies to the Gameboy Color colors, DMG games on a CGB included
+    pub fn color_correction(&self) -> ColorCorrection {
+        self.bus.ppu.color_correction()
+    }
+
+    pub fn set_color_correction(&mut self, correction: ColorCorrection) {
+        self.bus.ppu.set_color_correction(correction);
+    }
+
+    pub fn buttons(&self) -> ButtonMask {
+        self.bus.joypad.buttons()
+    }
+
+    // Holds down the buttons in the mask and releases the rest.
+    // Ignored while a movie is playing, the buttons come from it
+    pub fn set_buttons(&mut self, buttons: ButtonMask) {
+        if self.movie_mode() != Some(MovieMode::Playing) {
+            self.apply_buttons(buttons);
         }
-        if change {
+    }
+
+    fn apply_buttons(&mut self, buttons: ButtonMask) {
+        let pressed = buttons.0 & !self.bus.joypad.buttons().0;
+        self.bus.joypad.set_buttons(buttons);
+        if pressed != 0 {
             self.bus.interrupts.request(Interrupt::Joypad);
         }
     }
 
+    // Goes through `set_buttons` like movies do, so recordings replay the same way
+    #[cfg(feature = "frontend")]
+    pub fn handle_input(&mut self, input: &WinitInputHelper) {
+        let mut buttons = self.buttons();
+        for (key, button) in KEY_BINDINGS {
+            let mask = ButtonMask::from_button(button);
+            if input.key_pressed(key) {
+                buttons = buttons | mask;
+            }
+            if input.key_released(key) {
+                buttons = ButtonMask(buttons.0 & !mask.0);
+            }
+        }
+        self.set_buttons(buttons);
+    }
+
+    fn trace(&mut self) {
+        let tracer = match self.tracer.as_mut() {
+            Some(tracer) => tracer,
+            None => return,
+        };
+        if !self.cpu.will_execute(&self.bus) {
+            return;
+        }
+        if let Err(err) = tracer.trace(&self.cpu, &self.bus) {
+            eprintln!("Could not write the trace, stopping it: {}", err);
+            self.tracer = None;
+        }
+    }
+
     fn tick(&mut self, frame_buffer: &mut [u8]) {
+        self.trace();
+        let pc = self.cpu.registers().get(Register::PC);
         self.cpu.run(&mut self.bus);
+        if let Some(hit) = self.bus.take_watch_hit() {
+            self.debugger.watch_hit(hit, pc, &self.bus);
+        }
         let cycles = self.cpu.get_last_op_cycles().to_t();
         self.bus.ppu.do_cycles(&mut self.bus.interrupts, cycles, frame_buffer);
-        self.bus.sound.do_cycles(cycles);
         self.bus.timer.do_cycles(&mut self.bus.interrupts, cycles);
         if self.bus.double_speed_mode() {
             self.bus.timer.do_cycles(&mut self.bus.interrupts, Cycles(cycles.0 * 3.0));
         }
+        let double_speed = self.bus.double_speed_mode();
+        self.bus.sound.do_cycles(cycles, self.bus.timer.div(), double_speed);
+        self.bus.serial.do_cycles(&mut self.bus.interrupts, cycles, double_speed);
+        self.bus.rom.do_cycles(cycles);
 
         // 1 CPU cycle = 238.42ns
         // thread::sleep(time::Duration::from_nanos((self.cpu.get_last_op_cycles().0 * 238).try_into().unwrap()));
@@ -126,42 +527,486 @@ impl Emulator {
     pub fn run(&mut self, cpu_cycles: Cycles, frame_buffer: &mut [u8]) {
         self.cpu.reset_cycles();
         while self.cpu.get_cycles().to_t().0 <= cpu_cycles.0 {
+            if self.should_break() {
+                break;
+            }
             self.tick(frame_buffer);
         }
+        self.flush_audio();
     }
 
     pub fn run_frame(&mut self, frame_buffer: &mut [u8]) {
+        self.movie_input();
         self.cpu.reset_cycles();
         let mut frame_started = true;
+        let mut completed = true;
         while self.bus.ppu.lcd_y() < 144 || frame_started {
+            if self.should_break() {
+                completed = false;
+                break;
+            }
             self.tick(frame_buffer);
             if self.bus.ppu.lcd_y() == 0 {
                 frame_started = false;
             }
         }
+        if completed {
+            self.end_frame();
+        }
+        self.flush_audio();
+    }
+
+    // Runs a single instruction, or a single interrupt dispatch or halted cycle.
+    // Returns the t-cycles it took
+    pub fn step(&mut self, frame_buffer: &mut [u8]) -> Cycles {
+        self.tick(frame_buffer);
+        self.flush_audio();
+        self.cpu.get_last_op_cycles().to_t()
+    }
+
+    pub fn registers(&self) -> &Registers {
+        self.cpu.registers()
+    }
+
+    pub fn registers_mut(&mut self) -> &mut Registers {
+        self.cpu.registers_mut()
+    }
+
+    pub fn register(&self, register: Register) -> u16 {
+        self.cpu.registers().get(register)
+    }
+
+    pub fn set_register(&mut self, register: Register, value: u16) {
+        self.cpu.registers_mut().set(register, value);
+    }
+
+    // Reads through the bus like the CPU would, without side effects
+    pub fn peek(&self, address: u16) -> u8 {
+        self.bus.peek(address)
+    }
+
+    // Writes through the bus like the CPU would, so writes to ROM switch banks
+    pub fn poke(&mut self, address: u16, data: u8) {
+        self.bus.write(address, data);
+        // Not the game's doing, don't let it trigger a watchpoint
+        self.bus.take_watch_hit();
+    }
+
+    // `length` bytes starting at `address`, wrapping around at the end of the address space
+    pub fn read_range(&self, address: u16, length: usize) -> Vec<u8> {
+        (0..length).map(|offset| self.bus.peek(address.wrapping_add(offset as u16))).collect()
     }
 
-    pub fn cpu_loop(&mut self) {
-        let mut exit = false;
-        let mut frame: [u8; 144 * 160 * 4] = [0; 144 * 160 * 4];
-        while !exit {
-            self.cpu.run(&mut self.bus);
-            let cycles = self.cpu.get_last_op_cycles().to_t();
-            self.bus.ppu.do_cycles(&mut self.bus.interrupts, cycles, &mut frame);
-            self.bus.timer.do_cycles(&mut self.bus.interrupts, cycles);
-
-            // exit = self.cpu.get_exec_calls_count() >= 1258895; // log 1
-            exit = self.cpu.get_exec_calls_count() >= 161502; // log 2
-            // exit = self.cpu.get_exec_calls_count() >= 1068422; // log 3
-            // exit = self.cpu.get_exec_calls_count() >= 1262766; // log 4
-            // exit = self.cpu.get_exec_calls_count() >= 1763388; // log 5
-            // exit = self.cpu.get_exec_calls_count() >= 1763388; // log 5
-            // exit = self.cpu.get_exec_calls_count() >= 243272; // log 6
-            // exit = self.cpu.get_exec_calls_count() >= 287416; // log 7
-            // exit = self.cpu.get_exec_calls_count() >= 223892; // log 8
-            // exit = self.cpu.get_exec_calls_count() >= 4420382; // log 9
-            // exit = self.cpu.get_exec_calls_count() >= 6714723; // log 10
-            // exit = self.cpu.get_exec_calls_count() >= 7429762; // log 11
+    // Reads a specific ROM, cartridge RAM or work RAM bank, mapped or not. None if there is no such bank
+    pub fn peek_banked(&self, bank: u16, address: u16) -> Option<u8> {
+        self.bus.peek_banked(bank, address)
+    }
+
+    // Writes a specific cartridge RAM or work RAM bank, mapped or not. False if there is no such bank
+    pub fn poke_banked(&mut self, bank: u16, address: u16, data: u8) -> bool {
+        let written = self.bus.poke_banked(bank, address, data);
+        self.bus.take_watch_hit();
+        written
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rom::BootRom;
+    use crate::palette::preset;
+    use crate::rom::tests::{rom_with_program, set_header_checksum};
+
+    fn test_rom() -> Vec<u8> {
+        // JR -2: spin forever at the entry point
+        rom_with_program(&[0x18, 0xFE])
+    }
+
+    #[test]
+    fn test_from_rom_bytes() {
+        let mut emulator = Emulator::from_rom_bytes(test_rom(), Config::new()).unwrap();
+        let mut frame = vec![0; 144 * 160 * 4];
+        emulator.run_frame(&mut frame);
+        assert_eq!(emulator.bus.read(0x0100), 0x18);
+        assert_eq!(emulator.bus.ppu.lcd_y(), 144);
+    }
+
+    #[test]
+    fn test_from_rom_bytes_bad_checksum() {
+        let mut data = test_rom();
+        data[0x014D] = data[0x014D].wrapping_add(1);
+        assert!(matches!(Emulator::from_rom_bytes(data, Config::new()), Err(RomError::BadHeaderChecksum)));
+    }
+
+    // Paused by the debugger at the entry point of a ROM that calls
+    // 0x0150 and jumps back to it forever, NOP and RET at 0x0150
+    fn debugger_emulator(frame: &mut [u8]) -> Emulator {
+        let mut data = test_rom();
+        data[0x0100..0x0105].copy_from_slice(&[0xCD, 0x50, 0x01, 0x18, 0xFB]);
+        data[0x0150..0x0152].copy_from_slice(&[0x00, 0xC9]);
+        let config = Config {
+            debugger: true,
+            ..Config::new()
+        };
+        let mut emulator = Emulator::from_rom_bytes(data, config).unwrap();
+        emulator.run_frame(frame);
+        emulator
+    }
+
+    fn pc(emulator: &Emulator) -> u16 {
+        emulator.registers().get(Register::PC)
+    }
+
+    #[test]
+    fn test_debugger() {
+        let mut frame = vec![0; 144 * 160 * 4];
+        let mut emulator = debugger_emulator(&mut frame);
+        let mut out = Vec::new();
+        assert!(emulator.is_paused());
+        assert_eq!(pc(&emulator), 0x0100);
+
+        assert_eq!(emulator.debug_command("next", &mut out).unwrap(), DebuggerAction::Resume);
+        emulator.run_frame(&mut frame);
+        assert_eq!(pc(&emulator), 0x0103);
+
+        assert_eq!(emulator.debug_command("step", &mut out).unwrap(), DebuggerAction::Resume);
+        emulator.run_frame(&mut frame);
+        assert_eq!(pc(&emulator), 0x0100);
+
+        assert_eq!(emulator.debug_command("break 0151", &mut out).unwrap(), DebuggerAction::Stay);
+        emulator.debug_command("continue", &mut out).unwrap();
+        emulator.run_frame(&mut frame);
+        assert_eq!(pc(&emulator), 0x0151);
+        assert!(emulator.is_paused());
+
+        emulator.debug_command("finish", &mut out).unwrap();
+        emulator.run_frame(&mut frame);
+        assert_eq!(pc(&emulator), 0x0103);
+        emulator.debug_command("regs", &mut out).unwrap();
+        assert!(String::from_utf8_lossy(&out).contains("SP: FFFE  PC: 00:0103"));
+
+        // A banked breakpoint doesn't match other banks
+        emulator.debug_command("delete", &mut out).unwrap();
+        emulator.debug_command("break 01:0151", &mut out).unwrap();
+        emulator.debug_command("c", &mut out).unwrap();
+        emulator.run_frame(&mut frame);
+        assert!(!emulator.is_paused());
+
+        assert_eq!(emulator.debug_command("quit", &mut Vec::new()).unwrap(), DebuggerAction::Quit);
+    }
+
+    #[test]
+    fn test_debugger_memory_dump() {
+        let mut frame = vec![0; 144 * 160 * 4];
+        let mut emulator = debugger_emulator(&mut frame);
+        // Lengths are decimal
+        let mut out = Vec::new();
+        emulator.debug_command("mem 0100 16", &mut out).unwrap();
+        assert_eq!(String::from_utf8_lossy(&out), "0100: CD 50 01 18 FB 00 00 00 00 00 00 00 00 00 00 00\n");
+    }
+
+    #[test]
+    fn test_debugger_watchpoints() {
+        let mut frame = vec![0; 144 * 160 * 4];
+        let mut emulator = debugger_emulator(&mut frame);
+        let mut out = Vec::new();
+
+        // The CALL pushes the return address
+        emulator.debug_command("watch w FFFC-FFFD", &mut out).unwrap();
+        emulator.debug_command("c", &mut out).unwrap();
+        emulator.run_frame(&mut frame);
+        assert!(emulator.is_paused());
+        assert_eq!(pc(&emulator), 0x0150);
+        let reason = emulator.debugger_mut().pause_reason().unwrap().to_string();
+        assert!(reason.starts_with("Watchpoint 0: write FFFC = 03 by 00:0100"), "{}", reason);
+
+        // The RET reads it back, but not this value
+        emulator.debug_command("unwatch", &mut out).unwrap();
+        emulator.debug_command("watch r FFFC FF", &mut out).unwrap();
+        emulator.debug_command("c", &mut out).unwrap();
+        emulator.run_frame(&mut frame);
+        assert!(!emulator.is_paused());
+    }
+
+    #[test]
+    fn test_debugger_symbols() {
+        let mut frame = vec![0; 144 * 160 * 4];
+        let mut emulator = debugger_emulator(&mut frame);
+        let mut out = Vec::new();
+
+        emulator.set_symbols(Symbols::parse("00:0150 Func\n00:0100 Main\n"));
+        emulator.debug_command("break Func", &mut out).unwrap();
+        emulator.debug_command("c", &mut out).unwrap();
+        emulator.run_frame(&mut frame);
+        assert_eq!(pc(&emulator), 0x0150);
+        emulator.debug_command("disasm Main 1", &mut out).unwrap();
+        let output = String::from_utf8_lossy(&out);
+        assert!(output.contains("Breakpoint 0 at 0150 (Func)"), "{}", output);
+        assert!(output.contains("Main:\n  00:0100  CD 50 01  call Func"), "{}", output);
+    }
+
+    #[test]
+    fn test_memory_access() {
+        // CGB only MBC5 cartridge, 4 ROM banks and 4 RAM banks
+        let mut data = vec![0; 0x10000];
+        data[0x0100..0x0102].copy_from_slice(&[0x18, 0xFE]);
+        data[0x0143] = 0xC0;
+        data[0x0147] = 0x1B;
+        data[0x0148] = 0x01;
+        data[0x0149] = 0x03;
+        set_header_checksum(&mut data);
+        data[0x4000 * 2 + 0x10] = 0x42;
+        let mut emulator = Emulator::from_rom_bytes(data, Config::new()).unwrap();
+
+        emulator.poke(0xC000, 0x12);
+        emulator.poke(0xC001, 0x34);
+        assert_eq!(emulator.read_range(0xC000, 3), vec![0x12, 0x34, 0x00]);
+        assert_eq!(emulator.read_range(0xFFFF, 2).len(), 2);
+
+        // ROM banks
+        assert_eq!(emulator.peek(0x4010), 0x00);
+        assert_eq!(emulator.peek_banked(2, 0x4010), Some(0x42));
+        assert_eq!(emulator.peek_banked(4, 0x4010), None);
+        assert!(!emulator.poke_banked(2, 0x4010, 0x00));
+
+        // Cartridge RAM banks, even with the RAM disabled
+        assert!(emulator.poke_banked(3, 0xA123, 0x99));
+        assert_eq!(emulator.peek_banked(3, 0xA123), Some(0x99));
+        assert!(!emulator.poke_banked(4, 0xA123, 0x99));
+        emulator.poke(0x0000, 0x0A);
+        emulator.poke(0x4000, 0x03);
+        assert_eq!(emulator.peek(0xA123), 0x99);
+
+        // Work RAM banks
+        assert!(emulator.poke_banked(5, 0xD000, 0x55));
+        assert_eq!(emulator.peek(0xD000), 0x00);
+        emulator.poke(0xFF70, 5);
+        assert_eq!(emulator.peek(0xD000), 0x55);
+        assert_eq!(emulator.peek_banked(0, 0xD000), None);
+        assert_eq!(emulator.peek_banked(8, 0xD000), None);
+        assert_eq!(emulator.peek_banked(7, 0xC001), Some(0x34));
+
+        emulator.set_register(Register::HL, 0xC000);
+        assert_eq!(emulator.register(Register::H), 0xC0);
+        assert_eq!(emulator.registers().get(Register::HL), 0xC000);
+    }
+
+    #[test]
+    fn test_boot_rom() {
+        let mut boot_rom = vec![0; 0x100];
+        // ld sp, $FFFE / ld a, $42 / ld [$C000], a / jp $00FC
+        boot_rom[0x00..0x0B].copy_from_slice(&[0x31, 0xFE, 0xFF, 0x3E, 0x42, 0xEA, 0x00, 0xC0, 0xC3, 0xFC, 0x00]);
+        // ld a, $01 / ldh [$50], a, then falls through to the cartridge entry point
+        boot_rom[0xFC..0x100].copy_from_slice(&[0x3E, 0x01, 0xE0, 0x50]);
+        let config = Config {
+            boot_rom: Some(BootRom::new(boot_rom).unwrap()),
+            ..Config::new()
+        };
+        let mut emulator = Emulator::from_rom_bytes(test_rom(), config).unwrap();
+        let mut frame = vec![0; 144 * 160 * 4];
+        assert_eq!(emulator.register(Register::PC), 0x0000);
+        assert_eq!(emulator.peek(0x0000), 0x31);
+        assert_eq!(emulator.peek(0x0100), 0x18);
+        for _ in 0..6 {
+            emulator.step(&mut frame);
         }
+        assert_eq!(emulator.register(Register::PC), 0x0100);
+        assert_eq!(emulator.register(Register::SP), 0xFFFE);
+        assert_eq!(emulator.peek(0xC000), 0x42);
+        assert_eq!(emulator.peek(0x0000), 0x00);
+        assert!(!emulator.bus.is_boot_rom_mapped());
+
+        // A CGB boot ROM doesn't fit the DMG model, it starts right at the cartridge then
+        let config = Config {
+            boot_rom: Some(BootRom::new(vec![0xFF; 0x900]).unwrap()),
+            ..Config::new()
+        };
+        let emulator = Emulator::from_rom_bytes(test_rom(), config).unwrap();
+        assert_eq!(emulator.register(Register::PC), 0x0100);
+        assert_eq!(emulator.peek(0x0000), 0x00);
+        assert!(BootRom::new(vec![0; 0x200]).is_err());
+        let cgb_boot_rom = BootRom::new(vec![0xAA; 0x900]).unwrap();
+        assert_eq!(cgb_boot_rom.read(0x00FF), Some(0xAA));
+        assert_eq!(cgb_boot_rom.read(0x0150), None);
+        assert_eq!(cgb_boot_rom.read(0x0200), Some(0xAA));
+
+        // The boot ROM has to match the model, not the game
+        let config = Config {
+            model: Some(Model::CGB),
+            boot_rom: Some(cgb_boot_rom),
+            ..Config::new()
+        };
+        let emulator = Emulator::from_rom_bytes(test_rom(), config).unwrap();
+        assert!(!emulator.cgb_mode());
+        assert!(emulator.bus.is_boot_rom_mapped());
+        assert_eq!(emulator.register(Register::PC), 0x0000);
+        assert_eq!(emulator.peek(0x0000), 0xAA);
+        let config = Config {
+            model: Some(Model::CGB),
+            boot_rom: Some(BootRom::new(vec![0x31; 0x100]).unwrap()),
+            ..Config::new()
+        };
+        let emulator = Emulator::from_rom_bytes(test_rom(), config).unwrap();
+        assert!(!emulator.bus.is_boot_rom_mapped());
+        assert_eq!(emulator.register(Register::PC), 0x0100);
+    }
+
+    #[test]
+    fn test_models() {
+        let emulator = |model: Option<Model>, cgb_flag: u8| {
+            let mut data = test_rom();
+            // Nintendo game with a single letter title
+            data[0x0134] = b'X';
+            data[0x0143] = cgb_flag;
+            data[0x014B] = 0x01;
+            set_header_checksum(&mut data);
+            Emulator::from_rom_bytes(data, Config { model, ..Config::new() }).unwrap()
+        };
+        let registers = |emulator: &Emulator| [Register::AF, Register::BC, Register::DE, Register::HL].map(|register| emulator.register(register));
+
+        let dmg = emulator(None, 0x00);
+        assert_eq!(dmg.model(), Model::DMG);
+        assert_eq!(registers(&dmg), [0x01B0, 0x0013, 0x00D8, 0x014D]);
+        assert_eq!(dmg.peek(0xFF46), 0xFF);
+        assert_eq!(registers(&emulator(Some(Model::MGB), 0x00)), [0xFFB0, 0x0013, 0x00D8, 0x014D]);
+        assert_eq!(registers(&emulator(Some(Model::SGB), 0x00)), [0x0100, 0x0014, 0x0000, 0xC060]);
+
+        // DMG games on a Gameboy Color
+        let mut cgb = emulator(Some(Model::CGB), 0x00);
+        assert!(!cgb.cgb_mode());
+        assert_eq!(registers(&cgb), [0x1180, 0x5800, 0x0008, 0x991A]);
+        assert_eq!(cgb.peek(0xFF46), 0x00);
+        cgb.bus.write(0xFF70, 0x02);
+        assert_eq!(cgb.bus.read(0xFF70), 0xFF);
+        let agb = emulator(Some(Model::AGB), 0x00);
+        assert_eq!(registers(&agb), [0x1100, 0x5900, 0x0008, 0x991A]);
+        assert!(matches!(cgb.load_state(&agb.save_state()), Err(StateError::ModeMismatch)));
+
+        // Gameboy Color games
+        let mut cgb = emulator(None, 0x80);
+        assert_eq!(cgb.model(), Model::CGB);
+        assert!(cgb.cgb_mode());
+        assert_eq!(registers(&cgb), [0x1180, 0x0000, 0xFF56, 0x000D]);
+        cgb.bus.write(0xFF70, 0x02);
+        assert_eq!(cgb.bus.read(0xFF70), 0x02);
+        assert_eq!(registers(&emulator(Some(Model::AGB), 0x80)), [0x1100, 0x0100, 0xFF56, 0x000D]);
+        assert!(!emulator(Some(Model::DMG), 0x80).cgb_mode());
+    }
+
+    #[test]
+    fn test_compat_palette() {
+        let mut frame = vec![0; 144 * 160 * 4];
+        let mut dmg = Emulator::from_rom_bytes(test_rom(), Config::new()).unwrap();
+        dmg.run_frame(&mut frame);
+        assert_eq!(frame[..3], [0x83, 0xE6, 0xCD]);
+
+        // The lightest color of the default palette, then the darkest of the inverted one
+        let mut cgb = Emulator::from_rom_bytes(test_rom(), Config { model: Some(Model::CGB), ..Config::new() }).unwrap();
+        cgb.run_frame(&mut frame);
+        assert_eq!(frame[..3], [0xFF, 0xFF, 0xFF]);
+        let config = Config {
+            model: Some(Model::AGB),
+            compat_palette: Palettes::from_buttons("right+b"),
+            ..Config::new()
+        };
+        let mut agb = Emulator::from_rom_bytes(test_rom(), config).unwrap();
+        agb.run_frame(&mut frame);
+        assert_eq!(frame[..3], [0x00, 0x00, 0x00]);
+        // DMG games keep their shades whatever the colors are
+        assert_eq!(agb.frame_shades()[0], 0);
+
+        // The boot ROM colors go through the color correction like the game's own would
+        cgb.set_color_correction(ColorCorrection::GbcLcd);
+        cgb.run_frame(&mut frame);
+        assert_eq!(frame[..3], [0xF8, 0xF8, 0xF8]);
+        assert_eq!(cgb.color_correction(), ColorCorrection::GbcLcd);
+
+        // A CGB boot ROM writes the palette itself, then the color RAM is locked
+        let mut boot_rom = vec![0x00; 0x900];
+        let program = [
+            0x3E, 0x80, 0xE0, 0x68,
+            // Red as the first background color
+            0x3E, 0x1F, 0xE0, 0x69,
+            0x3E, 0x00, 0xE0, 0x69,
+            0x3E, 0xFC, 0xE0, 0x47,
+            0x3E, 0x91, 0xE0, 0x40,
+        ];
+        boot_rom[..program.len()].copy_from_slice(&program);
+        boot_rom[0xFC..0x100].copy_from_slice(&[0x3E, 0x01, 0xE0, 0x50]);
+        let config = Config {
+            model: Some(Model::CGB),
+            boot_rom: Some(BootRom::new(boot_rom).unwrap()),
+            ..Config::new()
+        };
+        let mut cgb = Emulator::from_rom_bytes(test_rom(), config).unwrap();
+        cgb.run_frame(&mut frame);
+        cgb.run_frame(&mut frame);
+        assert!(!cgb.bus.is_boot_rom_mapped());
+        assert_eq!(frame[..3], [0xFF, 0x00, 0x00]);
+        cgb.poke(0xFF68, 0x80);
+        cgb.poke(0xFF69, 0xE0);
+        cgb.run_frame(&mut frame);
+        assert_eq!(frame[..3], [0xFF, 0x00, 0x00]);
+    }
+
+    #[test]
+    fn test_dmg_palettes() {
+        let mut frame = vec![0; 144 * 160 * 4];
+        let mut mgb = Emulator::from_rom_bytes(test_rom(), Config { model: Some(Model::MGB), ..Config::new() }).unwrap();
+        mgb.run_frame(&mut frame);
+        assert_eq!(frame[..3], [0xE0, 0xDB, 0xCD]);
+
+        let mut palettes = Palettes::uniform(preset("green").unwrap());
+        palettes.bg = [0x000000, 0x555555, 0xAAAAAA, 0xFFFFFF];
+        mgb.set_dmg_palettes(palettes);
+        mgb.run_frame(&mut frame);
+        assert_eq!(frame[..3], [0x00, 0x00, 0x00]);
+        // The shades follow the palette, not the brightness
+        assert_eq!(mgb.frame_shades()[0], 0);
+        assert_eq!(mgb.dmg_palettes(), palettes);
+
+        // Two shades with the same color still tell apart
+        palettes.bg = [0x000000, 0x000000, 0xAAAAAA, 0xFFFFFF];
+        mgb.set_dmg_palettes(palettes);
+        mgb.poke(0xFF47, 0xFD);
+        mgb.run_frame(&mut frame);
+        assert_eq!(frame[..3], [0x00, 0x00, 0x00]);
+        assert_eq!(mgb.frame_shades()[0], 1);
+    }
+
+    #[test]
+    fn test_save_state_roundtrip() {
+        let mut emulator = Emulator::from_rom_bytes(test_rom(), Config::new()).unwrap();
+        let mut frame = vec![0; 144 * 160 * 4];
+        emulator.run_frame(&mut frame);
+        emulator.bus.write(0xC000, 0x42);
+        let state = emulator.save_state();
+
+        emulator.bus.write(0xC000, 0x00);
+        emulator.run_frame(&mut frame);
+        emulator.load_state(&state).unwrap();
+        assert_eq!(emulator.bus.read(0xC000), 0x42);
+        assert_eq!(emulator.save_state(), state);
+    }
+
+    #[test]
+    fn test_load_state_errors() {
+        let mut emulator = Emulator::from_rom_bytes(test_rom(), Config::new()).unwrap();
+        let state = emulator.save_state();
+
+        assert!(matches!(emulator.load_state(b"nope"), Err(StateError::BadMagic)));
+
+        let mut old_version = state.clone();
+        old_version[4] = 0;
+        assert!(matches!(emulator.load_state(&old_version), Err(StateError::UnsupportedVersion(0))));
+
+        let mut other_rom = state.clone();
+        other_rom[6] = other_rom[6].wrapping_add(1);
+        assert!(matches!(emulator.load_state(&other_rom), Err(StateError::RomMismatch)));
+
+        emulator.bus.write(0xC000, 0x42);
+        let truncated = &state[..state.len() - 1];
+        assert!(matches!(emulator.load_state(truncated), Err(StateError::UnexpectedEnd)));
+        assert_eq!(emulator.bus.read(0xC000), 0x42);
     }
 }
@@ -1,11 +1,16 @@
-use std::{thread, time};
-use std::time::Instant;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// A DMG frame takes 70224 cycles at 4194304 Hz, around 59.7275 frames per second
+pub const FRAME_CYCLES: u64 = 70224;
+pub const CPU_FREQUENCY: u64 = 4194304;
+pub const FRAME_DURATION: Duration = Duration::from_nanos(FRAME_CYCLES * 1_000_000_000 / CPU_FREQUENCY);
 
 pub struct Frames {
     count: usize,
     timer: Instant,
     time_start: u128,
-    fps: u128,
+    next_frame: Instant,
 }
 
 impl Frames {
@@ -14,7 +19,7 @@ impl Frames {
             count: 0,
             timer: Instant::now(),
             time_start: 0,
-            fps: 16600,
+            next_frame: Instant::now() + FRAME_DURATION,
         }
     }
 
@@ -38,12 +43,16 @@ impl Frames {
         self.count
     }
 
-    pub fn limit(&self) {
-        let elapsed = self.timer.elapsed().as_micros();
-        if elapsed > self.fps {
+    // Sleeps until the next frame is due. The deadlines are kept on a fixed
+    // grid so the time lost oversleeping doesn't add up over time
+    pub fn limit(&mut self) {
+        let now = Instant::now();
+        if self.next_frame <= now {
+            // Running behind, don't try to catch up
+            self.next_frame = now + FRAME_DURATION;
             return;
         }
-        let wait = (self.fps - elapsed).try_into().unwrap();
-        thread::sleep(time::Duration::from_micros(wait));
+        thread::sleep(self.next_frame - now);
+        self.next_frame += FRAME_DURATION;
     }
 }
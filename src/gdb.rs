@@ -0,0 +1,538 @@
+// GDB remote serial protocol stub, lets GDB (or anything else speaking RSP)
+// attach to the emulator over TCP. It drives the same `Debugger` as the terminal
+// prompt, so breakpoints, stepping and pausing behave the same way.
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::thread;
+use crate::cpu::Register;
+use crate::debugger::Breakpoint;
+use crate::emulator::Emulator;
+
+// The register set as GDB sees it, every register is 16 bits and little endian
+pub const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.rmg001.sm83.core">
+    <reg name="af" bitsize="16" type="uint16" regnum="0"/>
+    <reg name="bc" bitsize="16" type="uint16"/>
+    <reg name="de" bitsize="16" type="uint16"/>
+    <reg name="hl" bitsize="16" type="uint16"/>
+    <reg name="sp" bitsize="16" type="data_ptr"/>
+    <reg name="pc" bitsize="16" type="code_ptr"/>
+  </feature>
+</target>
+"#;
+
+// Register numbers, in the same order as the target description
+const REGISTERS: [Register; 6] = [
+    Register::AF,
+    Register::BC,
+    Register::DE,
+    Register::HL,
+    Register::SP,
+    Register::PC,
+];
+
+const INTERRUPT: u8 = 0x03;
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+const PACKET_SIZE: usize = 0x1000;
+
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0, |sum, byte| sum.wrapping_add(*byte))
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn decode_hex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len()).step_by(2)
+        .map(|index| u8::from_str_radix(text.get(index..index + 2)?, 16).ok())
+        .collect()
+}
+
+fn parse_number(text: &str) -> Option<u16> {
+    u16::from_str_radix(text, 16).ok()
+}
+
+fn stop_reply(signal: u8) -> String {
+    format!("S{:02x}", signal)
+}
+
+fn error_reply() -> Option<String> {
+    Some("E01".to_string())
+}
+
+enum Incoming {
+    Interrupt,
+    Packet(String),
+    BadChecksum,
+}
+
+struct Connection {
+    stream: TcpStream,
+    buffer: Vec<u8>,
+    no_ack: bool,
+    // Waiting for the emulation to stop after a continue or a step
+    running: bool,
+    open: bool,
+    // Removed again when GDB goes away
+    breakpoints: Vec<Breakpoint>,
+}
+
+impl Connection {
+    fn new(stream: TcpStream) -> Self {
+        Self {
+            stream,
+            buffer: Vec::new(),
+            no_ack: false,
+            running: false,
+            open: true,
+            breakpoints: Vec::new(),
+        }
+    }
+
+    // Reads whatever arrived without blocking
+    fn receive(&mut self) -> io::Result<()> {
+        let mut data = [0; 1024];
+        loop {
+            match self.stream.read(&mut data) {
+                Ok(0) => {
+                    self.open = false;
+                    return Ok(());
+                },
+                Ok(length) => self.buffer.extend_from_slice(&data[..length]),
+                Err(err) if err.kind() == ErrorKind::WouldBlock => return Ok(()),
+                Err(err) if err.kind() == ErrorKind::Interrupted => {},
+                Err(err) => return Err(err),
+            };
+        }
+    }
+
+    fn next_incoming(&mut self) -> Option<Incoming> {
+        loop {
+            match self.buffer.first()? {
+                b'$' => break,
+                &INTERRUPT => {
+                    self.buffer.remove(0);
+                    return Some(Incoming::Interrupt);
+                },
+                // Acks, and anything else between packets
+                _ => {
+                    self.buffer.remove(0);
+                },
+            };
+        }
+        let end = self.buffer.iter().position(|byte| *byte == b'#')?;
+        if self.buffer.len() < end + 3 {
+            return None;
+        }
+        let packet: Vec<u8> = self.buffer.drain(..end + 3).collect();
+        let data = &packet[1..end];
+        let expected = std::str::from_utf8(&packet[end + 1..]).ok()
+            .and_then(|text| u8::from_str_radix(text, 16).ok());
+        match expected == Some(checksum(data)) {
+            true => Some(Incoming::Packet(String::from_utf8_lossy(data).to_string())),
+            false => Some(Incoming::BadChecksum),
+        }
+    }
+
+    fn write_all(&mut self, mut data: &[u8]) -> io::Result<()> {
+        while !data.is_empty() {
+            match self.stream.write(data) {
+                Ok(0) => return Err(io::Error::from(ErrorKind::WriteZero)),
+                Ok(length) => data = &data[length..],
+                Err(err) if err.kind() == ErrorKind::WouldBlock || err.kind() == ErrorKind::Interrupted => thread::yield_now(),
+                Err(err) => return Err(err),
+            };
+        }
+        Ok(())
+    }
+
+    fn send(&mut self, packet: &str) -> io::Result<()> {
+        let data = format!("${}#{:02x}", packet, checksum(packet.as_bytes()));
+        self.write_all(data.as_bytes())
+    }
+
+    fn handle(&mut self, incoming: Incoming, emulator: &mut Emulator) -> io::Result<()> {
+        match incoming {
+            Incoming::Interrupt => {
+                emulator.pause();
+                if self.running {
+                    self.running = false;
+                    self.send(&stop_reply(SIGINT))?;
+                }
+            },
+            Incoming::BadChecksum => if !self.no_ack {
+                self.write_all(b"-")?;
+            },
+            Incoming::Packet(packet) => {
+                if !self.no_ack {
+                    self.write_all(b"+")?;
+                }
+                if let Some(reply) = self.respond(&packet, emulator) {
+                    self.send(&reply)?;
+                }
+                if packet == "QStartNoAckMode" {
+                    self.no_ack = true;
+                }
+            },
+        };
+        Ok(())
+    }
+
+    // The reply to a packet, None when it doesn't get one right away
+    fn respond(&mut self, packet: &str, emulator: &mut Emulator) -> Option<String> {
+        let (command, args) = match packet.chars().next() {
+            Some(first) => packet.split_at(first.len_utf8()),
+            // Nothing to do with an empty packet
+            None => return Some(String::new()),
+        };
+        match command {
+            "?" => Some(stop_reply(SIGTRAP)),
+            "g" => {
+                let registers = emulator.registers();
+                let bytes: Vec<u8> = REGISTERS.iter()
+                    .flat_map(|register| registers.get(*register).to_le_bytes())
+                    .collect();
+                Some(encode_hex(&bytes))
+            },
+            "G" => {
+                let bytes = match decode_hex(args) {
+                    Some(bytes) if bytes.len() == REGISTERS.len() * 2 => bytes,
+                    _ => return error_reply(),
+                };
+                for (register, value) in REGISTERS.iter().zip(bytes.chunks(2)) {
+                    emulator.registers_mut().set(*register, u16::from_le_bytes([value[0], value[1]]));
+                }
+                Some("OK".to_string())
+            },
+            "p" => match parse_number(args).and_then(|number| REGISTERS.get(number as usize)) {
+                Some(register) => Some(encode_hex(&emulator.registers().get(*register).to_le_bytes())),
+                None => error_reply(),
+            },
+            "P" => {
+                let (number, value) = match args.split_once('=') {
+                    Some(parts) => parts,
+                    None => return error_reply(),
+                };
+                let register = parse_number(number).and_then(|number| REGISTERS.get(number as usize));
+                match (register, decode_hex(value)) {
+                    (Some(register), Some(value)) if value.len() == 2 => {
+                        emulator.registers_mut().set(*register, u16::from_le_bytes([value[0], value[1]]));
+                        Some("OK".to_string())
+                    },
+                    _ => error_reply(),
+                }
+            },
+            "m" => {
+                let (address, length) = match args.split_once(',') {
+                    Some((address, length)) => (parse_number(address), parse_number(length)),
+                    None => return error_reply(),
+                };
+                match (address, length) {
+                    (Some(address), Some(length)) => {
+                        let bytes: Vec<u8> = (0..length.min(PACKET_SIZE as u16 / 2))
+                            .map(|offset| emulator.peek(address.wrapping_add(offset)))
+                            .collect();
+                        Some(encode_hex(&bytes))
+                    },
+                    _ => error_reply(),
+                }
+            },
+            "M" => {
+                let (location, data) = match args.split_once(':') {
+                    Some(parts) => parts,
+                    None => return error_reply(),
+                };
+                let address = location.split_once(',').and_then(|(address, _)| parse_number(address));
+                match (address, decode_hex(data)) {
+                    (Some(address), Some(bytes)) => {
+                        for (offset, byte) in bytes.iter().enumerate() {
+                            emulator.poke(address.wrapping_add(offset as u16), *byte);
+                        }
+                        Some("OK".to_string())
+                    },
+                    _ => error_reply(),
+                }
+            },
+            // Software and hardware breakpoints are the same thing here, watchpoints aren't supported
+            "Z" | "z" => {
+                let mut parts = args.split(',');
+                let kind = parts.next();
+                let address = parts.next().and_then(parse_number);
+                let address = match (kind, address) {
+                    (Some("0") | Some("1"), Some(address)) => address,
+                    _ => return Some(String::new()),
+                };
+                let breakpoint = Breakpoint { bank: None, address };
+                match command {
+                    "Z" => {
+                        emulator.debugger_mut().add_breakpoint(breakpoint);
+                        if !self.breakpoints.contains(&breakpoint) {
+                            self.breakpoints.push(breakpoint);
+                        }
+                    },
+                    _ => {
+                        emulator.debugger_mut().remove_breakpoint(&breakpoint);
+                        self.breakpoints.retain(|other| *other != breakpoint);
+                    },
+                };
+                Some("OK".to_string())
+            },
+            "c" | "s" => {
+                if let Some(address) = parse_number(args) {
+                    emulator.registers_mut().set(Register::PC, address);
+                }
+                match command {
+                    "c" => emulator.resume(),
+                    _ => emulator.resume_step(),
+                };
+                self.running = true;
+                None
+            },
+            "D" => {
+                self.open = false;
+                Some("OK".to_string())
+            },
+            "k" => {
+                self.open = false;
+                None
+            },
+            "H" => Some("OK".to_string()),
+            "q" | "Q" => self.query(packet),
+            // Empty replies mean the packet isn't supported
+            _ => Some(String::new()),
+        }
+    }
+
+    fn query(&self, packet: &str) -> Option<String> {
+        if packet.starts_with("qSupported") {
+            return Some(format!("PacketSize={:x};qXfer:features:read+;QStartNoAckMode+", PACKET_SIZE));
+        }
+        if let Some(range) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+            let (offset, length) = match range.split_once(',') {
+                Some((offset, length)) => (usize::from_str_radix(offset, 16), usize::from_str_radix(length, 16)),
+                None => return error_reply(),
+            };
+            let (offset, length) = match (offset, length) {
+                (Ok(offset), Ok(length)) => (offset.min(TARGET_XML.len()), length),
+                _ => return error_reply(),
+            };
+            let end = offset.saturating_add(length).min(TARGET_XML.len());
+            let prefix = match end == TARGET_XML.len() {
+                true => "l",
+                false => "m",
+            };
+            return Some(format!("{}{}", prefix, &TARGET_XML[offset..end]));
+        }
+        Some(match packet {
+            "QStartNoAckMode" => "OK",
+            "qAttached" => "1",
+            "qC" => "QC1",
+            "qfThreadInfo" => "m1",
+            "qsThreadInfo" => "l",
+            _ => "",
+        }.to_string())
+    }
+
+    // GDB is gone, let the game run again without its breakpoints
+    fn detach(&mut self, emulator: &mut Emulator) {
+        for breakpoint in &self.breakpoints {
+            emulator.debugger_mut().remove_breakpoint(breakpoint);
+        }
+        if emulator.is_paused() {
+            emulator.resume();
+        }
+    }
+}
+
+pub struct GdbStub {
+    listener: TcpListener,
+    connection: Option<Connection>,
+}
+
+impl GdbStub {
+    pub fn bind<A: ToSocketAddrs>(address: A) -> io::Result<Self> {
+        let listener = TcpListener::bind(address)?;
+        listener.set_nonblocking(true)?;
+        Ok(Self {
+            listener,
+            connection: None,
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.connection.is_some()
+    }
+
+    // Accepts a connection and answers the packets that arrived since the last call,
+    // without blocking. Meant to be called between frames. The emulation pauses when
+    // GDB attaches and resumes when it detaches
+    pub fn update(&mut self, emulator: &mut Emulator) -> io::Result<()> {
+        if self.connection.is_none() {
+            match self.listener.accept() {
+                Ok((stream, address)) => {
+                    stream.set_nonblocking(true)?;
+                    stream.set_nodelay(true)?;
+                    println!("GDB connected from {}", address);
+                    emulator.pause();
+                    self.connection = Some(Connection::new(stream));
+                },
+                Err(err) if err.kind() == ErrorKind::WouldBlock => return Ok(()),
+                Err(err) => return Err(err),
+            };
+        }
+        let connection = match self.connection.as_mut() {
+            Some(connection) => connection,
+            None => return Ok(()),
+        };
+        let result = Self::update_connection(connection, emulator);
+        if result.is_err() || !connection.open {
+            connection.detach(emulator);
+            self.connection = None;
+            println!("GDB disconnected");
+        }
+        result
+    }
+
+    fn update_connection(connection: &mut Connection, emulator: &mut Emulator) -> io::Result<()> {
+        connection.receive()?;
+        while connection.open {
+            match connection.next_incoming() {
+                Some(incoming) => connection.handle(incoming, emulator)?,
+                None => break,
+            };
+        }
+        if connection.open && connection.running && emulator.is_paused() {
+            connection.running = false;
+            connection.send(&stop_reply(SIGTRAP))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::BufReader;
+    use crate::config::Config;
+    use crate::rom::tests::rom_with_program;
+
+    fn test_rom() -> Vec<u8> {
+        // CALL 0x0150 and JR back to it forever, NOP and RET at 0x0150
+        let mut data = rom_with_program(&[0xCD, 0x50, 0x01, 0x18, 0xFB]);
+        data[0x0150..0x0152].copy_from_slice(&[0x00, 0xC9]);
+        data
+    }
+
+    struct Client {
+        reader: BufReader<TcpStream>,
+        stream: TcpStream,
+    }
+
+    impl Client {
+        fn connect(address: SocketAddr) -> Self {
+            let stream = TcpStream::connect(address).unwrap();
+            Self {
+                reader: BufReader::new(stream.try_clone().unwrap()),
+                stream,
+            }
+        }
+
+        fn read_byte(&mut self) -> u8 {
+            let mut byte = [0];
+            self.reader.read_exact(&mut byte).unwrap();
+            byte[0]
+        }
+
+        // Sends a packet and returns the reply
+        fn request(&mut self, packet: &str) -> String {
+            self.request_bytes(packet.as_bytes())
+        }
+
+        fn request_bytes(&mut self, packet: &[u8]) -> String {
+            self.stream.write_all(b"$").unwrap();
+            self.stream.write_all(packet).unwrap();
+            write!(self.stream, "#{:02x}", checksum(packet)).unwrap();
+            assert_eq!(self.read_byte(), b'+');
+            assert_eq!(self.read_byte(), b'$');
+            let mut reply = Vec::new();
+            loop {
+                match self.read_byte() {
+                    b'#' => break,
+                    byte => reply.push(byte),
+                };
+            }
+            let expected = [self.read_byte(), self.read_byte()];
+            assert_eq!(format!("{:02x}", checksum(&reply)).as_bytes(), &expected);
+            self.stream.write_all(b"+").unwrap();
+            String::from_utf8(reply).unwrap()
+        }
+    }
+
+    #[test]
+    fn test_loopback_session() {
+        let mut emulator = Emulator::from_rom_bytes(test_rom(), Config::new()).unwrap();
+        let mut stub = GdbStub::bind("127.0.0.1:0").unwrap();
+        let address = stub.local_addr().unwrap();
+
+        let client = thread::spawn(move || {
+            let mut client = Client::connect(address);
+            assert!(client.request("qSupported:swbreak+").contains("qXfer:features:read+"));
+            assert_eq!(client.request("?"), "S05");
+            assert!(client.request("qXfer:features:read:target.xml:0,1000").starts_with("l<?xml"));
+            assert_eq!(client.request("qXfer:features:read:target.xml:0,a"), "m<?xml vers");
+
+            // af, bc, de, hl, sp, pc after the boot ROM
+            assert_eq!(client.request("g"), "b0011300d8004d01feff0001");
+            assert_eq!(client.request("p5"), "0001");
+
+            assert_eq!(client.request("Z0,151,1"), "OK");
+            assert_eq!(client.request("c"), "S05");
+            assert_eq!(client.request("p5"), "5101");
+            assert_eq!(client.request("p4"), "fcff");
+            assert_eq!(client.request("mfffc,2"), "0301");
+            assert_eq!(client.request("m150,2"), "00c9");
+
+            assert_eq!(client.request("s"), "S05");
+            assert_eq!(client.request("p5"), "0301");
+            assert_eq!(client.request("z0,151,1"), "OK");
+
+            assert_eq!(client.request("Mc000,3:abcdef"), "OK");
+            assert_eq!(client.request("mc000,3"), "abcdef");
+            assert_eq!(client.request("P3=00c0"), "OK");
+            assert_eq!(client.request("p3"), "00c0");
+            assert_eq!(client.request("vMustReplyEmpty"), "");
+
+            // Empty and malformed packets get an empty or error reply
+            assert_eq!(client.request(""), "");
+            assert_eq!(client.request_bytes(&[0xFF, b'g']), "");
+            assert_eq!(client.request("P3"), "E01");
+            assert_eq!(client.request("Mc000,3"), "E01");
+            assert_eq!(client.request("qXfer:features:read:target.xml:0"), "E01");
+            assert!(client.request("qXfer:features:read:target.xml:1,ffffffffffffffff").starts_with("l?xml"));
+            assert_eq!(client.request("D"), "OK");
+        });
+
+        let mut frame = vec![0; 144 * 160 * 4];
+        while !client.is_finished() {
+            stub.update(&mut emulator).unwrap();
+            emulator.run_frame(&mut frame);
+            thread::yield_now();
+        }
+        client.join().unwrap();
+        stub.update(&mut emulator).unwrap();
+        assert!(!stub.is_connected());
+        assert!(!emulator.is_paused());
+        assert!(emulator.debugger_mut().breakpoints().is_empty());
+    }
+}
@@ -0,0 +1,252 @@
+// Gym style environment for reinforcement learning: a headless emulator driven
+// one action at a time. Nothing is global, so many of them can run in parallel
+// threads, and with the cartridge clock following the emulated cycles the same
+// seed and actions always give the same observations.
+use crate::config::Config;
+use crate::emulator::Emulator;
+use crate::joypad::ButtonMask;
+use crate::ppu::{WIDTH, HEIGHT};
+use crate::rom::RomError;
+use crate::rtc::RtcMode;
+use crate::savestate::StateError;
+
+const FRAME_BUFFER_SIZE: usize = (WIDTH * HEIGHT * 4) as usize;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct GymOptions {
+    // Also return the frame as 2 bit shades
+    pub shades: bool,
+    // Memory copied into every observation, as start address and length
+    pub ram_ranges: Vec<(u16, usize)>,
+    // Up to this many frames without input after each reset, picked by the seed
+    pub max_noop_frames: u32,
+}
+
+impl GymOptions {
+    pub fn new() -> Self {
+        Self {
+            shades: false,
+            ram_ranges: Vec::new(),
+            max_noop_frames: 0,
+        }
+    }
+}
+
+impl Default for GymOptions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Observation {
+    // RGBA, 160x144
+    pub frame: Vec<u8>,
+    // 0 to 3 per pixel, from the lightest to the darkest, if enabled
+    pub shades: Option<Vec<u8>>,
+    // The `ram_ranges` one after the other
+    pub ram: Vec<u8>,
+    // Frames since the last reset
+    pub frame_count: u64,
+}
+
+pub struct Gym {
+    emulator: Emulator,
+    frame_buffer: Vec<u8>,
+    options: GymOptions,
+    reset_state: Vec<u8>,
+    // The screen isn't part of the state, it's kept next to it for resets without noop frames
+    reset_frame: Vec<u8>,
+    reset_shades: Vec<u8>,
+    frame_count: u64,
+    rng: u64,
+}
+
+impl Gym {
+    pub fn new(rom: Vec<u8>, options: GymOptions) -> Result<Self, RomError> {
+        let config = Config {
+            rtc_mode: RtcMode::Cycles,
+            ..Config::new()
+        };
+        Ok(Self::from_emulator(Emulator::from_rom_bytes(rom, config)?, options))
+    }
+
+    // Resets go back to the state the emulator is in right now
+    pub fn from_emulator(emulator: Emulator, options: GymOptions) -> Self {
+        let reset_state = emulator.save_state();
+        let reset_shades = emulator.frame_shades().to_vec();
+        let mut gym = Self {
+            emulator,
+            frame_buffer: vec![0; FRAME_BUFFER_SIZE],
+            options,
+            reset_state,
+            reset_frame: vec![0; FRAME_BUFFER_SIZE],
+            reset_shades,
+            frame_count: 0,
+            rng: 0,
+        };
+        gym.seed(0);
+        gym
+    }
+
+    pub fn emulator(&self) -> &Emulator {
+        &self.emulator
+    }
+
+    pub fn emulator_mut(&mut self) -> &mut Emulator {
+        &mut self.emulator
+    }
+
+    // Only the number of frames without input after a reset is random
+    pub fn seed(&mut self, seed: u64) {
+        // xorshift gets stuck at 0
+        self.rng = seed ^ 0x9E37_79B9_7F4A_7C15;
+        if self.rng == 0 {
+            self.rng = 1;
+        }
+    }
+
+    // xorshift64*
+    fn next_random(&mut self) -> u64 {
+        self.rng ^= self.rng >> 12;
+        self.rng ^= self.rng << 25;
+        self.rng ^= self.rng >> 27;
+        self.rng.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    // Resets start from this saved state from now on, it has to be from the same ROM.
+    // The frame on screen right now is the one they show until the next frame is run
+    pub fn set_reset_state(&mut self, state: Vec<u8>) -> Result<(), StateError> {
+        self.emulator.load_state(&state)?;
+        self.reset_state = state;
+        self.reset_frame.copy_from_slice(&self.frame_buffer);
+        self.reset_shades = self.emulator.frame_shades().to_vec();
+        Ok(())
+    }
+
+    pub fn reset(&mut self) -> Result<Observation, StateError> {
+        self.emulator.load_state(&self.reset_state)?;
+        self.frame_buffer.copy_from_slice(&self.reset_frame);
+        self.emulator.set_frame_shades(&self.reset_shades);
+        self.frame_count = 0;
+        let noop_frames = match self.options.max_noop_frames {
+            0 => 0,
+            max => (self.next_random() % (max as u64 + 1)) as u32,
+        };
+        Ok(self.step(ButtonMask::NONE, noop_frames))
+    }
+
+    // Holds the buttons down for the given number of frames
+    pub fn step(&mut self, action: ButtonMask, frames: u32) -> Observation {
+        self.emulator.set_buttons(action);
+        for _ in 0..frames {
+            self.emulator.run_frame(&mut self.frame_buffer);
+            self.frame_count += 1;
+        }
+        self.observe()
+    }
+
+    pub fn observe(&self) -> Observation {
+        Observation {
+            frame: self.frame_buffer.clone(),
+            shades: match self.options.shades {
+                true => Some(self.emulator.frame_shades().to_vec()),
+                false => None,
+            },
+            ram: self.options.ram_ranges.iter()
+                .flat_map(|(address, length)| self.emulator.read_range(*address, *length))
+                .collect(),
+            frame_count: self.frame_count,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use crate::rom::tests::rom_with_program;
+
+    fn test_rom() -> Vec<u8> {
+        // Count the frames in C000 and copy the joypad to C001:
+        //   ld a, $10 / ldh [$00], a  select the action buttons
+        // loop:
+        //   ldh a, [$44] / cp $90 / jr nz, loop
+        //   ld hl, $C000 / inc [hl]
+        //   ldh a, [$00] / ld [$C001], a
+        // wait:
+        //   ldh a, [$44] / cp $90 / jr z, wait / jr loop
+        let program = [
+            0x3E, 0x10, 0xE0, 0x00,
+            0xF0, 0x44, 0xFE, 0x90, 0x20, 0xFA,
+            0x21, 0x00, 0xC0, 0x34,
+            0xF0, 0x00, 0xEA, 0x01, 0xC0,
+            0xF0, 0x44, 0xFE, 0x90, 0x28, 0xFA, 0x18, 0xE9,
+        ];
+        rom_with_program(&program)
+    }
+
+    fn options() -> GymOptions {
+        GymOptions {
+            shades: true,
+            ram_ranges: vec![(0xC000, 2)],
+            max_noop_frames: 30,
+        }
+    }
+
+    #[test]
+    fn test_step() {
+        let mut gym = Gym::new(test_rom(), options()).unwrap();
+        let observation = gym.step(ButtonMask::NONE, 3);
+        assert_eq!(observation.frame_count, 3);
+        assert_eq!(observation.frame.len(), FRAME_BUFFER_SIZE);
+        assert_eq!(observation.shades.unwrap().len(), FRAME_BUFFER_SIZE / 4);
+        // The frame ends as soon as LY reaches 144, before the game sees it
+        let counter = observation.ram[0];
+        assert_eq!(counter, 2);
+        assert_eq!(observation.ram[1] & 0x0F, 0x0F);
+
+        let observation = gym.step(ButtonMask::A | ButtonMask::START, 1);
+        assert_eq!(observation.ram[0], counter + 1);
+        assert_eq!(observation.ram[1] & 0x0F, 0b0110);
+    }
+
+    #[test]
+    fn test_seeded_reset() {
+        let mut gym = Gym::new(test_rom(), options()).unwrap();
+        let mut frames = Vec::new();
+        for seed in 0..4 {
+            gym.seed(seed);
+            let first = gym.reset().unwrap();
+            gym.seed(seed);
+            let second = gym.reset().unwrap();
+            assert_eq!(first, second);
+            assert_eq!(first.ram[0] as u64, first.frame_count.saturating_sub(1));
+            frames.push(first.frame_count);
+        }
+        assert!(frames.iter().any(|count| *count != frames[0]), "{:?}", frames);
+    }
+
+    #[test]
+    fn test_reset_without_noop_frames() {
+        let options = GymOptions {
+            max_noop_frames: 0,
+            ..options()
+        };
+        let mut gym = Gym::new(test_rom(), options).unwrap();
+        let first = gym.reset().unwrap();
+        assert_ne!(gym.step(ButtonMask::A, 5), first);
+        assert_eq!(gym.reset().unwrap(), first);
+    }
+
+    #[test]
+    fn test_parallel() {
+        let handles: Vec<_> = (0..4).map(|_| {
+            let mut gym = Gym::new(test_rom(), GymOptions::new()).unwrap();
+            thread::spawn(move || gym.step(ButtonMask::NONE, 10).frame_count)
+        }).collect();
+        for handle in handles {
+            assert_eq!(handle.join().unwrap(), 10);
+        }
+    }
+}
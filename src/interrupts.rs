@@ -3,6 +3,7 @@ use crate::utils::{
     get_bit,
     set_bit,
 };
+use crate::savestate::{SaveState, StateWriter, StateReader, StateError};
 
 pub const INTERRUPT_ENABLE_ADDRESS: u16 = 0xFFFF;
 pub const INTERRUPT_FLAG_ADDRESS: u16 = 0xFF0F;
@@ -88,3 +89,16 @@ impl Interrupts {
         self.set(interrupt, true)
     }
 }
+
+impl SaveState for Interrupts {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.interrupt_enable);
+        state.write_u8(self.interrupt_flag);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.interrupt_enable = state.read_u8()?;
+        self.interrupt_flag = state.read_u8()?;
+        Ok(())
+    }
+}
@@ -1,4 +1,6 @@
+use std::ops::BitOr;
 use crate::utils::{BitIndex, get_bit};
+use crate::savestate::{SaveState, StateWriter, StateReader, StateError};
 
 pub const JOYPAD_ADDRESS: u16 = 0xFF00;
 
@@ -14,6 +16,61 @@ pub enum Button {
     Select
 }
 
+impl Button {
+    pub const ALL: [Button; 8] = [
+        Button::A,
+        Button::B,
+        Button::Select,
+        Button::Start,
+        Button::Right,
+        Button::Left,
+        Button::Up,
+        Button::Down,
+    ];
+}
+
+// One bit per button, A, B, Select and Start in the low nibble and
+// Right, Left, Up and Down in the high one, like the joypad register reads them
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub struct ButtonMask(pub u8);
+
+impl ButtonMask {
+    pub const NONE: ButtonMask   = ButtonMask(0);
+    pub const A: ButtonMask      = ButtonMask(0b0000_0001);
+    pub const B: ButtonMask      = ButtonMask(0b0000_0010);
+    pub const SELECT: ButtonMask = ButtonMask(0b0000_0100);
+    pub const START: ButtonMask  = ButtonMask(0b0000_1000);
+    pub const RIGHT: ButtonMask  = ButtonMask(0b0001_0000);
+    pub const LEFT: ButtonMask   = ButtonMask(0b0010_0000);
+    pub const UP: ButtonMask     = ButtonMask(0b0100_0000);
+    pub const DOWN: ButtonMask   = ButtonMask(0b1000_0000);
+
+    pub fn from_button(button: Button) -> Self {
+        match button {
+            Button::A      => ButtonMask::A,
+            Button::B      => ButtonMask::B,
+            Button::Select => ButtonMask::SELECT,
+            Button::Start  => ButtonMask::START,
+            Button::Right  => ButtonMask::RIGHT,
+            Button::Left   => ButtonMask::LEFT,
+            Button::Up     => ButtonMask::UP,
+            Button::Down   => ButtonMask::DOWN,
+        }
+    }
+
+    pub fn contains(&self, button: Button) -> bool {
+        self.0 & ButtonMask::from_button(button).0 != 0
+    }
+}
+
+impl BitOr for ButtonMask {
+    type Output = ButtonMask;
+
+    fn bitor(self, other: ButtonMask) -> ButtonMask {
+        ButtonMask(self.0 | other.0)
+    }
+}
+
 pub struct Joypad {
     a: bool,
     b: bool,
@@ -65,6 +122,35 @@ impl Joypad {
         };
     }
 
+    pub fn is_pressed(&self, button: Button) -> bool {
+        match button {
+            Button::A      => self.a,
+            Button::B      => self.b,
+            Button::Up     => self.up,
+            Button::Down   => self.down,
+            Button::Left   => self.left,
+            Button::Right  => self.right,
+            Button::Start  => self.start,
+            Button::Select => self.select,
+        }
+    }
+
+    pub fn buttons(&self) -> ButtonMask {
+        Button::ALL.iter()
+            .filter(|button| self.is_pressed(**button))
+            .fold(ButtonMask::NONE, |mask, button| mask | ButtonMask::from_button(*button))
+    }
+
+    // Presses the buttons in the mask and releases the rest
+    pub fn set_buttons(&mut self, buttons: ButtonMask) {
+        for button in Button::ALL {
+            match buttons.contains(button) {
+                true => self.press(button),
+                false => self.release(button),
+            };
+        }
+    }
+
     pub fn read(&self, byte: u8) -> u8 {
         let direction = !get_bit(byte, BitIndex::I4);
         let action = !get_bit(byte, BitIndex::I5);
@@ -82,3 +168,45 @@ impl Joypad {
         return data;
     }
 }
+
+impl SaveState for Joypad {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_bytes(&[
+            self.a as u8, self.b as u8, self.up as u8, self.down as u8,
+            self.left as u8, self.right as u8, self.start as u8, self.select as u8,
+        ]);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        let mut buttons = [0; 8];
+        state.read_bytes(&mut buttons)?;
+        let [a, b, up, down, left, right, start, select] = buttons.map(|button| button != 0);
+        self.a = a;
+        self.b = b;
+        self.up = up;
+        self.down = down;
+        self.left = left;
+        self.right = right;
+        self.start = start;
+        self.select = select;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_button_mask() {
+        let mut joypad = Joypad::new();
+        joypad.set_buttons(ButtonMask::A | ButtonMask::DOWN);
+        assert_eq!(joypad.buttons(), ButtonMask(0b1000_0001));
+        // Action buttons selected
+        assert_eq!(joypad.read(0b0001_0000) & 0x0F, 0b1110);
+        // Directions selected
+        assert_eq!(joypad.read(0b0010_0000) & 0x0F, 0b0111);
+        joypad.set_buttons(ButtonMask::NONE);
+        assert_eq!(joypad.read(0b0001_0000) & 0x0F, 0b1111);
+    }
+}
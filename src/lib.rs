@@ -3,11 +3,27 @@ pub mod cpu;
 pub mod ppu;
 pub mod timer;
 pub mod sound;
+pub mod audio;
 pub mod rom;
+pub mod model;
+pub mod palette;
+pub mod rtc;
+pub mod rewind;
 pub mod ram;
 pub mod bus;
 pub mod interrupts;
 pub mod joypad;
+pub mod movie;
+pub mod serial;
 pub mod emulator;
+pub mod debugger;
+pub mod disasm;
+pub mod gdb;
+pub mod gym;
+pub mod symbols;
+pub mod trace;
+#[cfg(feature = "frontend")]
 pub mod render;
 pub mod frames;
+pub mod config;
+pub mod savestate;
@@ -0,0 +1,78 @@
+use std::fmt;
+use crate::rom::ROMInfo;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Model {
+    // Original Gameboy
+    DMG,
+    // Gameboy Pocket
+    MGB,
+    // Super Gameboy
+    SGB,
+    // Gameboy Color
+    CGB,
+    // Gameboy Advance, running Gameboy Color games
+    AGB,
+}
+
+impl Model {
+    pub const ALL: [Model; 5] = [Model::DMG, Model::MGB, Model::SGB, Model::CGB, Model::AGB];
+
+    // A Gameboy Color for games that support it, the original Gameboy for the rest
+    pub fn detect(info: &ROMInfo) -> Self {
+        match info.cgb_features() || info.cgb_only() {
+            true => Model::CGB,
+            false => Model::DMG,
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Self> {
+        Model::ALL.iter().copied().find(|model| model.name().eq_ignore_ascii_case(name))
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Model::DMG => "dmg",
+            Model::MGB => "mgb",
+            Model::SGB => "sgb",
+            Model::CGB => "cgb",
+            Model::AGB => "agb",
+        }
+    }
+
+    // Has the Gameboy Color hardware, even when running games in DMG mode
+    pub fn is_cgb(&self) -> bool {
+        matches!(self, Model::CGB | Model::AGB)
+    }
+
+    pub fn to_u8(self) -> u8 {
+        Model::ALL.iter().position(|model| *model == self).unwrap_or(0) as u8
+    }
+
+    pub fn from_u8(value: u8) -> Option<Self> {
+        Model::ALL.get(value as usize).copied()
+    }
+}
+
+impl fmt::Display for Model {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name().to_uppercase())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_model() {
+        assert_eq!(Model::parse("agb"), Some(Model::AGB));
+        assert_eq!(Model::parse("MGB"), Some(Model::MGB));
+        assert_eq!(Model::parse("gba"), None);
+        for model in Model::ALL {
+            assert_eq!(Model::from_u8(model.to_u8()), Some(model));
+        }
+        assert_eq!(Model::from_u8(5), None);
+        assert_eq!(Model::SGB.to_string(), "SGB");
+    }
+}
@@ -0,0 +1,286 @@
+// Input movies: the buttons held during each frame, recorded from a save state
+// (taken at power on or at any later point) so playback through `run_frame`
+// reproduces the same frames exactly.
+use std::fmt;
+use std::fs;
+use std::io;
+use crate::joypad::ButtonMask;
+use crate::model::Model;
+use crate::savestate::{StateWriter, StateReader, StateError};
+
+pub const MOVIE_MAGIC: [u8; 4] = *b"RMGM";
+pub const MOVIE_VERSION: u16 = 2;
+
+#[derive(Debug)]
+pub enum MovieError {
+    Io(io::Error),
+    BadMagic,
+    UnsupportedVersion(u16),
+    RomMismatch,
+    ModelMismatch,
+    Truncated,
+    // The cartridge clock follows the host clock, so replays wouldn't match
+    WallClockRtc,
+    State(StateError),
+}
+
+impl fmt::Display for MovieError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MovieError::Io(err) => write!(f, "{}", err),
+            MovieError::BadMagic => write!(f, "Not a movie file"),
+            MovieError::UnsupportedVersion(version) => write!(f, "Movie version {} is not supported (expected {})", version, MOVIE_VERSION),
+            MovieError::RomMismatch => write!(f, "Movie belongs to a different ROM"),
+            MovieError::ModelMismatch => write!(f, "Movie was recorded on a different Gameboy model"),
+            MovieError::Truncated => write!(f, "Movie is truncated"),
+            MovieError::WallClockRtc => write!(f, "Movies need the cartridge clock to follow the emulated cycles (--rtc-cycles)"),
+            MovieError::State(err) => write!(f, "Could not load the movie start state: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for MovieError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            MovieError::Io(err) => Some(err),
+            MovieError::State(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for MovieError {
+    fn from(err: io::Error) -> Self {
+        MovieError::Io(err)
+    }
+}
+
+fn truncated(_: StateError) -> MovieError {
+    MovieError::Truncated
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum MovieMode {
+    // The input of every frame is appended to the movie
+    Recording,
+    // The input comes from the movie, the frontend's is ignored
+    Playing,
+    // Played until the end, the input is live again
+    Finished,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Movie {
+    pub header_checksum: u8,
+    pub global_checksum: u16,
+    pub model: Model,
+    // Recorded before the first frame ran
+    pub from_power_on: bool,
+    pub rerecords: u32,
+    // Emulator frame count at the start state
+    pub start_frame: u64,
+    // Where the movie starts, power on movies have one too so the battery RAM and the clock match
+    pub state: Vec<u8>,
+    // Buttons held during each frame
+    pub frames: Vec<ButtonMask>,
+}
+
+impl Movie {
+    // Frame count of the emulator right after the last frame of the movie
+    pub fn end_frame(&self) -> u64 {
+        self.start_frame + self.frames.len() as u64
+    }
+
+    pub fn contains_frame(&self, frame: u64) -> bool {
+        frame >= self.start_frame && frame <= self.end_frame()
+    }
+
+    // Buttons for the frame starting at this emulator frame count
+    pub fn input(&self, frame: u64) -> Option<ButtonMask> {
+        let index = frame.checked_sub(self.start_frame)?;
+        self.frames.get(index as usize).copied()
+    }
+
+    // Drops every frame from this one on
+    pub fn truncate(&mut self, frame: u64) {
+        self.frames.truncate(frame.saturating_sub(self.start_frame) as usize);
+    }
+
+    // Replaces the input of this frame and drops the ones after it
+    pub fn record(&mut self, frame: u64, buttons: ButtonMask) {
+        self.truncate(frame);
+        self.frames.push(buttons);
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut data = StateWriter::new();
+        data.write_bytes(&MOVIE_MAGIC);
+        data.write_u16(MOVIE_VERSION);
+        data.write_u8(self.header_checksum);
+        data.write_u16(self.global_checksum);
+        data.write_u8(self.model.to_u8());
+        data.write_bool(self.from_power_on);
+        data.write_u32(self.rerecords);
+        data.write_u64(self.start_frame);
+        data.write_vec(&self.state);
+        let frames: Vec<u8> = self.frames.iter().map(|buttons| buttons.0).collect();
+        data.write_vec(&frames);
+        data.into_bytes()
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, MovieError> {
+        let mut data = StateReader::new(bytes);
+        let mut magic = [0; 4];
+        data.read_bytes(&mut magic).map_err(|_| MovieError::BadMagic)?;
+        if magic != MOVIE_MAGIC {
+            return Err(MovieError::BadMagic);
+        }
+        let version = data.read_u16().map_err(truncated)?;
+        if version != MOVIE_VERSION {
+            return Err(MovieError::UnsupportedVersion(version));
+        }
+        Ok(Self {
+            header_checksum: data.read_u8().map_err(truncated)?,
+            global_checksum: data.read_u16().map_err(truncated)?,
+            model: Model::from_u8(data.read_u8().map_err(truncated)?).ok_or(MovieError::ModelMismatch)?,
+            from_power_on: data.read_bool().map_err(truncated)?,
+            rerecords: data.read_u32().map_err(truncated)?,
+            start_frame: data.read_u64().map_err(truncated)?,
+            state: data.read_vec().map_err(truncated)?,
+            frames: data.read_vec().map_err(truncated)?.into_iter().map(ButtonMask).collect(),
+        })
+    }
+
+    pub fn load(filename: &str) -> Result<Self, MovieError> {
+        Self::from_bytes(&fs::read(filename)?)
+    }
+
+    pub fn save(&self, filename: &str) -> io::Result<()> {
+        fs::write(filename, self.to_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::hash_map::DefaultHasher;
+    use std::hash::{Hash, Hasher};
+    use crate::config::Config;
+    use crate::emulator::Emulator;
+    use crate::rom::tests::{rom_with_program, set_header_checksum};
+    use crate::rtc::RtcMode;
+
+    fn test_rom(cartridge_type: u8) -> Vec<u8> {
+        // Copies the action buttons into the background palette all the time,
+        // so the frames change with the input:
+        //   ld a, $10 / ldh [$00], a
+        // loop:
+        //   ldh a, [$00] / ldh [$47], a / jr loop
+        let mut data = rom_with_program(&[0x3E, 0x10, 0xE0, 0x00, 0xF0, 0x00, 0xE0, 0x47, 0x18, 0xFA]);
+        data[0x0147] = cartridge_type;
+        set_header_checksum(&mut data);
+        data
+    }
+
+    fn emulator() -> Emulator {
+        let config = Config {
+            rtc_mode: RtcMode::Cycles,
+            ..Config::new()
+        };
+        Emulator::from_rom_bytes(test_rom(0x00), config).unwrap()
+    }
+
+    fn hash(frame: &[u8]) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        frame.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    fn input(frame: usize) -> ButtonMask {
+        match frame % 3 {
+            0 => ButtonMask::NONE,
+            1 => ButtonMask::A,
+            _ => ButtonMask::A | ButtonMask::B | ButtonMask::RIGHT,
+        }
+    }
+
+    #[test]
+    fn test_record_and_play() {
+        let mut frame = vec![0; 144 * 160 * 4];
+        let mut recorder = emulator();
+        recorder.run_frame(&mut frame);
+        recorder.record_movie().unwrap();
+        let mut hashes = Vec::new();
+        for index in 0..10 {
+            recorder.set_buttons(input(index));
+            recorder.run_frame(&mut frame);
+            hashes.push(hash(&frame));
+        }
+        assert_ne!(hashes[0], hashes[1]);
+        let movie = recorder.stop_movie().unwrap();
+        assert_eq!(movie.frames.len(), 10);
+        assert_eq!(movie.start_frame, 1);
+        assert!(!movie.from_power_on);
+        assert_eq!(Movie::from_bytes(&movie.to_bytes()).unwrap(), movie);
+
+        // The live input is ignored during playback
+        let mut player = emulator();
+        player.play_movie(movie.clone()).unwrap();
+        for expected in &hashes {
+            player.set_buttons(ButtonMask::START);
+            player.run_frame(&mut frame);
+            assert_eq!(hash(&frame), *expected);
+        }
+        assert_eq!(player.movie_mode(), Some(MovieMode::Finished));
+        assert_eq!(player.frame_count(), 11);
+
+        // Rerecord from a state saved in the middle of the playback
+        player.play_movie(movie.clone()).unwrap();
+        for _ in 0..4 {
+            player.run_frame(&mut frame);
+        }
+        let state = player.save_state();
+        player.run_frame(&mut frame);
+        player.load_state(&state).unwrap();
+        assert_eq!(player.movie_mode(), Some(MovieMode::Playing));
+        assert!(player.rerecord_movie());
+        player.set_buttons(ButtonMask::DOWN);
+        player.run_frame(&mut frame);
+        let rerecorded = player.stop_movie().unwrap();
+        assert_eq!(rerecorded.rerecords, 1);
+        assert_eq!(rerecorded.frames[..4], movie.frames[..4]);
+        assert_eq!(rerecorded.frames[4..], [ButtonMask::DOWN]);
+
+        // Loading a state while recording rewinds the movie
+        player.play_movie(rerecorded).unwrap();
+        for _ in 0..5 {
+            player.run_frame(&mut frame);
+        }
+        assert!(player.rerecord_movie());
+        player.run_frame(&mut frame);
+        assert_eq!(player.movie().unwrap().frames.len(), 6);
+        player.load_state(&state).unwrap();
+        assert_eq!(player.movie().unwrap().frames.len(), 4);
+        assert_eq!(player.movie().unwrap().rerecords, 3);
+    }
+
+    #[test]
+    fn test_movie_errors() {
+        let mut emulator = emulator();
+        emulator.record_movie().unwrap();
+        let mut movie = emulator.stop_movie().unwrap();
+        assert!(movie.from_power_on);
+
+        let bytes = movie.to_bytes();
+        assert!(matches!(Movie::from_bytes(b"nope"), Err(MovieError::BadMagic)));
+        assert!(matches!(Movie::from_bytes(&bytes[..bytes.len() - 1]), Err(MovieError::Truncated)));
+
+        movie.global_checksum = movie.global_checksum.wrapping_add(1);
+        assert!(matches!(emulator.play_movie(movie), Err(MovieError::RomMismatch)));
+        assert_eq!(emulator.movie_mode(), None);
+
+        // MBC3 with a clock following the host's
+        let mut wall_clock = Emulator::from_rom_bytes(test_rom(0x0F), Config::new()).unwrap();
+        assert!(matches!(wall_clock.record_movie(), Err(MovieError::WallClockRtc)));
+    }
+}
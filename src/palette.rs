@@ -0,0 +1,522 @@
+// Colors for DMG games. On the DMG models they're up to the user, either one of the
+// presets or a palette file, separately for the background and both sprite palettes.
+//
+// On a Gameboy Color, the CGB boot ROM looks up the sum of the title bytes of Nintendo
+// games in a table, a few sums are shared and the fourth letter of the title tells
+// those apart. Everything else gets the default palette, unless a direction (plus A
+// or B) is held down while the logo is shown.
+use std::fmt;
+use std::fs;
+use std::io;
+use std::sync::OnceLock;
+use crate::model::Model;
+use crate::rom::ROMInfo;
+
+// 0xRRGGBB, from the lightest to the darkest shade
+pub type Colors = [u32; 4];
+
+// The window uses the background one
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Palettes {
+    pub bg: Colors,
+    pub obj0: Colors,
+    pub obj1: Colors,
+}
+
+#[derive(Debug)]
+pub enum PaletteError {
+    Io(io::Error),
+    BadColor(String),
+    ColorCount(usize),
+}
+
+impl fmt::Display for PaletteError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PaletteError::Io(err) => write!(f, "{}", err),
+            PaletteError::BadColor(color) => write!(f, "Invalid color {}, expected RRGGBB in hex", color),
+            PaletteError::ColorCount(count) => write!(f, "Expected 4 colors, found {}", count),
+        }
+    }
+}
+
+impl std::error::Error for PaletteError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            PaletteError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for PaletteError {
+    fn from(err: io::Error) -> Self {
+        PaletteError::Io(err)
+    }
+}
+
+pub const DEFAULT_COLORS: Colors = [0x83E6CD, 0x66ADC6, 0x4F53AB, 0x3E2469];
+pub const POCKET_COLORS: Colors = [0xE0DBCD, 0xA89F94, 0x706B66, 0x2B2B26];
+
+pub const PRESETS: [(&str, Colors); 4] = [
+    ("default", DEFAULT_COLORS),
+    ("green", [0x9BBC0F, 0x8BAC0F, 0x306230, 0x0F380F]),
+    ("grey", POCKET_COLORS),
+    ("contrast", [0xFFFFFF, 0xAAAAAA, 0x555555, 0x000000]),
+];
+
+pub fn preset(name: &str) -> Option<Colors> {
+    PRESETS.iter()
+        .find(|(preset, _)| preset.eq_ignore_ascii_case(name))
+        .map(|(_, colors)| *colors)
+}
+
+// Four RRGGBB hex colors from the lightest to the darkest, one per line like the
+// .hex palettes from Lospec. A leading `#` and blank lines are fine
+pub fn parse_colors(text: &str) -> Result<Colors, PaletteError> {
+    let colors = text.split_whitespace()
+        .map(|color| match color.trim_start_matches('#') {
+            hex if hex.len() == 6 && hex.chars().all(|digit| digit.is_ascii_hexdigit()) => Ok(u32::from_str_radix(hex, 16).unwrap_or(0)),
+            _ => Err(PaletteError::BadColor(color.to_string())),
+        })
+        .collect::<Result<Vec<u32>, PaletteError>>()?;
+    colors.as_slice().try_into().map_err(|_| PaletteError::ColorCount(colors.len()))
+}
+
+// A preset name or a palette file
+pub fn load_colors(name: &str) -> Result<Colors, PaletteError> {
+    match preset(name) {
+        Some(colors) => Ok(colors),
+        None => parse_colors(&fs::read_to_string(name)?),
+    }
+}
+
+const RED: Colors = [0xFFFFFF, 0xFF8484, 0x943A3A, 0x000000];
+const GREEN: Colors = [0xFFFFFF, 0x7BFF31, 0x008400, 0x000000];
+const BLUE: Colors = [0xFFFFFF, 0x63A5FF, 0x0000FF, 0x000000];
+const BROWN: Colors = [0xFFFFFF, 0xFFAD63, 0x843100, 0x000000];
+const ORANGE: Colors = [0xFFFFFF, 0xFFFF00, 0xFF0000, 0x000000];
+
+const fn uniform(colors: Colors) -> Palettes {
+    Palettes {
+        bg: colors,
+        obj0: colors,
+        obj1: colors,
+    }
+}
+
+pub const COMPAT_DEFAULT: Palettes = Palettes {
+    bg: [0xFFFFFF, 0x7BFF31, 0x0063C5, 0x000000],
+    obj0: RED,
+    obj1: RED,
+};
+
+// Picked on the boot logo by holding these buttons
+pub const BUTTON_PALETTES: [(&str, Palettes); 12] = [
+    ("up", uniform(BROWN)),
+    ("up+a", uniform(RED)),
+    ("up+b", uniform([0xFFE7C5, 0xCE9C84, 0x846B29, 0x5A3108])),
+    ("left", Palettes {
+        bg: BLUE,
+        obj0: RED,
+        obj1: GREEN,
+    }),
+    ("left+a", Palettes {
+        bg: [0xFFFFFF, 0x8C8CDE, 0x52528C, 0x000000],
+        obj0: RED,
+        obj1: BROWN,
+    }),
+    ("left+b", uniform([0xFFFFFF, 0xA5A5A5, 0x525252, 0x000000])),
+    ("down", uniform([0xFFFFA5, 0xFF9494, 0x9494FF, 0x000000])),
+    ("down+a", uniform(ORANGE)),
+    ("down+b", Palettes {
+        bg: [0xFFFFFF, 0xFFFF00, 0x7B4A00, 0x000000],
+        obj0: BLUE,
+        obj1: GREEN,
+    }),
+    ("right", uniform([0xFFFFFF, 0x52FF00, 0xFF4200, 0x000000])),
+    ("right+a", COMPAT_DEFAULT),
+    ("right+b", uniform([0x000000, 0x008484, 0xFFDE00, 0xFFFFFF])),
+];
+
+// The colors of the boot ROM as RGB555, mostly four per palette
+const BOOT_COLORS: [u16; 120] = [
+    0x7FFF, 0x32BF, 0x00D0, 0x0000,
+    0x639F, 0x4279, 0x15B0, 0x04CB,
+    0x7FFF, 0x6E31, 0x454A, 0x0000,
+    0x7FFF, 0x1BEF, 0x0200, 0x0000,
+    0x7FFF, 0x421F, 0x1CF2, 0x0000,
+    0x7FFF, 0x5294, 0x294A, 0x0000,
+    0x7FFF, 0x03FF, 0x012F, 0x0000,
+    0x7FFF, 0x03EF, 0x01D6, 0x0000,
+    0x7FFF, 0x42B5, 0x3DC8, 0x0000,
+    0x7E74, 0x03FF, 0x0180, 0x0000,
+    0x67FF, 0x77AC, 0x1A13, 0x2D6B,
+    0x7ED6, 0x4BFF, 0x2175, 0x0000,
+    0x53FF, 0x4A5F, 0x7E52, 0x0000,
+    0x4FFF, 0x7ED2, 0x3A4C, 0x1CE0,
+    0x03ED, 0x7FFF, 0x255F, 0x0000,
+    0x036A, 0x021F, 0x03FF, 0x7FFF,
+    0x7FFF, 0x01DF, 0x0112, 0x0000,
+    0x231F, 0x035F, 0x00F2, 0x0009,
+    0x7FFF, 0x03EA, 0x011F, 0x0000,
+    0x299F, 0x001A, 0x000C, 0x0000,
+    0x7FFF, 0x027F, 0x001F, 0x0000,
+    0x7FFF, 0x03E0, 0x0206, 0x0120,
+    0x7FFF, 0x7EEB, 0x001F, 0x7C00,
+    0x7FFF, 0x3FFF, 0x7E00, 0x001F,
+    0x7FFF, 0x03FF, 0x001F, 0x0000,
+    0x03FF, 0x001F, 0x000C, 0x0000,
+    0x7FFF, 0x033F, 0x0193, 0x0000,
+    0x0000, 0x4200, 0x037F, 0x7FFF,
+    0x7FFF, 0x7E8C, 0x7C00, 0x0000,
+    0x7FFF, 0x1BEF, 0x6180, 0x0000,
+];
+
+// Where obj0, obj1 and bg start in `BOOT_COLORS`. A few start halfway through a palette
+const BOOT_COMBINATIONS: [(u8, u8, u8); 51] = [
+    (16, 16, 116), (72, 72, 72), (80, 80, 80), (96, 96, 96),
+    (36, 36, 36), (0, 0, 0), (108, 108, 108), (20, 20, 20),
+    (48, 48, 48), (104, 104, 104), (64, 32, 32), (16, 112, 112),
+    (16, 8, 8), (12, 16, 16), (16, 12, 12), (112, 16, 112),
+    (8, 68, 8), (64, 64, 32), (16, 16, 28), (16, 16, 72),
+    (16, 16, 80), (76, 76, 36), (15, 15, 44), (68, 68, 8),
+    (16, 16, 8), (16, 16, 12), (112, 112, 0), (12, 12, 0),
+    (0, 0, 4), (72, 88, 72), (80, 88, 80), (96, 88, 96),
+    (64, 88, 32), (68, 16, 52), (111, 0, 56), (111, 16, 60),
+    (76, 88, 36), (64, 112, 40), (16, 92, 112), (68, 88, 8),
+    (16, 0, 8), (16, 112, 12), (112, 12, 0), (12, 112, 16),
+    (16, 112, 84), (12, 112, 0), (100, 12, 112), (0, 112, 32),
+    (16, 12, 112), (112, 12, 24), (16, 112, 116),
+];
+
+// Title checksum, fourth letter of the title for the shared checksums, and the index in
+// `BOOT_COMBINATIONS`. Same order as the boot ROM, the first match wins
+const GAME_PALETTES: [(u8, Option<u8>, u8); 94] = [
+    (0x00, None, 0),
+    (0x88, None, 4), // ALLEY WAY
+    (0x16, None, 5), // YAKUMAN
+    (0x36, None, 35), // BASEBALL
+    (0xD1, None, 34), // TENNIS
+    (0xDB, None, 31), // TETRIS
+    (0xF2, None, 31), // QIX
+    (0x3C, None, 15), // DR.MARIO
+    (0x8C, None, 10), // RADARMISSION
+    (0x92, None, 5), // F1RACE
+    (0x3D, None, 19), // YOSSY NO TAMAGO
+    (0x5C, None, 36),
+    (0x58, None, 7), // X, DONKEY KONG LAND
+    (0xC9, None, 37), // MARIOLAND2
+    (0x3E, None, 30), // YOSSY NO COOKIE
+    (0x70, None, 44), // ZELDA
+    (0x1D, None, 21), // KIRBY'S PINBALL
+    (0x59, None, 32),
+    (0x69, None, 31), // TETRIS FLASH
+    (0x19, None, 33), // DONKEY KONG
+    (0x35, None, 5), // MARIO'S PICROSS
+    (0xA8, None, 33),
+    (0x14, None, 13), // POKEMON RED
+    (0xAA, None, 14), // POKEMON GREEN
+    (0x75, None, 5), // PICROSS 2
+    (0x95, None, 29), // YOSSY NO PANEPON
+    (0x99, None, 5), // KIRAKIRA KIDS
+    (0x34, None, 18), // GAMEBOY GALLERY
+    (0x6F, None, 9), // POCKETCAMERA
+    (0x15, None, 3),
+    (0xFF, None, 2), // BALLOON KID
+    (0x97, None, 26), // KINGOFTHEZOO
+    (0x4B, None, 25), // DMG FOOTBALL
+    (0x90, None, 25), // WORLD CUP
+    (0x17, None, 41), // OTHELLO
+    (0x10, None, 42), // SUPER RC PRO-AM
+    (0x39, None, 26), // DYNABLASTER
+    (0xF7, None, 45), // BOY AND BLOB GB2
+    (0xF6, None, 42), // MEGAMAN
+    (0xA2, None, 45), // STAR WARS-NOA
+    (0x49, None, 36), // KIRBY DREAM LAND
+    (0x4E, None, 38), // WAVERACE
+    (0x43, None, 26),
+    (0x68, None, 42), // LOLO2
+    (0xE0, None, 30), // YOSHI'S COOKIE
+    (0x8B, None, 41), // MYSTIC QUEST
+    (0xF0, None, 34),
+    (0xCE, None, 34), // TOPRANKINGTENNIS
+    (0x0C, None, 5), // MANSELL
+    (0x29, None, 42), // MEGAMAN3
+    (0xE8, None, 6), // SPACE INVADERS
+    (0xB7, None, 5), // GAME&WATCH
+    (0x86, None, 33), // DONKEYKONGLAND95
+    (0x9A, None, 25),
+    (0x52, None, 42), // STREET FIGHTER 2
+    (0x01, None, 42),
+    (0x9D, None, 40), // KILLERINSTINCT95
+    (0x71, None, 2), // TETRIS BLAST
+    (0x9C, None, 16), // PINOCCHIO
+    (0xBD, None, 25),
+    (0x5D, None, 42), // BA.TOSHINDEN
+    (0x6D, None, 42), // NETTOU KOF 95
+    (0x67, None, 5),
+    (0x3F, None, 0), // TETRIS PLUS
+    (0x6B, None, 39), // DONKEYKONGLAND 3
+    (0xB3, Some(b'B'), 36), // KIRBY2
+    (0x46, Some(b'E'), 32), // SUPER MARIOLAND
+    (0x28, Some(b'F'), 25), // GOLF
+    (0xA5, Some(b'A'), 6), // SOLARSTRIKER
+    (0xC6, Some(b'A'), 32),
+    (0xD3, Some(b'R'), 12),
+    (0x27, Some(b'B'), 36), // KIRBY BLOCKBALL
+    (0x61, Some(b'E'), 11), // POKEMON BLUE
+    (0x18, Some(b'K'), 39), // DONKEYKONGLAND
+    (0x66, Some(b'E'), 18),
+    (0x6A, Some(b'K'), 39),
+    (0xBF, Some(b' '), 24), // KID ICARUS
+    (0x0D, Some(b'R'), 31),
+    (0xF4, Some(b'-'), 50),
+    (0xB3, Some(b'U'), 17),
+    (0x46, Some(b'R'), 46), // METROID2
+    (0x28, Some(b'A'), 6),
+    (0xA5, Some(b'R'), 27),
+    (0xC6, Some(b' '), 0),
+    (0xD3, Some(b'I'), 47), // WARIOLAND2
+    (0x27, Some(b'N'), 41),
+    (0x61, Some(b'A'), 41),
+    (0x18, Some(b'I'), 0),
+    (0x66, Some(b'L'), 0),
+    (0x6A, Some(b'I'), 19),
+    (0xBF, Some(b'C'), 34),
+    (0x0D, Some(b'E'), 23),
+    (0xF4, Some(b' '), 18),
+    (0xB3, Some(b'R'), 29),
+];
+
+fn boot_colors(offset: u8) -> Colors {
+    let offset = offset as usize;
+    std::array::from_fn(|index| {
+        let color = BOOT_COLORS[offset + index];
+        let [red, green, blue] = [color & 0x1F, (color >> 5) & 0x1F, (color >> 10) & 0x1F]
+            .map(|channel| (u32::from(channel) * 255 + 15) / 31);
+        (red << 16) | (green << 8) | blue
+    })
+}
+
+fn boot_palettes(combination: u8) -> Palettes {
+    let (obj0, obj1, bg) = BOOT_COMBINATIONS[combination as usize];
+    Palettes {
+        bg: boot_colors(bg),
+        obj0: boot_colors(obj0),
+        obj1: boot_colors(obj1),
+    }
+}
+
+impl Palettes {
+    pub const fn uniform(colors: Colors) -> Self {
+        uniform(colors)
+    }
+
+    // Before the user picks any, the Gameboy Pocket has a grey screen
+    pub fn for_model(model: Model) -> Self {
+        match model {
+            Model::MGB => uniform(POCKET_COLORS),
+            _ => uniform(DEFAULT_COLORS),
+        }
+    }
+
+    // The one the CGB boot ROM would pick
+    pub fn for_game(info: &ROMInfo) -> Self {
+        if !info.nintendo_licensee() {
+            return COMPAT_DEFAULT;
+        }
+        let fourth_letter = info.title().as_bytes().get(3).copied();
+        GAME_PALETTES.iter()
+            .find(|(checksum, letter, _)| *checksum == info.title_checksum() && letter.is_none_or(|letter| Some(letter) == fourth_letter))
+            .map(|(_, _, combination)| boot_palettes(*combination))
+            .unwrap_or(COMPAT_DEFAULT)
+    }
+
+    // One of the `BUTTON_PALETTES`, e.g. `left+b`
+    pub fn from_buttons(name: &str) -> Option<Self> {
+        BUTTON_PALETTES.iter()
+            .find(|(buttons, _)| buttons.eq_ignore_ascii_case(name))
+            .map(|(_, palette)| *palette)
+    }
+}
+
+// How the RGB555 colors of the color RAM are turned into the ones on screen
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ColorCorrection {
+    // Each channel stretched from 5 to 8 bits, brighter and more saturated than the real screen
+    None,
+    // Channels bled into each other like on the Gameboy Color LCD
+    GbcLcd,
+    // Same mix done on linear light, the midtones stay brighter
+    Gamma,
+}
+
+const LCD_GAMMA: f32 = 2.2;
+
+// One table per correction mode, shared by every emulator
+static COLOR_TABLES: OnceLock<[Vec<u32>; 3]> = OnceLock::new();
+
+impl ColorCorrection {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "none" => Some(ColorCorrection::None),
+            "lcd" => Some(ColorCorrection::GbcLcd),
+            "gamma" => Some(ColorCorrection::Gamma),
+            _ => None,
+        }
+    }
+
+    // Every RGB555 color turned into 0xRRGGBB, so rendering a pixel is a single lookup
+    pub fn table(&self) -> &'static [u32] {
+        let tables = COLOR_TABLES.get_or_init(|| {
+            [ColorCorrection::None, ColorCorrection::GbcLcd, ColorCorrection::Gamma].map(|correction| correction.build_table())
+        });
+        match self {
+            ColorCorrection::None => &tables[0],
+            ColorCorrection::GbcLcd => &tables[1],
+            ColorCorrection::Gamma => &tables[2],
+        }
+    }
+
+    fn build_table(&self) -> Vec<u32> {
+        let linear: Vec<f32> = (0..32).map(|channel| (channel as f32 / 31.0).powf(LCD_GAMMA)).collect();
+        (0..0x8000u16).map(|color| {
+            let [red, green, blue] = [color & 0x1F, (color >> 5) & 0x1F, (color >> 10) & 0x1F].map(u32::from);
+            let [red, green, blue] = match self {
+                ColorCorrection::None => [red, green, blue].map(|channel| (channel << 3) | (channel >> 2)),
+                ColorCorrection::GbcLcd => [
+                    (red * 13 + green * 2 + blue) >> 1,
+                    (green * 3 + blue) << 1,
+                    (red * 3 + green * 2 + blue * 11) >> 1,
+                ],
+                ColorCorrection::Gamma => {
+                    let [red, green, blue] = [red, green, blue].map(|channel| linear[channel as usize]);
+                    [
+                        (red * 13.0 + green * 2.0 + blue) / 16.0,
+                        (green * 3.0 + blue) / 4.0,
+                        (red * 3.0 + green * 2.0 + blue * 11.0) / 16.0,
+                    ].map(|channel| (channel.powf(1.0 / LCD_GAMMA) * 255.0).round() as u32)
+                },
+            };
+            (red << 16) | (green << 8) | blue
+        }).collect()
+    }
+}
+
+// Color RAM format: RGB555, little endian
+pub fn to_cram(colors: &Colors) -> [u8; 8] {
+    let mut cram = [0; 8];
+    for (index, color) in colors.iter().enumerate() {
+        let red = (color >> 19) & 0x1F;
+        let green = (color >> 11) & 0x1F;
+        let blue = (color >> 3) & 0x1F;
+        let value = (red | (green << 5) | (blue << 10)) as u16;
+        cram[index * 2..index * 2 + 2].copy_from_slice(&value.to_le_bytes());
+    }
+    cram
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn info(title: &[u8], licensee: u8) -> ROMInfo {
+        let mut data = vec![0; 0x8000];
+        data[0x0134..0x0134 + title.len()].copy_from_slice(title);
+        data[0x014B] = licensee;
+        ROMInfo::from_bytes(&data).unwrap()
+    }
+
+    #[test]
+    fn test_game_palettes() {
+        assert_eq!(Palettes::for_game(&info(b"POKEMON BLUE", 0x01)).bg, BLUE);
+        assert_eq!(Palettes::for_game(&info(b"POKEMON RED", 0x01)).bg, RED);
+        assert_eq!(Palettes::for_game(&info(b"TETRIS", 0x01)).bg, ORANGE);
+        assert_eq!(Palettes::for_game(&info(b"ALLEY WAY", 0x01)), uniform([0xA59CFF, 0xFFFF00, 0x006300, 0x000000]));
+        assert_eq!(Palettes::for_game(&info(b"BASEBALL", 0x01)), Palettes {
+            bg: [0x52DE00, 0xFF8400, 0xFFFF00, 0xFFFFFF],
+            obj0: [0xFFFFFF, 0xFFFFFF, 0x63A5FF, 0x0000FF],
+            obj1: RED,
+        });
+        // Only Nintendo games get their own colors
+        assert_eq!(Palettes::for_game(&info(b"POKEMON BLUE", 0x02)), COMPAT_DEFAULT);
+        assert_eq!(Palettes::for_game(&info(b"HOMEBREW GAME", 0x01)), COMPAT_DEFAULT);
+    }
+
+    #[test]
+    fn test_game_palettes_fourth_letter() {
+        // SUPER MARIOLAND and METROID2 share a checksum
+        let mario = Palettes::for_game(&info(b"SUPER MARIOLAND", 0x01));
+        let metroid = Palettes::for_game(&info(b"METROID2", 0x01));
+        assert_eq!(mario.bg, [0xFFFFFF, 0xADAD84, 0x42737B, 0x000000]);
+        assert_eq!(metroid, Palettes {
+            bg: BLUE,
+            obj0: [0xFFFF00, 0xFF0000, 0x630000, 0x000000],
+            obj1: GREEN,
+        });
+        // Same checksum, a fourth letter that isn't in the table
+        let other = info(b"SUPLR MARIOEAND", 0x01);
+        assert_eq!(other.title_checksum(), 0x46);
+        assert_eq!(Palettes::for_game(&other), COMPAT_DEFAULT);
+        assert_eq!(Palettes::for_game(&info(b"GOLF", 0x01)), Palettes {
+            bg: GREEN,
+            obj0: RED,
+            obj1: RED,
+        });
+        // The fourth letter can be a space
+        assert_eq!(Palettes::for_game(&info(b"KID ICARUS", 0x01)).bg, [0xFFFFFF, 0x8C8CDE, 0x52528C, 0x000000]);
+    }
+
+    #[test]
+    fn test_boot_palette_table() {
+        // Every combination fits in the colors, the first one is the default
+        let palettes: Vec<Palettes> = (0..BOOT_COMBINATIONS.len() as u8).map(boot_palettes).collect();
+        assert_eq!(palettes[0], COMPAT_DEFAULT);
+        assert_eq!(palettes[5], uniform(BROWN));
+        assert_eq!(palettes[6], Palettes::from_buttons("right+b").unwrap());
+        assert!(GAME_PALETTES.iter().all(|(_, _, combination)| (*combination as usize) < BOOT_COMBINATIONS.len()));
+        assert_eq!(GAME_PALETTES.iter().filter(|(_, letter, _)| letter.is_some()).count(), 29);
+    }
+
+    #[test]
+    fn test_button_palettes() {
+        assert_eq!(Palettes::from_buttons("Right+A"), Some(COMPAT_DEFAULT));
+        assert_eq!(Palettes::from_buttons("left").unwrap().obj1, GREEN);
+        assert_eq!(Palettes::from_buttons("a+left"), None);
+        assert_eq!(to_cram(&[0xFFFFFF, 0x7BFF31, 0x0063C5, 0x000000]), [0xFF, 0x7F, 0xEF, 0x1B, 0x80, 0x61, 0x00, 0x00]);
+    }
+
+    #[test]
+    fn test_color_correction() {
+        let none = ColorCorrection::None.table();
+        assert_eq!(none.len(), 0x8000);
+        assert_eq!(none[0x7FFF], 0xFFFFFF);
+        assert_eq!(none[0x001F], 0xFF0000);
+        assert_eq!(none[0x0010 | (0x08 << 10)], 0x840042);
+
+        // The channels bleed into each other, pure colors aren't pure anymore
+        let lcd = ColorCorrection::GbcLcd.table();
+        assert_eq!(lcd[0x7FFF], 0xF8F8F8);
+        assert_eq!(lcd[0x001F], 0xC9002E);
+        let gamma = ColorCorrection::Gamma.table();
+        assert_eq!(gamma[0x7FFF], 0xFFFFFF);
+        assert_eq!(gamma[0x0000], 0x000000);
+        assert!(gamma[0x001F] >> 16 > lcd[0x001F] >> 16);
+        // Built once for everyone
+        assert!(std::ptr::eq(gamma, ColorCorrection::Gamma.table()));
+        assert_eq!(ColorCorrection::parse("LCD"), Some(ColorCorrection::GbcLcd));
+        assert_eq!(ColorCorrection::parse("srgb"), None);
+    }
+
+    #[test]
+    fn test_dmg_palettes() {
+        assert_eq!(load_colors("GREY").unwrap(), POCKET_COLORS);
+        assert_eq!(Palettes::for_model(Model::MGB).obj1, POCKET_COLORS);
+        assert_eq!(parse_colors("e0f8d0\n88C070\n\n#346856\n081820\n").unwrap(), [0xE0F8D0, 0x88C070, 0x346856, 0x081820]);
+        assert!(matches!(parse_colors("e0f8d0 88c070 346856"), Err(PaletteError::ColorCount(3))));
+        assert!(matches!(parse_colors("e0f8d0 88c070 346856 0818"), Err(PaletteError::BadColor(_))));
+        assert!(matches!(parse_colors("e0f8d0 88c070 346856 +81820"), Err(PaletteError::BadColor(_))));
+        assert!(matches!(load_colors("no-such-palette.hex"), Err(PaletteError::Io(_))));
+    }
+}
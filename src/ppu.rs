@@ -6,7 +6,10 @@ use crate::utils::{
 };
 use crate::bus::SPRITE_ATTRIBUTE_TABLE;
 use crate::cpu::Cycles;
+use crate::model::Model;
+use crate::palette::{Colors, Palettes, ColorCorrection, to_cram};
 use crate::interrupts::{Interrupts, Interrupt};
+use crate::savestate::{SaveState, StateWriter, StateReader, StateError};
 
 pub const LCD_WIDTH: u32 = 160;
 pub const LCD_HEIGHT: u32 = 144;
@@ -61,55 +64,40 @@ struct ColorPalette {
 }
 
 impl ColorPalette {
-    pub fn new_cgb(cram: &[u8], palette_number: u8) -> Self {
+    // `color_table` has the 0xRRGGBB of every RGB555 color
+    pub fn new_cgb(cram: &[u8], palette_number: u8, color_table: &[u32]) -> Self {
         let addr = (palette_number as usize) * 8;
         let white = join_bytes(cram[addr + 1], cram[addr]);
         let light = join_bytes(cram[addr + 3], cram[addr + 2]);
         let dark  = join_bytes(cram[addr + 5], cram[addr + 4]);
         let black = join_bytes(cram[addr + 7], cram[addr + 6]);
         Self {
-            white: extract_rgb(white),
-            light: extract_rgb(light),
-            dark:  extract_rgb(dark),
-            black: extract_rgb(black),
+            white: extract_rgb(color_table[(white & 0x7FFF) as usize]),
+            light: extract_rgb(color_table[(light & 0x7FFF) as usize]),
+            dark:  extract_rgb(color_table[(dark & 0x7FFF) as usize]),
+            black: extract_rgb(color_table[(black & 0x7FFF) as usize]),
         }
     }
-}
 
-fn extract_rgb(color: u16) -> RGBA {
-    let red   = (color         & 0b11111).to_be_bytes()[1];
-    let green = ((color >> 5)  & 0b11111).to_be_bytes()[1];
-    let blue  = ((color >> 10) & 0b11111).to_be_bytes()[1];
-    RGBA((red << 3) | (red >> 2), (green << 3) | (green >> 2), (blue << 3) | (blue >> 2), 0)
+    fn new_dmg(colors: &Colors) -> Self {
+        let [white, light, dark, black] = colors.map(extract_rgb);
+        Self {
+            white,
+            light,
+            dark,
+            black,
+        }
+    }
 }
 
-const BACKGROUND_COLORS: ColorPalette = ColorPalette {
-    white: RGBA(0x83, 0xE6, 0xCD, 0),
-    light: RGBA(0x66, 0xAD, 0xC6, 0),
-    dark:  RGBA(0x4F, 0x53, 0xAB, 0),
-    black: RGBA(0x3E, 0x24, 0x69, 0),
-};
-
-const WINDOW_COLORS: ColorPalette = ColorPalette {
-    white: RGBA(0x83, 0xE6, 0xCD, 0),
-    light: RGBA(0x66, 0xAD, 0xC6, 0),
-    dark:  RGBA(0x4F, 0x53, 0xAB, 0),
-    black: RGBA(0x3E, 0x24, 0x69, 0),
-};
-
-const SPRITE_0_COLORS: ColorPalette = ColorPalette {
-    white: RGBA(0x83, 0xE6, 0xCD, 0),
-    light: RGBA(0x66, 0xAD, 0xC6, 0),
-    dark:  RGBA(0x4F, 0x53, 0xAB, 0),
-    black: RGBA(0x3E, 0x24, 0x69, 0),
-};
+fn dmg_colors(palettes: &Palettes) -> [ColorPalette; 3] {
+    [ColorPalette::new_dmg(&palettes.bg), ColorPalette::new_dmg(&palettes.obj0), ColorPalette::new_dmg(&palettes.obj1)]
+}
 
-const SPRITE_1_COLORS: ColorPalette = ColorPalette {
-    white: RGBA(0x83, 0xE6, 0xCD, 0),
-    light: RGBA(0x66, 0xAD, 0xC6, 0),
-    dark:  RGBA(0x4F, 0x53, 0xAB, 0),
-    black: RGBA(0x3E, 0x24, 0x69, 0),
-};
+fn extract_rgb(color: u32) -> RGBA {
+    let [_, red, green, blue] = color.to_be_bytes();
+    RGBA(red, green, blue, 0)
+}
 
 #[derive(Debug, Copy, Clone)]
 pub enum LCDControl {
@@ -306,10 +294,28 @@ pub struct PPU {
     hdma_destination: u16,
     hdma_start: u8,
     cgb_mode: bool,
+    // DMG game on a Gameboy Color, the colors come from the first palettes of the color RAM
+    compat_mode: bool,
+    // Background, first and second sprite palettes in DMG mode
+    dmg_colors: [ColorPalette; 3],
+    dmg_palettes: Palettes,
+    color_correction: ColorCorrection,
+    color_table: &'static [u32],
+    // 2 bit shade of every pixel drawn, see `shades`
+    shades: Vec<u8>,
+    // A CGB boot ROM writes the palettes of DMG games before the color RAM gets locked
+    cram_unlocked: bool,
 }
 
 impl PPU {
-    pub fn new(cgb_mode: bool) -> Self {
+    pub fn new(model: Model, cgb_mode: bool) -> Self {
+        let dmg_palettes = Palettes::for_model(model);
+        let mut io_registers = [0; 16];
+        // Last OAM DMA source the boot ROM left behind
+        io_registers[(DMA_ADDRESS - 0xFF40) as usize] = match model.is_cgb() {
+            true => 0x00,
+            false => 0xFF,
+        };
         Self {
             state: false,
             background_priority: false,
@@ -331,7 +337,7 @@ impl PPU {
             scroll_y: 0,
             window_x: 0,
             window_y: 0,
-            io_registers: [0; 16],
+            io_registers,
             cram_registers: [0; 4],
             vram: [0; 0x2000 * 2],
             bg_cram: [0; 64],
@@ -342,13 +348,56 @@ impl PPU {
             hdma_destination: 0,
             hdma_start: 0,
             cgb_mode,
+            compat_mode: model.is_cgb() && !cgb_mode,
+            dmg_colors: dmg_colors(&dmg_palettes),
+            dmg_palettes,
+            color_correction: ColorCorrection::None,
+            color_table: ColorCorrection::None.table(),
+            shades: vec![0; (LCD_WIDTH * LCD_HEIGHT) as usize],
+            cram_unlocked: false,
         }
     }
 
+    pub fn color_correction(&self) -> ColorCorrection {
+        self.color_correction
+    }
+
+    pub fn set_color_correction(&mut self, correction: ColorCorrection) {
+        if correction != self.color_correction {
+            self.color_table = correction.table();
+            self.color_correction = correction;
+        }
+    }
+
+    pub fn dmg_palettes(&self) -> Palettes {
+        self.dmg_palettes
+    }
+
+    pub fn set_dmg_palettes(&mut self, palettes: Palettes) {
+        self.dmg_colors = dmg_colors(&palettes);
+        self.dmg_palettes = palettes;
+    }
+
+    pub fn set_cram_unlocked(&mut self, unlocked: bool) {
+        self.cram_unlocked = unlocked;
+    }
+
+    // What the CGB boot ROM writes to the color RAM for DMG games
+    pub fn set_compat_palette(&mut self, palette: &Palettes) {
+        self.bg_cram[0..8].copy_from_slice(&to_cram(&palette.bg));
+        self.obj_cram[0..8].copy_from_slice(&to_cram(&palette.obj0));
+        self.obj_cram[8..16].copy_from_slice(&to_cram(&palette.obj1));
+    }
+
     pub fn lcd_y(&self) -> u8 {
         self.lcd_y
     }
 
+    // Dots into the current line
+    pub fn line_cycles(&self) -> u16 {
+        self.cycles.0 as u16
+    }
+
     pub fn set_vram_bank(&mut self, bank: u8) {
         if self.cgb_mode {
             self.vram_bank = bank & 1;
@@ -424,6 +473,8 @@ impl PPU {
             0xFF68..=0xFF6B => {
                 self.cram_registers[(address as usize) - 0xFF68] = data;
                 match address {
+                    // The color RAM is locked outside of CGB mode
+                    BCPD_BGPD_ADDRESS | OCPD_OBPD_ADDRESS if !self.cgb_mode && !self.cram_unlocked => {},
                     BCPD_BGPD_ADDRESS => {
                         if self.get_lcd_status(LCDStatus::ModeFlag(LCDStatusModeFlag::TransferringToLCD)) {
                             return;
@@ -853,41 +904,35 @@ impl PPU {
         self.bg_palette = self.get_register(BACKGROUND_PALETTE_ADDRESS);
         let mut count = 0.0;
         while count < cycles.0 && (self.lcd_x as u32) < LCD_WIDTH {
-            let idx = (self.lcd_x as usize + (self.lcd_y as usize * LCD_WIDTH as usize)) * 4;
+            let idx = self.lcd_x as usize + (self.lcd_y as usize * LCD_WIDTH as usize);
 
             if let Some((window_pixel, palette_number)) = self.get_window_pixel() {
                 self.window_drawn = true;
-                let colors = match self.cgb_mode {
-                    true => ColorPalette::new_cgb(&self.bg_cram, palette_number),
-                    false => WINDOW_COLORS,
+                let colors = match (self.cgb_mode, self.compat_mode) {
+                    (true, _) => ColorPalette::new_cgb(&self.bg_cram, palette_number, self.color_table),
+                    (false, true) => ColorPalette::new_cgb(&self.bg_cram, 0, self.color_table),
+                    (false, false) => self.dmg_colors[0],
                 };
-                let rgba = PPU::get_rgba(window_pixel, colors);
-                frame_buffer[idx]     = rgba[0];
-                frame_buffer[idx + 1] = rgba[1];
-                frame_buffer[idx + 2] = rgba[2];
+                self.put_pixel(frame_buffer, idx, window_pixel, colors);
             } else if let Some((background_pixel, palette_number)) = self.get_background_pixel() {
-                let colors = match self.cgb_mode {
-                    true => ColorPalette::new_cgb(&self.bg_cram, palette_number),
-                    false => BACKGROUND_COLORS,
+                let colors = match (self.cgb_mode, self.compat_mode) {
+                    (true, _) => ColorPalette::new_cgb(&self.bg_cram, palette_number, self.color_table),
+                    (false, true) => ColorPalette::new_cgb(&self.bg_cram, 0, self.color_table),
+                    (false, false) => self.dmg_colors[0],
                 };
-                let rgba = PPU::get_rgba(background_pixel, colors);
-                frame_buffer[idx]     = rgba[0];
-                frame_buffer[idx + 1] = rgba[1];
-                frame_buffer[idx + 2] = rgba[2];
+                self.put_pixel(frame_buffer, idx, background_pixel, colors);
             }
             if self.get_lcd_control(LCDControl::ObjectEnable) {
                 if let Some((sprite_pixel, palette_zero, palette_number)) = self.find_sprite_pixel() {
-                    let colors = match self.cgb_mode {
-                        true => ColorPalette::new_cgb(&self.obj_cram, palette_number),
-                        false => match palette_zero {
-                            true => SPRITE_0_COLORS,
-                            false => SPRITE_1_COLORS,
+                    let colors = match (self.cgb_mode, self.compat_mode) {
+                        (true, _) => ColorPalette::new_cgb(&self.obj_cram, palette_number, self.color_table),
+                        (false, true) => ColorPalette::new_cgb(&self.obj_cram, !palette_zero as u8, self.color_table),
+                        (false, false) => match palette_zero {
+                            true => self.dmg_colors[1],
+                            false => self.dmg_colors[2],
                         },
                     };
-                    let rgba = PPU::get_rgba(sprite_pixel, colors);
-                    frame_buffer[idx]     = rgba[0];
-                    frame_buffer[idx + 1] = rgba[1];
-                    frame_buffer[idx + 2] = rgba[2];
+                    self.put_pixel(frame_buffer, idx, sprite_pixel, colors);
                 }
             }
 
@@ -896,6 +941,33 @@ impl PPU {
         }
     }
 
+    // `index` is the pixel number, not the offset in the frame buffer
+    fn put_pixel(&mut self, frame_buffer: &mut [u8], index: usize, pixel: Pixel, colors: ColorPalette) {
+        let rgba = PPU::get_rgba(pixel, colors);
+        frame_buffer[index * 4]     = rgba[0];
+        frame_buffer[index * 4 + 1] = rgba[1];
+        frame_buffer[index * 4 + 2] = rgba[2];
+        self.shades[index] = match self.cgb_mode {
+            // Color indexes don't say how dark a CGB color is, go by brightness
+            true => {
+                let luma = (rgba[0] as u32 * 299 + rgba[1] as u32 * 587 + rgba[2] as u32 * 114) / 1000;
+                3 - (luma * 4 / 256) as u8
+            },
+            false => pixel as u8,
+        };
+    }
+
+    // Shade of each pixel of the last frame, from 0 for the lightest to 3 for the darkest.
+    // DMG shades come from the palette registers, whatever colors they are shown with
+    pub fn shades(&self) -> &[u8] {
+        &self.shades
+    }
+
+    // Puts back the shades of a frame that was rendered before
+    pub fn set_shades(&mut self, shades: &[u8]) {
+        self.shades.copy_from_slice(shades);
+    }
+
     fn get_palette(index: u8, palette_byte: u8) -> u8 {
         match index {
             0b00 => palette_byte & 0b11,
@@ -938,3 +1010,154 @@ impl PPU {
         ]
     }
 }
+
+fn save_pixels(state: &mut StateWriter, pixels: &Option<([u8; 8], u8)>) {
+    state.write_bool(pixels.is_some());
+    if let Some((pixels, bank)) = pixels {
+        state.write_bytes(pixels);
+        state.write_u8(*bank);
+    }
+}
+
+fn load_pixels(state: &mut StateReader) -> Result<Option<([u8; 8], u8)>, StateError> {
+    if !state.read_bool()? {
+        return Ok(None);
+    }
+    let mut pixels = [0; 8];
+    state.read_bytes(&mut pixels)?;
+    Ok(Some((pixels, state.read_u8()?)))
+}
+
+impl SaveState for Sprite {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_bytes(&[self.x, self.y, self.tile_number, self.palette]);
+        state.write_bool(self.palette_zero);
+        state.write_bool(self.x_flip);
+        state.write_bool(self.y_flip);
+        state.write_bool(self.over_bg);
+        state.write_bool(self.bit_pixels.is_some());
+        if let Some(bit_pixels) = &self.bit_pixels {
+            state.write_bytes(bit_pixels);
+        }
+        state.write_u8(self.vram_bank);
+        state.write_u8(self.palette_number);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        let mut bytes = [0; 4];
+        state.read_bytes(&mut bytes)?;
+        [self.x, self.y, self.tile_number, self.palette] = bytes;
+        self.palette_zero = state.read_bool()?;
+        self.x_flip = state.read_bool()?;
+        self.y_flip = state.read_bool()?;
+        self.over_bg = state.read_bool()?;
+        self.bit_pixels = match state.read_bool()? {
+            true => {
+                let mut bit_pixels = [0; 8];
+                state.read_bytes(&mut bit_pixels)?;
+                Some(bit_pixels)
+            },
+            false => None,
+        };
+        self.vram_bank = state.read_u8()?;
+        self.palette_number = state.read_u8()?;
+        Ok(())
+    }
+}
+
+impl SaveState for PPU {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_bool(self.state);
+        state.write_bool(self.background_priority);
+        state.write_bool(self.window_enable);
+        state.write_bool(self.lcd_enable);
+        state.write_bool(self.window_drawn);
+        state.write_f32(self.cycles.0);
+        state.write_u8(self.sprite_buffer.len() as u8);
+        for sprite in &self.sprite_buffer {
+            sprite.save_state(state);
+        }
+        state.write_u8(self.window_y_counter);
+        state.write_u8(self.last_bg_index);
+        state.write_bool(self.last_bg_priority);
+        state.write_u8(self.bg_palette);
+        state.write_u8(self.lcd_control);
+        save_pixels(state, &self.current_background_pixels);
+        save_pixels(state, &self.current_window_pixels);
+        state.write_bytes(&[
+            self.lcd_y, self.lcd_x,
+            self.scroll_x, self.scroll_y,
+            self.window_x, self.window_y,
+        ]);
+        state.write_bytes(&self.io_registers);
+        state.write_bytes(&self.cram_registers);
+        state.write_bytes(&self.vram);
+        state.write_bytes(&self.bg_cram);
+        state.write_bytes(&self.obj_cram);
+        state.write_bytes(&self.oam);
+        state.write_u8(self.vram_bank);
+        state.write_u16(self.hdma_source);
+        state.write_u16(self.hdma_destination);
+        state.write_u8(self.hdma_start);
+        state.write_bool(self.cgb_mode);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.state = state.read_bool()?;
+        self.background_priority = state.read_bool()?;
+        self.window_enable = state.read_bool()?;
+        self.lcd_enable = state.read_bool()?;
+        self.window_drawn = state.read_bool()?;
+        self.cycles = Cycles(state.read_f32()?);
+        let sprite_count = state.read_u8()?;
+        if sprite_count > 10 {
+            return Err(StateError::Invalid("too many sprites in the sprite buffer"));
+        }
+        self.sprite_buffer.clear();
+        for _ in 0..sprite_count {
+            let mut sprite = Sprite {
+                x: 0,
+                y: 0,
+                tile_number: 0,
+                palette: 0,
+                palette_zero: false,
+                x_flip: false,
+                y_flip: false,
+                over_bg: false,
+                bit_pixels: None,
+                vram_bank: 0,
+                palette_number: 0,
+            };
+            sprite.load_state(state)?;
+            self.sprite_buffer.push(sprite);
+        }
+        self.window_y_counter = state.read_u8()?;
+        self.last_bg_index = state.read_u8()?;
+        self.last_bg_priority = state.read_bool()?;
+        self.bg_palette = state.read_u8()?;
+        self.lcd_control = state.read_u8()?;
+        self.current_background_pixels = load_pixels(state)?;
+        self.current_window_pixels = load_pixels(state)?;
+        let mut position = [0; 6];
+        state.read_bytes(&mut position)?;
+        [
+            self.lcd_y, self.lcd_x,
+            self.scroll_x, self.scroll_y,
+            self.window_x, self.window_y,
+        ] = position;
+        state.read_bytes(&mut self.io_registers)?;
+        state.read_bytes(&mut self.cram_registers)?;
+        state.read_bytes(&mut self.vram)?;
+        state.read_bytes(&mut self.bg_cram)?;
+        state.read_bytes(&mut self.obj_cram)?;
+        state.read_bytes(&mut self.oam)?;
+        self.vram_bank = state.read_u8()?;
+        self.hdma_source = state.read_u16()?;
+        self.hdma_destination = state.read_u16()?;
+        self.hdma_start = state.read_u8()?;
+        if state.read_bool()? != self.cgb_mode {
+            return Err(StateError::ModeMismatch);
+        }
+        Ok(())
+    }
+}
@@ -1,10 +1,19 @@
 use crate::bus::{ECHO_RAM, WORK_RAM_1};
+use crate::savestate::{SaveState, StateWriter, StateReader, StateError};
 
 pub const WRAM_BANK_SELECT_ADDRESS: u16 = 0xFF70;
 
-pub trait RAM {
+pub trait RAM: SaveState + Send {
     fn read(&self, address: u16) -> u8;
     fn write(&mut self, address: u16, value: u8);
+    // Every bank one after the other, 4KB each
+    fn data(&self) -> &[u8];
+    fn data_mut(&mut self) -> &mut [u8];
+
+    // Bank currently mapped at 0xD000-0xDFFF
+    fn bank(&self) -> u8 {
+        1
+    }
 }
 
 pub struct DMGRAM {
@@ -43,24 +52,37 @@ impl RAM for DMGRAM {
         let address = parse_echo_ram_address(address);
         self.data[(address - 0xC000) as usize] = value;
     }
+
+    fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    fn data_mut(&mut self) -> &mut [u8] {
+        &mut self.data
+    }
 }
 
 
 pub struct CGBRAM {
     data: [u8; 4096 * 8],
     bank: u8,
-
+    // DMG games on a Gameboy Color can't switch banks
+    cgb_mode: bool,
 }
 
 impl CGBRAM {
-    pub fn new() -> Self {
+    pub fn new(cgb_mode: bool) -> Self {
         Self {
             data: [0; 4096 * 8],
             bank: 1,
+            cgb_mode,
         }
     }
 
     fn switch_bank(&mut self, bank: u8) {
+        if !self.cgb_mode {
+            return;
+        }
         self.bank = bank;
         if self.bank > 7 {
             self.bank = 7;
@@ -73,7 +95,10 @@ impl CGBRAM {
 impl RAM for CGBRAM {
     fn read(&self, address: u16) -> u8 {
         if address == WRAM_BANK_SELECT_ADDRESS {
-            return self.bank;
+            return match self.cgb_mode {
+                true => self.bank,
+                false => 0xFF,
+            };
         }
         let address = parse_echo_ram_address(address);
         if address <= 0xCFFF {
@@ -92,4 +117,59 @@ impl RAM for CGBRAM {
         }
         self.data[((address - 0xD000) as usize) + (4096 * (self.bank as usize))] = value;
     }
+
+    fn bank(&self) -> u8 {
+        self.bank
+    }
+
+    fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    fn data_mut(&mut self) -> &mut [u8] {
+        &mut self.data
+    }
+}
+
+impl SaveState for DMGRAM {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_bytes(&self.data);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        state.read_bytes(&mut self.data)
+    }
+}
+
+impl SaveState for CGBRAM {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_bytes(&self.data);
+        state.write_u8(self.bank);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        state.read_bytes(&mut self.data)?;
+        // Keep a corrupted bank number inside the 8 banks like switch_bank does
+        self.bank = state.read_u8()?.clamp(1, 7);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_load_state_clamps_bank() {
+        let mut ram = CGBRAM::new(true);
+        let mut state = StateWriter::new();
+        ram.save_state(&mut state);
+        let mut data = state.into_bytes();
+        *data.last_mut().unwrap() = 0xFF;
+
+        ram.load_state(&mut StateReader::new(&data)).unwrap();
+        assert_eq!(ram.bank(), 7);
+        ram.write(0xDFFF, 0x42);
+        assert_eq!(ram.read(0xDFFF), 0x42);
+    }
 }
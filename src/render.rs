@@ -1,8 +1,14 @@
 use crate::emulator::Emulator;
+use crate::config::CliOptions;
 use crate::frames::Frames;
+use crate::gdb::GdbStub;
+use crate::movie::{Movie, MovieMode};
+use crate::palette::{Palettes, PRESETS};
+use crate::rewind::Rewind;
 use crate::ppu::{WIDTH, HEIGHT};
 
 use std::env;
+use std::fs;
 use log::error;
 use pixels::{wgpu, Pixels, PixelsBuilder, SurfaceTexture};
 use winit::dpi::LogicalSize;
@@ -43,8 +49,117 @@ pub fn create_window<T>(width: u32, height: u32, title: String, event_loop: &Eve
         .unwrap()
 }
 
-pub fn start_eventloop() {
-    let mut emulator = Emulator::new();
+const STATE_SLOT_KEYS: [VirtualKeyCode; 9] = [
+    VirtualKeyCode::F1,
+    VirtualKeyCode::F2,
+    VirtualKeyCode::F3,
+    VirtualKeyCode::F4,
+    VirtualKeyCode::F5,
+    VirtualKeyCode::F6,
+    VirtualKeyCode::F7,
+    VirtualKeyCode::F8,
+    VirtualKeyCode::F9,
+];
+
+fn state_slot_filename(emulator: &Emulator, slot: usize) -> String {
+    format!("{}.ss{}", emulator.rom_info().filename(), slot)
+}
+
+// Shift + F1..F9 saves the state into that slot, F1..F9 alone loads it back.
+// Loading one while a movie plays takes over the recording if there is somewhere to save it
+fn handle_state_slots(emulator: &mut Emulator, input: &WinitInputHelper, recording: bool) {
+    for (index, key) in STATE_SLOT_KEYS.iter().enumerate() {
+        if !input.key_pressed(*key) {
+            continue;
+        }
+        let slot = index + 1;
+        let filename = state_slot_filename(emulator, slot);
+        match input.held_shift() {
+            true => match fs::write(&filename, emulator.save_state()) {
+                Ok(_) => println!("Saved state to slot {}", slot),
+                Err(err) => eprintln!("Could not save state to {}: {}", filename, err),
+            },
+            false => match fs::read(&filename) {
+                Ok(data) => match emulator.load_state(&data) {
+                    Ok(_) => {
+                        println!("Loaded state from slot {}", slot);
+                        if recording && emulator.rerecord_movie() {
+                            println!("Recording the movie from frame {}", emulator.frame_count());
+                        }
+                    },
+                    Err(err) => eprintln!("Could not load state from {}: {}", filename, err),
+                },
+                Err(err) => eprintln!("Could not read {}: {}", filename, err),
+            },
+        };
+    }
+}
+
+// Goes to the next preset, or the first one from a custom palette
+fn switch_palette(emulator: &mut Emulator) {
+    // Gameboy Color models have their own colors
+    if emulator.model().is_cgb() {
+        return;
+    }
+    let current = emulator.dmg_palettes();
+    let next = PRESETS.iter()
+        .position(|(_, colors)| Palettes::uniform(*colors) == current)
+        .map_or(0, |index| (index + 1) % PRESETS.len());
+    let (name, colors) = PRESETS[next];
+    emulator.set_dmg_palettes(Palettes::uniform(colors));
+    println!("Palette: {}", name);
+}
+
+fn start_movie(emulator: &mut Emulator, play_movie: &Option<String>, record_movie: &Option<String>) {
+    let result = match (play_movie, record_movie) {
+        (Some(filename), _) => Movie::load(filename).and_then(|movie| emulator.play_movie(movie)),
+        (None, Some(_)) => emulator.record_movie(),
+        (None, None) => return,
+    };
+    if let Err(err) = result {
+        eprintln!("Could not start the movie: {}", err);
+        std::process::exit(1);
+    }
+}
+
+fn close(emulator: &mut Emulator, record_movie: &Option<String>) {
+    if let Some(filename) = record_movie {
+        let movie = emulator.stop_movie().filter(|movie| !movie.frames.is_empty());
+        if let Some(movie) = movie {
+            match movie.save(filename) {
+                Ok(_) => println!("Saved {} frames of input to {}", movie.frames.len(), filename),
+                Err(err) => eprintln!("Could not save the movie to {}: {}", filename, err),
+            };
+        }
+    }
+    emulator.close();
+}
+
+pub fn start_eventloop(options: CliOptions) {
+    let mut emulator = match Emulator::from_file(&options.rom_path, options.config) {
+        Ok(emulator) => emulator,
+        Err(err) => {
+            eprintln!("Could not read ROM: {}", err);
+            std::process::exit(1);
+        },
+    };
+    start_movie(&mut emulator, &options.play_movie, &options.record_movie);
+    let record_movie = options.record_movie.clone();
+    let mut rewind = match options.rewind.seconds {
+        0 => None,
+        _ => Some(Rewind::new(options.rewind.clone())),
+    };
+    let mut rewinding = false;
+    let mut gdb = options.gdb_port.and_then(|port| match GdbStub::bind(("127.0.0.1", port)) {
+        Ok(gdb) => {
+            println!("Waiting for GDB on port {}", port);
+            Some(gdb)
+        },
+        Err(err) => {
+            eprintln!("Could not listen for GDB on port {}: {}", port, err);
+            None
+        },
+    });
     let mut frame_counter = Frames::new();
     let mut frame_limit = Frames::new();
 
@@ -62,12 +177,21 @@ pub fn start_eventloop() {
         if input.update(&event) {
             // Close events
             if input.key_pressed(VirtualKeyCode::Escape) || input.quit() {
-                emulator.close();
+                close(&mut emulator, &record_movie);
                 *control_flow = ControlFlow::Exit;
                 return;
             }
 
             emulator.handle_input(&input);
+            handle_state_slots(&mut emulator, &input, record_movie.is_some());
+            if input.key_pressed(VirtualKeyCode::F12) {
+                emulator.pause();
+            }
+            if input.key_pressed(VirtualKeyCode::P) {
+                switch_palette(&mut emulator);
+            }
+            // Holding Backspace plays the game backwards
+            rewinding = rewind.is_some() && input.key_held(VirtualKeyCode::Back);
 
             // Resize the window
             if let Some(size) = input.window_resized() {
@@ -84,7 +208,40 @@ pub fn start_eventloop() {
                 *control_flow = ControlFlow::Exit
             },
             Event::MainEventsCleared => {
-                emulator.run_frame(pixels.get_frame());
+                if let Some(gdb) = gdb.as_mut() {
+                    if let Err(err) = gdb.update(&mut emulator) {
+                        eprintln!("GDB connection error: {}", err);
+                    }
+                }
+                let gdb_connected = gdb.as_ref().is_some_and(|gdb| gdb.is_connected());
+                // The window stops updating while the debugger waits for commands
+                if emulator.is_paused() && !gdb_connected && !emulator.debugger_prompt() {
+                    close(&mut emulator, &record_movie);
+                    *control_flow = ControlFlow::Exit;
+                    return;
+                }
+                match (rewinding, rewind.as_mut()) {
+                    (true, Some(rewind)) => if let Err(err) = rewind.step_back(&mut emulator, pixels.get_frame()) {
+                        eprintln!("Could not rewind: {}", err);
+                        rewind.clear();
+                    },
+                    (_, rewind) => {
+                        emulator.run_frame(pixels.get_frame());
+                        // Nothing to keep if a breakpoint stopped it
+                        if let Some(rewind) = rewind.filter(|_| !emulator.is_paused()) {
+                            rewind.push(&emulator, pixels.get_frame());
+                        }
+                    },
+                };
+                if emulator.movie_mode() == Some(MovieMode::Finished) {
+                    match record_movie.is_some() && emulator.rerecord_movie() {
+                        true => println!("Movie finished, recording from frame {}", emulator.frame_count()),
+                        false => {
+                            println!("Movie finished");
+                            emulator.stop_movie();
+                        },
+                    };
+                }
                 frame_counter.increment();
                 if frame_counter.elapsed_ms() >= 1000 {
                     window.set_title(&format!("rmg-001 (FPS: {})", frame_counter.count()));
@@ -93,9 +250,11 @@ pub fn start_eventloop() {
                 }
                 window.request_redraw();
                 if !is_fps_unlocked() {
-                    frame_limit.limit();
+                    match emulator.is_audio_synced() {
+                        true => emulator.sync_audio(),
+                        false => frame_limit.limit(),
+                    };
                 }
-                frame_limit.reset_timer();
             },
             Event::RedrawRequested(_) => {
                 if pixels
@@ -0,0 +1,298 @@
+// Rewind buffer: a snapshot of the machine and the frame buffer every few frames.
+// Only the newest snapshot is kept whole, each older one is stored as the XOR
+// against the one after it, which is mostly zeros and compresses to almost nothing.
+use std::collections::VecDeque;
+use crate::emulator::Emulator;
+use crate::savestate::StateError;
+
+const FRAMES_PER_SECOND: u32 = 60;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RewindOptions {
+    // How far back it can go, 0 disables rewinding
+    pub seconds: u32,
+    // Frames between snapshots
+    pub interval: u32,
+    // The oldest snapshots are dropped above this many bytes
+    pub memory_limit: usize,
+}
+
+impl RewindOptions {
+    pub fn new() -> Self {
+        Self {
+            seconds: 10,
+            interval: 1,
+            memory_limit: 64 * 1024 * 1024,
+        }
+    }
+
+    // Number of snapshots to cover the seconds
+    pub fn capacity(&self) -> usize {
+        (self.seconds.saturating_mul(FRAMES_PER_SECOND) / self.interval.max(1)).max(1) as usize
+    }
+}
+
+impl Default for RewindOptions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn write_length(output: &mut Vec<u8>, mut length: usize) {
+    while length >= 0x80 {
+        output.push((length as u8 & 0x7F) | 0x80);
+        length >>= 7;
+    }
+    output.push(length as u8);
+}
+
+fn read_length(input: &[u8], position: &mut usize) -> usize {
+    let mut length = 0;
+    let mut shift = 0;
+    while let Some(byte) = input.get(*position) {
+        *position += 1;
+        length |= ((byte & 0x7F) as usize) << shift;
+        if byte & 0x80 == 0 {
+            break;
+        }
+        shift += 7;
+    }
+    length
+}
+
+// XOR of two snapshots of the same size, as runs of unchanged bytes followed by
+// changed ones: unchanged count, changed count, then the changed bytes XORed
+fn compress(previous: &[u8], current: &[u8]) -> Vec<u8> {
+    let mut output = Vec::new();
+    let length = current.len();
+    let same = |index: usize| previous[index] == current[index];
+    let mut index = 0;
+    while index < length {
+        let unchanged = index;
+        while index < length && same(index) {
+            index += 1;
+        }
+        let changed = index;
+        // A single unchanged byte costs less inside the changed run than starting a new one
+        while index < length && !(same(index) && (index + 1 >= length || same(index + 1))) {
+            index += 1;
+        }
+        write_length(&mut output, changed - unchanged);
+        write_length(&mut output, index - changed);
+        output.extend((changed..index).map(|offset| previous[offset] ^ current[offset]));
+    }
+    output
+}
+
+// Turns a snapshot into the one the delta was made against, or the other way around
+fn apply_delta(delta: &[u8], data: &mut [u8]) {
+    let mut position = 0;
+    let mut index = 0;
+    while position < delta.len() {
+        index += read_length(delta, &mut position);
+        let changed = read_length(delta, &mut position);
+        for byte in &mut data[index..index + changed] {
+            *byte ^= delta[position];
+            position += 1;
+        }
+        index += changed;
+    }
+}
+
+pub struct Rewind {
+    options: RewindOptions,
+    // Newest snapshot, empty when there are none
+    latest: Vec<u8>,
+    // Going back from `latest`, the last one is the closest to it
+    deltas: VecDeque<Vec<u8>>,
+    deltas_size: usize,
+    // Since `latest` was taken or restored
+    frames: u32,
+}
+
+impl Rewind {
+    pub fn new(options: RewindOptions) -> Self {
+        Self {
+            options,
+            latest: Vec::new(),
+            deltas: VecDeque::new(),
+            deltas_size: 0,
+            frames: 0,
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.latest.clear();
+        self.deltas.clear();
+        self.deltas_size = 0;
+        self.frames = 0;
+    }
+
+    pub fn len(&self) -> usize {
+        match self.latest.is_empty() {
+            true => 0,
+            false => self.deltas.len() + 1,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.latest.is_empty()
+    }
+
+    // Bytes used by the snapshots
+    pub fn memory(&self) -> usize {
+        self.latest.len() + self.deltas_size
+    }
+
+    // Called after every frame, takes a snapshot every `interval` frames
+    pub fn push(&mut self, emulator: &Emulator, frame_buffer: &[u8]) {
+        self.frames += 1;
+        if self.frames < self.options.interval {
+            return;
+        }
+        self.frames = 0;
+        let mut snapshot = emulator.save_state();
+        snapshot.extend_from_slice(frame_buffer);
+        match self.latest.len() == snapshot.len() {
+            true => {
+                let delta = compress(&self.latest, &snapshot);
+                self.deltas_size += delta.len();
+                self.deltas.push_back(delta);
+            },
+            // Nothing to go back to, or a different machine
+            false => self.clear(),
+        };
+        self.latest = snapshot;
+        while !self.deltas.is_empty() && (self.len() > self.options.capacity() || self.memory() > self.options.memory_limit) {
+            if let Some(delta) = self.deltas.pop_front() {
+                self.deltas_size -= delta.len();
+            }
+        }
+    }
+
+    fn pop_latest(&mut self) {
+        match self.deltas.pop_back() {
+            Some(delta) => {
+                self.deltas_size -= delta.len();
+                apply_delta(&delta, &mut self.latest);
+            },
+            None => self.latest.clear(),
+        };
+    }
+
+    // Goes back to the previous snapshot, restoring the frame buffer it had.
+    // Returns false when there is nothing older
+    pub fn step_back(&mut self, emulator: &mut Emulator, frame_buffer: &mut [u8]) -> Result<bool, StateError> {
+        // `latest` is where the emulation is right now
+        if self.frames == 0 {
+            if self.deltas.is_empty() {
+                return Ok(false);
+            }
+            self.pop_latest();
+        }
+        let state_length = match self.latest.len().checked_sub(frame_buffer.len()) {
+            Some(length) => length,
+            None => return Ok(false),
+        };
+        emulator.load_state(&self.latest[..state_length])?;
+        frame_buffer.copy_from_slice(&self.latest[state_length..]);
+        self.frames = 0;
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::joypad::ButtonMask;
+    use crate::rom::tests::rom_with_program;
+
+    fn test_rom() -> Vec<u8> {
+        // Copies the action buttons into the background palette all the time
+        rom_with_program(&[0x3E, 0x10, 0xE0, 0x00, 0xF0, 0x00, 0xE0, 0x47, 0x18, 0xFA])
+    }
+
+    #[test]
+    fn test_capacity() {
+        assert_eq!(RewindOptions::new().capacity(), 600);
+        let options = RewindOptions {
+            seconds: u32::MAX,
+            ..RewindOptions::new()
+        };
+        assert_eq!(options.capacity(), u32::MAX as usize);
+    }
+
+    #[test]
+    fn test_delta() {
+        let previous: Vec<u8> = (0..=255).collect();
+        let mut current = previous.clone();
+        current[0] = 0xFF;
+        current[10] = 0x42;
+        current[12] = 0x43;
+        current[255] = 0x00;
+        let delta = compress(&previous, &current);
+        assert!(delta.len() < 16, "{:?}", delta);
+        let mut data = current.clone();
+        apply_delta(&delta, &mut data);
+        assert_eq!(data, previous);
+        apply_delta(&delta, &mut data);
+        assert_eq!(data, current);
+        assert!(compress(&previous, &previous).len() <= 3);
+    }
+
+    #[test]
+    fn test_rewind() {
+        let mut emulator = Emulator::from_rom_bytes(test_rom(), Config::new()).unwrap();
+        let mut frame = vec![0; 144 * 160 * 4];
+        let mut rewind = Rewind::new(RewindOptions {
+            seconds: 1,
+            interval: 2,
+            ..RewindOptions::new()
+        });
+        assert!(!rewind.step_back(&mut emulator, &mut frame).unwrap());
+
+        let mut frames = Vec::new();
+        for index in 0..80 {
+            emulator.set_buttons(match index % 16 < 8 {
+                true => ButtonMask::A,
+                false => ButtonMask::NONE,
+            });
+            emulator.run_frame(&mut frame);
+            rewind.push(&emulator, &frame);
+            frames.push(frame.clone());
+        }
+        assert_eq!(rewind.len(), 30);
+        let full = rewind.latest.len();
+        assert!(rewind.memory() < full * rewind.len() / 2, "{} {}", rewind.memory(), full);
+
+        // Back through the snapshots taken every other frame
+        for expected in (21..78).rev().step_by(2) {
+            assert!(rewind.step_back(&mut emulator, &mut frame).unwrap());
+            assert_eq!(emulator.frame_count(), expected as u64 + 1);
+            assert!(frame == frames[expected]);
+        }
+        assert!(!rewind.step_back(&mut emulator, &mut frame).unwrap());
+        assert_eq!(emulator.frame_count(), 22);
+
+        // Running again continues from there
+        emulator.run_frame(&mut frame);
+        rewind.push(&emulator, &frame);
+        emulator.run_frame(&mut frame);
+        rewind.push(&emulator, &frame);
+        assert_eq!(rewind.len(), 2);
+        assert!(rewind.step_back(&mut emulator, &mut frame).unwrap());
+        assert_eq!(emulator.frame_count(), 22);
+
+        // The memory limit drops the oldest snapshots
+        let mut small = Rewind::new(RewindOptions {
+            memory_limit: full + 1,
+            ..RewindOptions::new()
+        });
+        for _ in 0..3 {
+            emulator.run_frame(&mut frame);
+            small.push(&emulator, &frame);
+        }
+        assert_eq!(small.len(), 1);
+    }
+}
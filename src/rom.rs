@@ -1,3 +1,5 @@
+use std::fmt;
+use log::debug;
 #[cfg(not(test))]
 use std::fs::File;
 #[cfg(not(test))]
@@ -10,21 +12,106 @@ use crate::bus::{
     BANK_SWITCHABLE,
     EXTERNAL_RAM,
 };
+use crate::config::Config;
+use crate::cpu::Cycles;
+use crate::rtc::RealTimeClock;
+#[cfg(not(test))]
+use crate::rtc::{RTC_FOOTER_SIZE, RTC_FOOTER_SIZE_LEGACY};
+use crate::savestate::{SaveState, StateWriter, StateReader, StateError};
 
+pub const TITLE_ADDRESS: u16 = 0x0134;
+pub const NEW_LICENSEE_CODE_ADDRESS: u16 = 0x0144;
 pub const CARTRIDGE_TYPE_ADDRESS: u16 = 0x0147;
 pub const CGB_FLAG_ADDRESS: u16 = 0x0143;
 pub const SGB_FLAG_ADDRESS: u16 = 0x0146;
 pub const RAM_SIZE_ADDRESS: u16 = 0x0149;
 pub const ROM_SIZE_ADDRESS: u16 = 0x0148;
 pub const DESTINATION_CODE_ADDRESS: u16 = 0x014A;
+pub const OLD_LICENSEE_CODE_ADDRESS: u16 = 0x014B;
 pub const HEADER_CHECKSUM_ADDRESS: u16 = 0x014D;
+pub const GLOBAL_CHECKSUM_ADDRESS: u16 = 0x014E;
+
+#[derive(Debug)]
+pub enum RomError {
+    Io(std::io::Error),
+    TruncatedHeader,
+    BadHeaderChecksum,
+    UnknownCartridgeType(u8),
+    UnsupportedMapper(MBC),
+    UnknownRomSize(u8),
+    UnknownRamSize(u8),
+    SizeMismatch { expected: usize, actual: usize },
+    BadBootRomSize(usize),
+}
 
-#[cfg(not(test))]
-fn header_checksum(data: &Vec<u8>) -> bool {
-    if data.len() < HEADER_CHECKSUM_ADDRESS as usize {
-        return false;
+impl fmt::Display for RomError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RomError::Io(err) => write!(f, "{}", err),
+            RomError::TruncatedHeader => write!(f, "File is too small to contain a cartridge header"),
+            RomError::BadHeaderChecksum => write!(f, "Header checksum failed. Is this a Gameboy ROM?"),
+            RomError::UnknownCartridgeType(byte) => write!(f, "Unknown cartridge type {:02X}", byte),
+            RomError::UnsupportedMapper(mbc) => write!(f, "Mapper {:?} is not supported", mbc),
+            RomError::UnknownRomSize(byte) => write!(f, "Unknown ROM size {:02X}", byte),
+            RomError::UnknownRamSize(byte) => write!(f, "Unknown RAM size {:02X}", byte),
+            RomError::SizeMismatch { expected, actual } => write!(f, "The header declares {} bytes of ROM but the file has {}", expected, actual),
+            RomError::BadBootRomSize(size) => write!(f, "A boot ROM has {} or {} bytes, not {}", BOOT_ROM_SIZE, CGB_BOOT_ROM_SIZE, size),
+        }
+    }
+}
+
+impl std::error::Error for RomError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            RomError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for RomError {
+    fn from(err: std::io::Error) -> Self {
+        RomError::Io(err)
+    }
+}
+
+pub const BOOT_ROM_SIZE: usize = 0x100;
+pub const CGB_BOOT_ROM_SIZE: usize = 0x900;
+
+// DMG, MGB and SGB boot ROMs cover 0x0000-0x00FF. The CGB one also covers 0x0200-0x08FF,
+// leaving the cartridge header in between visible
+#[derive(Debug, Clone, PartialEq)]
+pub struct BootRom {
+    data: Vec<u8>,
+}
+
+impl BootRom {
+    pub fn new(data: Vec<u8>) -> Result<Self, RomError> {
+        match data.len() {
+            BOOT_ROM_SIZE | CGB_BOOT_ROM_SIZE => Ok(Self { data }),
+            size => Err(RomError::BadBootRomSize(size)),
+        }
+    }
+
+    pub fn load(filename: &str) -> Result<Self, RomError> {
+        Self::new(std::fs::read(filename)?)
     }
 
+    pub fn is_cgb(&self) -> bool {
+        self.data.len() == CGB_BOOT_ROM_SIZE
+    }
+
+    // None for the addresses it doesn't cover
+    pub fn read(&self, address: u16) -> Option<u8> {
+        match address {
+            0x0000..=0x00FF => Some(self.data[address as usize]),
+            0x0200..=0x08FF if self.is_cgb() => Some(self.data[address as usize]),
+            _ => None,
+        }
+    }
+}
+
+fn header_checksum(data: &[u8]) -> bool {
     let mut checksum: u8 = 0;
     let mut index: u16 = 0x0134;
     while index < HEADER_CHECKSUM_ADDRESS {
@@ -35,7 +122,7 @@ fn header_checksum(data: &Vec<u8>) -> bool {
 }
 
 #[cfg(test)]
-pub fn load_rom(_filename: &str) -> std::io::Result<Box<dyn ROM>> {
+pub fn load_rom(_filename: &str, _config: &Config) -> Result<Box<dyn ROM>, RomError> {
     Ok(Box::new(NoMBC::new(Vec::new(), ROMInfo {
         mbc: MBC::NoMBC,
         filename: "".to_string(),
@@ -50,53 +137,76 @@ pub fn load_rom(_filename: &str) -> std::io::Result<Box<dyn ROM>> {
         ram_banks: 0,
         rom_banks: 2,
         region: Region::NonJapanese,
+        header_checksum: 0,
+        global_checksum: 0,
+        title_checksum: 0,
+        nintendo_licensee: false,
     })))
 }
 
-#[cfg(not(test))]
-pub fn load_rom(filename: &str) -> std::io::Result<Box<dyn ROM>> {
-    let mut file = File::open(filename)?;
-    let mut data = vec![];
-    file.read_to_end(&mut data)?;
+pub fn load_rom_from_bytes(data: Vec<u8>, config: &Config) -> Result<Box<dyn ROM>, RomError> {
+    create_rom(data, "".to_string(), config)
+}
+
+fn create_rom(data: Vec<u8>, filename: String, config: &Config) -> Result<Box<dyn ROM>, RomError> {
+    if data.len() <= HEADER_CHECKSUM_ADDRESS as usize {
+        return Err(RomError::TruncatedHeader);
+    }
     if !header_checksum(&data) {
-        return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "Header checksum failed. Is this a Gameboy ROM?"));
+        return Err(RomError::BadHeaderChecksum);
     }
 
-    let mut info = ROMInfo::from_bytes(&data);
-    info.set_filename(filename.to_string());
-    let info_copy = info.clone();
+    let mut info = ROMInfo::from_bytes(&data)?;
+    info.set_filename(filename);
+    if data.len() < info.rom_size() {
+        return Err(RomError::SizeMismatch { expected: info.rom_size(), actual: data.len() });
+    }
 
-    let mut rom: Box<dyn ROM> = match info.mbc {
+    let rom: Box<dyn ROM> = match info.mbc {
         MBC::NoMBC => Box::new(NoMBC::new(data, info)),
         MBC::MBC1 => Box::new(MBC1::new(data, info)),
         MBC::MBC2 => Box::new(MBC2::new(data, info)),
-        MBC::MBC3 => Box::new(MBC3::new(data, info)),
+        MBC::MBC3 => Box::new(MBC3::new(data, info, config)),
         MBC::MBC5 => Box::new(MBC5::new(data, info)),
-        _ => unimplemented!(),
+        mbc => return Err(RomError::UnsupportedMapper(mbc)),
     };
 
-    #[cfg(not(test))]
-    match load_save(rom.ram_mut(), &info_copy) {
-        Err(err) => eprintln!("Could not load save file: {}", err),
-        _ => {},
-    };
+    Ok(rom)
+}
+
+#[cfg(not(test))]
+pub fn load_rom(filename: &str, config: &Config) -> Result<Box<dyn ROM>, RomError> {
+    let mut file = File::open(filename)?;
+    let mut data = vec![];
+    file.read_to_end(&mut data)?;
+
+    let mut rom = create_rom(data, filename.to_string(), config)?;
+
+    if let Err(err) = load_save(rom.as_mut()) {
+        eprintln!("Could not load save file: {}", err);
+    }
 
     Ok(rom)
 }
 
 #[cfg(not(test))]
-pub fn save_file(ram: &Vec<u8>, info: &ROMInfo) -> std::io::Result<()> {
-    if !info.has_ram || !info.has_battery {
+pub fn save_file(rom: &dyn ROM) -> std::io::Result<()> {
+    let info = rom.info();
+    if !info.has_battery || (!info.has_ram && !info.has_timer) || info.filename.is_empty() {
         return Ok(());
     }
     let mut file = File::create(format!("{}.sav", info.filename))?;
-    file.write_all(ram)?;
+    file.write_all(rom.ram())?;
+    if let Some(rtc) = rom.rtc() {
+        file.write_all(&rtc.footer())?;
+    }
     Ok(())
 }
 
 #[cfg(not(test))]
-pub fn load_save(ram: &mut Vec<u8>, info: &ROMInfo) -> std::io::Result<()> {
-    if !info.has_ram || !info.has_battery {
+pub fn load_save(rom: &mut dyn ROM) -> std::io::Result<()> {
+    let info = rom.info().clone();
+    if !info.has_battery || (!info.has_ram && !info.has_timer) {
         return Ok(());
     }
 
@@ -104,6 +214,8 @@ pub fn load_save(ram: &mut Vec<u8>, info: &ROMInfo) -> std::io::Result<()> {
     let mut data = vec![];
     file.read_to_end(&mut data)?;
 
+    let ram = rom.ram_mut();
+    let ram_size = ram.len();
     let mut index = 0;
     let size = match ram.len() < data.len() {
         true => ram.len(),
@@ -114,11 +226,18 @@ pub fn load_save(ram: &mut Vec<u8>, info: &ROMInfo) -> std::io::Result<()> {
         index += 1;
     } 
 
+    if let Some(rtc) = rom.rtc_mut() {
+        let footer = &data[ram_size.min(data.len())..];
+        if footer.len() == RTC_FOOTER_SIZE || footer.len() == RTC_FOOTER_SIZE_LEGACY {
+            rtc.load_footer(footer);
+        }
+    }
+
     Ok(())
 }
 
-#[derive(Debug, Copy, Clone)]
-enum MBC {
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum MBC {
     NoMBC,
     MBC1,
     MBC2,
@@ -160,6 +279,11 @@ pub struct ROMInfo {
     ram_banks: u8,
     rom_banks: u16,
     region: Region,
+    header_checksum: u8,
+    global_checksum: u16,
+    // Sum of the title bytes, the CGB boot ROM uses it to pick the colors of DMG games
+    title_checksum: u8,
+    nintendo_licensee: bool,
 }
 
 impl ROMInfo {
@@ -175,9 +299,36 @@ impl ROMInfo {
         self.filename = filename;
     }
 
-    pub fn from_bytes(bytes: &[u8]) -> Self {
+    pub fn filename(&self) -> &str {
+        &self.filename
+    }
+
+    pub fn header_checksum(&self) -> u8 {
+        self.header_checksum
+    }
+
+    pub fn global_checksum(&self) -> u16 {
+        self.global_checksum
+    }
+
+    pub fn title(&self) -> &str {
+        &self.title
+    }
+
+    pub fn title_checksum(&self) -> u8 {
+        self.title_checksum
+    }
+
+    pub fn nintendo_licensee(&self) -> bool {
+        self.nintendo_licensee
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, RomError> {
+        if bytes.len() <= HEADER_CHECKSUM_ADDRESS as usize {
+            return Err(RomError::TruncatedHeader);
+        }
         let rom_type = bytes[CARTRIDGE_TYPE_ADDRESS as usize];
-        Self {
+        Ok(Self {
             mbc: match rom_type {
                 0x00 => MBC::NoMBC,
                 0x01 => MBC::MBC1,
@@ -189,6 +340,7 @@ impl ROMInfo {
                 0x09 => MBC::NoMBC,
                 0x0B => MBC::MMM01,
                 0x0C => MBC::MMM01,
+                0x0D => MBC::MMM01,
                 0x0F => MBC::MBC3,
                 0x10 => MBC::MBC3,
                 0x11 => MBC::MBC3,
@@ -206,7 +358,7 @@ impl ROMInfo {
                 0xFD => MBC::BandaiTIMA5,
                 0xFE => MBC::HuC3,
                 0xFF => MBC::HuC1,
-                _ => unreachable!(),
+                _ => return Err(RomError::UnknownCartridgeType(rom_type)),
             },
             filename: "".to_string(),
             region: match bytes[DESTINATION_CODE_ADDRESS as usize] {
@@ -214,7 +366,10 @@ impl ROMInfo {
                 _ => Region::NonJapanese,
             },
             publisher: "".to_string(), // TODO: Extract publisher
-            title: "".to_string(), // TODO: Extract the game title
+            title: bytes[TITLE_ADDRESS as usize..=CGB_FLAG_ADDRESS as usize].iter()
+                .take_while(|byte| (0x20..0x7F).contains(*byte))
+                .map(|byte| *byte as char)
+                .collect(),
             cgb_features: bytes[CGB_FLAG_ADDRESS as usize] == 0x80,
             cgb_only: bytes[CGB_FLAG_ADDRESS as usize] == 0xC0,
             sgb_features: bytes[SGB_FLAG_ADDRESS as usize] == 0x03,
@@ -238,7 +393,7 @@ impl ROMInfo {
                 0x03 => 4,
                 0x04 => 16,
                 0x05 => 8,
-                _ => unreachable!(),
+                byte => return Err(RomError::UnknownRamSize(byte)),
             },
             rom_banks: match bytes[ROM_SIZE_ADDRESS as usize] {
                 0x00 => 2,
@@ -253,9 +408,22 @@ impl ROMInfo {
                 0x52 => 72,
                 0x53 => 80,
                 0x54 => 96,
-                _ => unreachable!(),
+                byte => return Err(RomError::UnknownRomSize(byte)),
             },
-        }
+            header_checksum: bytes[HEADER_CHECKSUM_ADDRESS as usize],
+            global_checksum: u16::from_be_bytes([
+                bytes.get(GLOBAL_CHECKSUM_ADDRESS as usize).copied().unwrap_or(0),
+                bytes.get(GLOBAL_CHECKSUM_ADDRESS as usize + 1).copied().unwrap_or(0),
+            ]),
+            title_checksum: bytes[TITLE_ADDRESS as usize..=CGB_FLAG_ADDRESS as usize].iter()
+                .fold(0, |sum: u8, byte| sum.wrapping_add(*byte)),
+            // 0x33 means the code is in the new licensee field
+            nintendo_licensee: match bytes[OLD_LICENSEE_CODE_ADDRESS as usize] {
+                0x01 => true,
+                0x33 => bytes[NEW_LICENSEE_CODE_ADDRESS as usize..=NEW_LICENSEE_CODE_ADDRESS as usize + 1] == *b"01",
+                _ => false,
+            },
+        })
     }
 
     pub fn rom_size(&self) -> usize {
@@ -267,12 +435,29 @@ impl ROMInfo {
     }
 }
 
-pub trait ROM {
+pub trait ROM: SaveState + Send {
     fn read(&self, address: u16) -> u8;
     fn write(&mut self, address: u16, data: u8);
     fn ram_mut(&mut self) -> &mut Vec<u8>;
     fn ram(&self) -> &Vec<u8>;
     fn info(&self) -> &ROMInfo;
+    // The whole ROM, every bank
+    fn data(&self) -> &[u8];
+
+    fn do_cycles(&mut self, _cycles: Cycles) {}
+
+    // Bank currently mapped at a ROM or external RAM address
+    fn bank(&self, _address: u16) -> u16 {
+        0
+    }
+
+    fn rtc(&self) -> Option<&RealTimeClock> {
+        None
+    }
+
+    fn rtc_mut(&mut self) -> Option<&mut RealTimeClock> {
+        None
+    }
 }
 
 pub struct NoMBC {
@@ -288,8 +473,8 @@ impl NoMBC {
             info,
             ram: Vec::new(),
         };
-        println!("MBC {:?}", rom.info.mbc);
-        println!("Region {:?}", rom.info.region);
+        debug!("MBC {:?}", rom.info.mbc);
+        debug!("Region {:?}", rom.info.region);
 
         rom
     }
@@ -313,9 +498,32 @@ impl ROM for NoMBC {
         &self.ram
     }
 
+    fn data(&self) -> &[u8] {
+        &self.data
+    }
+
     fn info(&self) -> &ROMInfo {
         &self.info
     }
+
+    // rgblink numbers the upper half of a 32KB ROM as bank 1
+    fn bank(&self, address: u16) -> u16 {
+        match BANK_SWITCHABLE.contains(&address) {
+            true => 1,
+            false => 0,
+        }
+    }
+}
+
+impl SaveState for NoMBC {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_vec(&self.ram);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        state.read_vec_into(&mut self.ram)?;
+        Ok(())
+    }
 }
 
 pub struct MBC1 {
@@ -331,12 +539,12 @@ pub struct MBC1 {
 
 impl MBC1 {
     fn new(data: Vec<u8>, info: ROMInfo) -> Self {
-        println!("MBC {:?}", info.mbc);
-        println!("Region {:?}", info.region);
-        println!("Has RAM {}", info.has_ram);
-        println!("Has battery {}", info.has_battery);
-        println!("ROM banks {}", info.rom_banks);
-        println!("RAM banks {}", info.ram_banks);
+        debug!("MBC {:?}", info.mbc);
+        debug!("Region {:?}", info.region);
+        debug!("Has RAM {}", info.has_ram);
+        debug!("Has battery {}", info.has_battery);
+        debug!("ROM banks {}", info.rom_banks);
+        debug!("RAM banks {}", info.ram_banks);
         let ram = vec![0; info.ram_size() as usize];
         let mut bitmask = 0b11111;
         if info.rom_banks <= 2 {
@@ -477,9 +685,46 @@ impl ROM for MBC1 {
         &self.ram
     }
 
+    fn data(&self) -> &[u8] {
+        &self.data
+    }
+
     fn info(&self) -> &ROMInfo {
         &self.info
     }
+
+    fn bank(&self, address: u16) -> u16 {
+        if BANK_SWITCHABLE.contains(&address) {
+            return (self.get_bank_switchable_address(address) / 0x4000) as u16;
+        } else if EXTERNAL_RAM.contains(&address) {
+            return (self.get_ram_address(address) / 0x2000) as u16;
+        }
+        0
+    }
+}
+
+impl SaveState for MBC1 {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_vec(&self.ram);
+        state.write_u16(self.rom_bank);
+        state.write_u8(self.ram_bank);
+        state.write_bool(self.ram_enable);
+        state.write_u8(self.bitmask);
+        state.write_bool(self.banking_mode == BankingMode::Advanced);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        state.read_vec_into(&mut self.ram)?;
+        self.rom_bank = state.read_u16()?;
+        self.ram_bank = state.read_u8()?;
+        self.ram_enable = state.read_bool()?;
+        self.bitmask = state.read_u8()?;
+        self.banking_mode = match state.read_bool()? {
+            true => BankingMode::Advanced,
+            false => BankingMode::Simple,
+        };
+        Ok(())
+    }
 }
 
 pub struct MBC2 {
@@ -492,12 +737,12 @@ pub struct MBC2 {
 
 impl MBC2 {
     fn new(data: Vec<u8>, info: ROMInfo) -> Self {
-        println!("MBC {:?}", info.mbc);
-        println!("Region {:?}", info.region);
-        println!("Has RAM {}", info.has_ram);
-        println!("Has battery {}", info.has_battery);
-        println!("ROM banks {}", info.rom_banks);
-        println!("RAM banks {}", info.ram_banks);
+        debug!("MBC {:?}", info.mbc);
+        debug!("Region {:?}", info.region);
+        debug!("Has RAM {}", info.has_ram);
+        debug!("Has battery {}", info.has_battery);
+        debug!("ROM banks {}", info.rom_banks);
+        debug!("RAM banks {}", info.ram_banks);
         let ram = vec![0; 0x200];
         Self {
             data,
@@ -567,9 +812,35 @@ impl ROM for MBC2 {
         &self.ram
     }
 
+    fn data(&self) -> &[u8] {
+        &self.data
+    }
+
     fn info(&self) -> &ROMInfo {
         &self.info
     }
+
+    fn bank(&self, address: u16) -> u16 {
+        match BANK_SWITCHABLE.contains(&address) {
+            true => self.rom_bank,
+            false => 0,
+        }
+    }
+}
+
+impl SaveState for MBC2 {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_vec(&self.ram);
+        state.write_u16(self.rom_bank);
+        state.write_bool(self.ram_enable);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        state.read_vec_into(&mut self.ram)?;
+        self.rom_bank = state.read_u16()?;
+        self.ram_enable = state.read_bool()?;
+        Ok(())
+    }
 }
 
 pub struct MBC3 {
@@ -582,17 +853,22 @@ pub struct MBC3 {
     map_rtc: bool,
     prev_rtc_latch: u8,
     rtc_register: u8,
+    rtc: Option<RealTimeClock>,
 }
 
 impl MBC3 {
-    fn new(data: Vec<u8>, info: ROMInfo) -> Self {
-        println!("MBC {:?}", info.mbc);
-        println!("Region {:?}", info.region);
-        println!("Has RAM {}", info.has_ram);
-        println!("Has battery {}", info.has_battery);
-        println!("ROM banks {}", info.rom_banks);
-        println!("RAM banks {}", info.ram_banks);
+    fn new(data: Vec<u8>, info: ROMInfo, config: &Config) -> Self {
+        debug!("MBC {:?}", info.mbc);
+        debug!("Region {:?}", info.region);
+        debug!("Has RAM {}", info.has_ram);
+        debug!("Has battery {}", info.has_battery);
+        debug!("ROM banks {}", info.rom_banks);
+        debug!("RAM banks {}", info.ram_banks);
         let ram = vec![0; info.ram_size() as usize];
+        let rtc = match info.has_timer {
+            true => Some(RealTimeClock::new(config.rtc_mode)),
+            false => None,
+        };
         Self {
             data,
             info,
@@ -603,6 +879,7 @@ impl MBC3 {
             map_rtc: false,
             prev_rtc_latch: 0,
             rtc_register: 0x08,
+            rtc,
         }
     }
 
@@ -631,7 +908,10 @@ impl ROM for MBC3 {
                 if !self.ram_timer_enable {
                     return 0xFF;
                 }
-                return 0xFF;
+                return match &self.rtc {
+                    Some(rtc) => rtc.read(self.rtc_register),
+                    None => 0xFF,
+                };
             } else {
                 if !self.ram_timer_enable {
                     return 0xFF;
@@ -665,7 +945,9 @@ impl ROM for MBC3 {
             }
         } else if address >= 0x6000 && address <= 0x7FFF {
             if self.prev_rtc_latch == 0 && data == 1 {
-                // latch
+                if let Some(rtc) = self.rtc.as_mut() {
+                    rtc.latch();
+                }
             }
             self.prev_rtc_latch = data;
         } else if address >= 0xA000 && address <= 0xBFFF {
@@ -674,6 +956,9 @@ impl ROM for MBC3 {
             }
 
             if self.map_rtc {
+                if let Some(rtc) = self.rtc.as_mut() {
+                    rtc.write(self.rtc_register, data);
+                }
             } else {
                 let address = self.get_ram_address(address);
                 if let Some(elem) = self.ram.get_mut(address) {
@@ -691,9 +976,65 @@ impl ROM for MBC3 {
         &self.ram
     }
 
+    fn data(&self) -> &[u8] {
+        &self.data
+    }
+
     fn info(&self) -> &ROMInfo {
         &self.info
     }
+
+    fn bank(&self, address: u16) -> u16 {
+        if BANK_SWITCHABLE.contains(&address) {
+            return self.rom_bank;
+        } else if EXTERNAL_RAM.contains(&address) {
+            return self.ram_bank as u16;
+        }
+        0
+    }
+
+    fn do_cycles(&mut self, cycles: Cycles) {
+        if let Some(rtc) = self.rtc.as_mut() {
+            rtc.do_cycles(cycles);
+        }
+    }
+
+    fn rtc(&self) -> Option<&RealTimeClock> {
+        self.rtc.as_ref()
+    }
+
+    fn rtc_mut(&mut self) -> Option<&mut RealTimeClock> {
+        self.rtc.as_mut()
+    }
+}
+
+impl SaveState for MBC3 {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_vec(&self.ram);
+        state.write_u16(self.rom_bank);
+        state.write_u8(self.ram_bank);
+        state.write_bool(self.ram_timer_enable);
+        state.write_bool(self.map_rtc);
+        state.write_u8(self.prev_rtc_latch);
+        state.write_u8(self.rtc_register);
+        if let Some(rtc) = &self.rtc {
+            rtc.save_state(state);
+        }
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        state.read_vec_into(&mut self.ram)?;
+        self.rom_bank = state.read_u16()?;
+        self.ram_bank = state.read_u8()?;
+        self.ram_timer_enable = state.read_bool()?;
+        self.map_rtc = state.read_bool()?;
+        self.prev_rtc_latch = state.read_u8()?;
+        self.rtc_register = state.read_u8()?;
+        if let Some(rtc) = self.rtc.as_mut() {
+            rtc.load_state(state)?;
+        }
+        Ok(())
+    }
 }
 
 pub struct MBC5 {
@@ -707,12 +1048,12 @@ pub struct MBC5 {
 
 impl MBC5 {
     fn new(data: Vec<u8>, info: ROMInfo) -> Self {
-        println!("MBC {:?}", info.mbc);
-        println!("Region {:?}", info.region);
-        println!("Has RAM {}", info.has_ram);
-        println!("Has battery {}", info.has_battery);
-        println!("ROM banks {}", info.rom_banks);
-        println!("RAM banks {}", info.ram_banks);
+        debug!("MBC {:?}", info.mbc);
+        debug!("Region {:?}", info.region);
+        debug!("Has RAM {}", info.has_ram);
+        debug!("Has battery {}", info.has_battery);
+        debug!("ROM banks {}", info.rom_banks);
+        debug!("RAM banks {}", info.ram_banks);
         let ram = vec![0; info.ram_size() as usize];
         Self {
             data,
@@ -778,7 +1119,124 @@ impl ROM for MBC5 {
         &self.ram
     }
 
+    fn data(&self) -> &[u8] {
+        &self.data
+    }
+
     fn info(&self) -> &ROMInfo {
         &self.info
     }
+
+    fn bank(&self, address: u16) -> u16 {
+        if BANK_SWITCHABLE.contains(&address) {
+            return self.rom_bank;
+        } else if EXTERNAL_RAM.contains(&address) {
+            return self.ram_bank as u16;
+        }
+        0
+    }
+}
+
+impl SaveState for MBC5 {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_vec(&self.ram);
+        state.write_u16(self.rom_bank);
+        state.write_u8(self.ram_bank);
+        state.write_bool(self.ram_enable);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        state.read_vec_into(&mut self.ram)?;
+        self.rom_bank = state.read_u16()?;
+        self.ram_bank = state.read_u8()?;
+        self.ram_enable = state.read_bool()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::rtc::RtcMode;
+
+    /// Fixes up the header checksum after the header bytes were modified.
+    pub(crate) fn set_header_checksum(data: &mut [u8]) {
+        let mut checksum: u8 = 0;
+        for byte in &data[0x0134..HEADER_CHECKSUM_ADDRESS as usize] {
+            checksum = checksum.wrapping_sub(*byte).wrapping_sub(1);
+        }
+        data[HEADER_CHECKSUM_ADDRESS as usize] = checksum;
+    }
+
+    /// A 32 KiB ROM only cartridge with `program` at the entry point.
+    pub(crate) fn rom_with_program(program: &[u8]) -> Vec<u8> {
+        let mut data = vec![0; 0x8000];
+        data[0x0100..0x0100 + program.len()].copy_from_slice(program);
+        set_header_checksum(&mut data);
+        data
+    }
+
+    fn rom_with_header(cartridge_type: u8, rom_size: u8, ram_size: u8) -> Vec<u8> {
+        let mut data = vec![0; 0x8000];
+        data[CARTRIDGE_TYPE_ADDRESS as usize] = cartridge_type;
+        data[ROM_SIZE_ADDRESS as usize] = rom_size;
+        data[RAM_SIZE_ADDRESS as usize] = ram_size;
+        set_header_checksum(&mut data);
+        data
+    }
+
+    #[test]
+    fn test_header_errors() {
+        assert!(matches!(ROMInfo::from_bytes(&[0; 0x100]), Err(RomError::TruncatedHeader)));
+        assert!(matches!(ROMInfo::from_bytes(&rom_with_header(0x04, 0x00, 0x00)), Err(RomError::UnknownCartridgeType(0x04))));
+        assert!(matches!(ROMInfo::from_bytes(&rom_with_header(0x00, 0x42, 0x00)), Err(RomError::UnknownRomSize(0x42))));
+        assert!(matches!(ROMInfo::from_bytes(&rom_with_header(0x00, 0x00, 0x07)), Err(RomError::UnknownRamSize(0x07))));
+        assert!(ROMInfo::from_bytes(&rom_with_header(0x13, 0x00, 0x03)).is_ok());
+    }
+
+    #[test]
+    fn test_load_rom_errors() {
+        let mut data = rom_with_header(0x00, 0x00, 0x00);
+        data[HEADER_CHECKSUM_ADDRESS as usize] ^= 0xFF;
+        assert!(matches!(load_rom_from_bytes(data, &Config::new()), Err(RomError::BadHeaderChecksum)));
+        assert!(matches!(load_rom_from_bytes(rom_with_header(0xFF, 0x00, 0x00), &Config::new()), Err(RomError::UnsupportedMapper(MBC::HuC1))));
+        assert!(matches!(load_rom_from_bytes(rom_with_header(0x01, 0x01, 0x00), &Config::new()), Err(RomError::SizeMismatch { expected: 0x10000, actual: 0x8000 })));
+        assert!(load_rom_from_bytes(rom_with_header(0x01, 0x00, 0x00), &Config::new()).is_ok());
+    }
+
+    #[test]
+    fn test_no_mbc_banks() {
+        let rom = load_rom_from_bytes(rom_with_header(0x00, 0x00, 0x00), &Config::new()).unwrap();
+        assert_eq!(rom.bank(0x0150), 0);
+        assert_eq!(rom.bank(0x4000), 1);
+        assert_eq!(rom.bank(0x7FFF), 1);
+    }
+
+    #[test]
+    fn test_mbc3_rtc() {
+        let mut config = Config::new();
+        config.rtc_mode = RtcMode::Cycles;
+        let mut rom = load_rom_from_bytes(rom_with_header(0x10, 0x00, 0x02), &config).unwrap();
+        rom.write(0x0000, 0x0A);
+        // Select the minutes register and set it
+        rom.write(0x4000, 0x09);
+        rom.write(0xA000, 10);
+        assert_eq!(rom.read(0xA000), 0);
+
+        // 61 seconds later
+        rom.do_cycles(Cycles(4194304.0 * 61.0));
+        rom.write(0x6000, 0x00);
+        rom.write(0x6000, 0x01);
+        assert_eq!(rom.read(0xA000), 11);
+        rom.write(0x4000, 0x08);
+        assert_eq!(rom.read(0xA000), 1);
+
+        // Registers stay latched until the next 0 -> 1 write
+        rom.do_cycles(Cycles(4194304.0));
+        assert_eq!(rom.read(0xA000), 1);
+
+        let footer = rom.rtc().unwrap().footer();
+        assert_eq!(footer[4], 11);
+        assert_eq!(footer[24], 11);
+    }
 }
@@ -0,0 +1,333 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+use crate::cpu::Cycles;
+use crate::savestate::{SaveState, StateWriter, StateReader, StateError};
+
+pub const RTC_SECONDS: u8     = 0x08;
+pub const RTC_MINUTES: u8     = 0x09;
+pub const RTC_HOURS: u8       = 0x0A;
+pub const RTC_DAY_LOW: u8     = 0x0B;
+pub const RTC_DAY_HIGH: u8    = 0x0C;
+
+// Same layout as VBA-M, BGB, SameBoy and others append to the .sav file:
+// 5 current registers + 5 latched registers as u32 LE, followed by a u64 LE unix timestamp
+pub const RTC_FOOTER_SIZE: usize = 48;
+// Older emulators write the timestamp as u32
+pub const RTC_FOOTER_SIZE_LEGACY: usize = 44;
+
+const CYCLES_PER_SECOND: f32 = 4194304.0;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum RtcMode {
+    // Advances following the host clock, also while the emulator is closed
+    WallClock,
+    // Advances with the emulated cycles only, so runs are reproducible
+    Cycles,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Default)]
+struct RtcRegisters {
+    seconds: u8,
+    minutes: u8,
+    hours: u8,
+    days: u16,
+    halt: bool,
+    day_carry: bool,
+}
+
+impl RtcRegisters {
+    fn read(&self, register: u8) -> u8 {
+        match register {
+            RTC_SECONDS => self.seconds,
+            RTC_MINUTES => self.minutes,
+            RTC_HOURS => self.hours,
+            RTC_DAY_LOW => self.days.to_le_bytes()[0],
+            RTC_DAY_HIGH => {
+                ((self.day_carry as u8) << 7) |
+                ((self.halt as u8) << 6) |
+                (self.days.to_le_bytes()[1] & 1)
+            },
+            _ => 0xFF,
+        }
+    }
+
+    fn write(&mut self, register: u8, data: u8) {
+        match register {
+            RTC_SECONDS => self.seconds = data & 0x3F,
+            RTC_MINUTES => self.minutes = data & 0x3F,
+            RTC_HOURS => self.hours = data & 0x1F,
+            RTC_DAY_LOW => self.days = (self.days & 0x100) | data as u16,
+            RTC_DAY_HIGH => {
+                self.days = (self.days & 0xFF) | (((data & 1) as u16) << 8);
+                self.halt = data & 0b0100_0000 != 0;
+                self.day_carry = data & 0b1000_0000 != 0;
+            },
+            _ => {},
+        }
+    }
+
+    fn is_in_range(&self) -> bool {
+        self.seconds < 60 && self.minutes < 60 && self.hours < 24
+    }
+
+    // The counters only carry when they reach exactly 60/60/24,
+    // values written out of range keep counting until their bits overflow
+    fn tick(&mut self) {
+        self.seconds = (self.seconds + 1) & 0x3F;
+        if self.seconds != 60 {
+            return;
+        }
+        self.seconds = 0;
+        self.minutes = (self.minutes + 1) & 0x3F;
+        if self.minutes != 60 {
+            return;
+        }
+        self.minutes = 0;
+        self.hours = (self.hours + 1) & 0x1F;
+        if self.hours != 24 {
+            return;
+        }
+        self.hours = 0;
+        self.days += 1;
+        if self.days > 0x1FF {
+            self.days = 0;
+            self.day_carry = true;
+        }
+    }
+
+    fn advance(&mut self, mut seconds: u64) {
+        if self.halt {
+            return;
+        }
+        while seconds > 0 && !self.is_in_range() {
+            self.tick();
+            seconds -= 1;
+        }
+        if seconds == 0 {
+            return;
+        }
+        let total = self.seconds as u64 +
+            (self.minutes as u64 * 60) +
+            (self.hours as u64 * 3600) +
+            (self.days as u64 * 86400) +
+            seconds;
+        self.seconds = (total % 60) as u8;
+        self.minutes = ((total / 60) % 60) as u8;
+        self.hours = ((total / 3600) % 24) as u8;
+        let days = total / 86400;
+        if days > 0x1FF {
+            self.day_carry = true;
+        }
+        self.days = (days % 0x200) as u16;
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RealTimeClock {
+    mode: RtcMode,
+    current: RtcRegisters,
+    latched: RtcRegisters,
+    cycles: f32,
+    timestamp: u64,
+}
+
+fn unix_timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+impl RealTimeClock {
+    pub fn new(mode: RtcMode) -> Self {
+        Self {
+            mode,
+            current: RtcRegisters::default(),
+            latched: RtcRegisters::default(),
+            cycles: 0.0,
+            timestamp: unix_timestamp(),
+        }
+    }
+
+    pub fn mode(&self) -> RtcMode {
+        self.mode
+    }
+
+    // Catch up with the host clock
+    fn update(&mut self) {
+        if self.mode != RtcMode::WallClock {
+            return;
+        }
+        let now = unix_timestamp();
+        self.current.advance(now.saturating_sub(self.timestamp));
+        self.timestamp = now;
+    }
+
+    pub fn do_cycles(&mut self, cycles: Cycles) {
+        if self.mode != RtcMode::Cycles || self.current.halt {
+            return;
+        }
+        self.cycles += cycles.0;
+        while self.cycles >= CYCLES_PER_SECOND {
+            self.cycles -= CYCLES_PER_SECOND;
+            self.current.tick();
+        }
+    }
+
+    pub fn latch(&mut self) {
+        self.update();
+        self.latched = self.current;
+    }
+
+    pub fn read(&self, register: u8) -> u8 {
+        self.latched.read(register)
+    }
+
+    pub fn write(&mut self, register: u8, data: u8) {
+        self.update();
+        if register == RTC_SECONDS {
+            self.cycles = 0.0;
+        }
+        self.current.write(register, data);
+    }
+
+    pub fn footer(&self) -> [u8; RTC_FOOTER_SIZE] {
+        let mut rtc = self.clone();
+        rtc.update();
+        let mut footer = [0; RTC_FOOTER_SIZE];
+        let registers = [RTC_SECONDS, RTC_MINUTES, RTC_HOURS, RTC_DAY_LOW, RTC_DAY_HIGH];
+        for (index, register) in registers.iter().enumerate() {
+            let current = rtc.current.read(*register) as u32;
+            let latched = rtc.latched.read(*register) as u32;
+            footer[index * 4..index * 4 + 4].copy_from_slice(&current.to_le_bytes());
+            footer[20 + index * 4..20 + index * 4 + 4].copy_from_slice(&latched.to_le_bytes());
+        }
+        footer[40..48].copy_from_slice(&rtc.timestamp.to_le_bytes());
+        footer
+    }
+
+    pub fn load_footer(&mut self, footer: &[u8]) {
+        if footer.len() < RTC_FOOTER_SIZE_LEGACY {
+            return;
+        }
+        let registers = [RTC_SECONDS, RTC_MINUTES, RTC_HOURS, RTC_DAY_LOW, RTC_DAY_HIGH];
+        for (index, register) in registers.iter().enumerate() {
+            self.current.write(*register, footer[index * 4]);
+            self.latched.write(*register, footer[20 + index * 4]);
+        }
+        let mut timestamp = [0; 8];
+        match footer.len() >= RTC_FOOTER_SIZE {
+            true => timestamp.copy_from_slice(&footer[40..48]),
+            false => timestamp[..4].copy_from_slice(&footer[40..44]),
+        };
+        self.timestamp = u64::from_le_bytes(timestamp);
+        self.cycles = 0.0;
+        // The time the game was closed counts too, but only if we follow the host clock
+        match self.mode {
+            RtcMode::WallClock => self.update(),
+            RtcMode::Cycles => self.timestamp = unix_timestamp(),
+        };
+    }
+}
+
+impl SaveState for RtcRegisters {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.seconds);
+        state.write_u8(self.minutes);
+        state.write_u8(self.hours);
+        state.write_u16(self.days);
+        state.write_bool(self.halt);
+        state.write_bool(self.day_carry);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.seconds = state.read_u8()? & 0x3F;
+        self.minutes = state.read_u8()? & 0x3F;
+        self.hours = state.read_u8()? & 0x1F;
+        self.days = state.read_u16()? & 0x1FF;
+        self.halt = state.read_bool()?;
+        self.day_carry = state.read_bool()?;
+        Ok(())
+    }
+}
+
+impl SaveState for RealTimeClock {
+    fn save_state(&self, state: &mut StateWriter) {
+        let mut rtc = self.clone();
+        rtc.update();
+        rtc.current.save_state(state);
+        rtc.latched.save_state(state);
+        state.write_f32(rtc.cycles);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.current.load_state(state)?;
+        self.latched.load_state(state)?;
+        self.cycles = state.read_f32()?;
+        // Loading a state sets the clock back to the saved time
+        self.timestamp = unix_timestamp();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cycles_mode_ticks() {
+        let mut rtc = RealTimeClock::new(RtcMode::Cycles);
+        rtc.write(RTC_SECONDS, 59);
+        rtc.write(RTC_MINUTES, 59);
+        rtc.write(RTC_HOURS, 23);
+        rtc.write(RTC_DAY_LOW, 0xFF);
+        rtc.write(RTC_DAY_HIGH, 0x01);
+        rtc.do_cycles(Cycles(CYCLES_PER_SECOND));
+        rtc.latch();
+        assert_eq!(rtc.read(RTC_SECONDS), 0);
+        assert_eq!(rtc.read(RTC_MINUTES), 0);
+        assert_eq!(rtc.read(RTC_HOURS), 0);
+        assert_eq!(rtc.read(RTC_DAY_LOW), 0);
+        assert_eq!(rtc.read(RTC_DAY_HIGH), 0b1000_0000);
+
+        // Halted clocks don't advance
+        rtc.write(RTC_DAY_HIGH, 0b0100_0000);
+        rtc.do_cycles(Cycles(CYCLES_PER_SECOND * 2.0));
+        rtc.latch();
+        assert_eq!(rtc.read(RTC_SECONDS), 0);
+    }
+
+    #[test]
+    fn test_advance() {
+        let mut registers = RtcRegisters {
+            seconds: 62,
+            ..RtcRegisters::default()
+        };
+        registers.advance(2);
+        assert_eq!(registers.seconds, 0);
+        assert_eq!(registers.minutes, 0);
+        registers.advance(86400 * 3 + 3661);
+        assert_eq!((registers.days, registers.hours, registers.minutes, registers.seconds), (3, 1, 1, 1));
+        registers.advance(86400 * 512);
+        assert_eq!(registers.days, 3);
+        assert!(registers.day_carry);
+    }
+
+    #[test]
+    fn test_footer_roundtrip() {
+        let mut rtc = RealTimeClock::new(RtcMode::Cycles);
+        rtc.write(RTC_MINUTES, 42);
+        rtc.write(RTC_DAY_HIGH, 0x41);
+        rtc.latch();
+        rtc.write(RTC_HOURS, 7);
+        let footer = rtc.footer();
+        assert_eq!(footer[4], 42);
+        assert_eq!(footer[20 + 16], 0x41);
+
+        let mut loaded = RealTimeClock::new(RtcMode::Cycles);
+        loaded.load_footer(&footer);
+        assert_eq!(loaded.current, rtc.current);
+        assert_eq!(loaded.latched, rtc.latched);
+        loaded.load_footer(&footer[..RTC_FOOTER_SIZE_LEGACY]);
+        assert_eq!(loaded.current, rtc.current);
+    }
+}
@@ -0,0 +1,216 @@
+use std::fmt;
+
+pub const STATE_MAGIC: [u8; 4] = *b"RMGS";
+pub const STATE_VERSION: u16 = 8;
+
+#[derive(Debug)]
+pub enum StateError {
+    Io(std::io::Error),
+    BadMagic,
+    UnsupportedVersion(u16),
+    RomMismatch,
+    ModeMismatch,
+    UnexpectedEnd,
+    Invalid(&'static str),
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StateError::Io(err) => write!(f, "{}", err),
+            StateError::BadMagic => write!(f, "Not a save state file"),
+            StateError::UnsupportedVersion(version) => write!(f, "Save state version {} is not supported (expected {})", version, STATE_VERSION),
+            StateError::RomMismatch => write!(f, "Save state belongs to a different ROM"),
+            StateError::ModeMismatch => write!(f, "Save state was created on a different Gameboy model or mode"),
+            StateError::UnexpectedEnd => write!(f, "Save state is truncated"),
+            StateError::Invalid(what) => write!(f, "Save state is corrupted: {}", what),
+        }
+    }
+}
+
+impl std::error::Error for StateError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            StateError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for StateError {
+    fn from(err: std::io::Error) -> Self {
+        StateError::Io(err)
+    }
+}
+
+pub trait SaveState {
+    fn save_state(&self, state: &mut StateWriter);
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError>;
+}
+
+pub struct StateWriter {
+    data: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new() -> Self {
+        Self {
+            data: Vec::new(),
+        }
+    }
+
+    pub fn write_u8(&mut self, val: u8) {
+        self.data.push(val);
+    }
+
+    pub fn write_bool(&mut self, val: bool) {
+        self.write_u8(val as u8);
+    }
+
+    pub fn write_u16(&mut self, val: u16) {
+        self.data.extend_from_slice(&val.to_le_bytes());
+    }
+
+    pub fn write_u32(&mut self, val: u32) {
+        self.data.extend_from_slice(&val.to_le_bytes());
+    }
+
+    pub fn write_u64(&mut self, val: u64) {
+        self.data.extend_from_slice(&val.to_le_bytes());
+    }
+
+    pub fn write_f32(&mut self, val: f32) {
+        self.data.extend_from_slice(&val.to_le_bytes());
+    }
+
+    // Fixed size data, the reader has to know the length beforehand
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        self.data.extend_from_slice(bytes);
+    }
+
+    // Variable size data, prefixed with its length
+    pub fn write_vec(&mut self, bytes: &[u8]) {
+        self.write_u32(bytes.len() as u32);
+        self.write_bytes(bytes);
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+impl Default for StateWriter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub struct StateReader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self {
+            data,
+            position: 0,
+        }
+    }
+
+    fn take(&mut self, length: usize) -> Result<&'a [u8], StateError> {
+        let end = self.position.checked_add(length).ok_or(StateError::UnexpectedEnd)?;
+        let bytes = self.data.get(self.position..end).ok_or(StateError::UnexpectedEnd)?;
+        self.position = end;
+        Ok(bytes)
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8, StateError> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn read_bool(&mut self) -> Result<bool, StateError> {
+        Ok(self.read_u8()? != 0)
+    }
+
+    pub fn read_u16(&mut self) -> Result<u16, StateError> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    pub fn read_u32(&mut self) -> Result<u32, StateError> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    pub fn read_u64(&mut self) -> Result<u64, StateError> {
+        let mut buffer = [0; 8];
+        buffer.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(buffer))
+    }
+
+    pub fn read_f32(&mut self) -> Result<f32, StateError> {
+        let bytes = self.take(4)?;
+        Ok(f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    pub fn read_bytes(&mut self, buffer: &mut [u8]) -> Result<(), StateError> {
+        buffer.copy_from_slice(self.take(buffer.len())?);
+        Ok(())
+    }
+
+    pub fn read_vec(&mut self) -> Result<Vec<u8>, StateError> {
+        let length = self.read_u32()? as usize;
+        Ok(self.take(length)?.to_vec())
+    }
+
+    // Reads a length prefixed block that has to fit exactly in `buffer`
+    pub fn read_vec_into(&mut self, buffer: &mut [u8]) -> Result<(), StateError> {
+        let length = self.read_u32()? as usize;
+        if length != buffer.len() {
+            return Err(StateError::Invalid("memory size mismatch"));
+        }
+        self.read_bytes(buffer)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.position >= self.data.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_writer_reader_roundtrip() {
+        let mut writer = StateWriter::new();
+        writer.write_u8(0xAB);
+        writer.write_bool(true);
+        writer.write_u16(0x1234);
+        writer.write_u32(0xDEADBEEF);
+        writer.write_u64(0x0102030405060708);
+        writer.write_f32(12.5);
+        writer.write_bytes(&[1, 2, 3]);
+        writer.write_vec(&[4, 5]);
+        let data = writer.into_bytes();
+
+        let mut reader = StateReader::new(&data);
+        assert_eq!(reader.read_u8().unwrap(), 0xAB);
+        assert!(reader.read_bool().unwrap());
+        assert_eq!(reader.read_u16().unwrap(), 0x1234);
+        assert_eq!(reader.read_u32().unwrap(), 0xDEADBEEF);
+        assert_eq!(reader.read_u64().unwrap(), 0x0102030405060708);
+        assert_eq!(reader.read_f32().unwrap(), 12.5);
+        let mut buffer = [0; 3];
+        reader.read_bytes(&mut buffer).unwrap();
+        assert_eq!(buffer, [1, 2, 3]);
+        assert_eq!(reader.read_vec().unwrap(), vec![4, 5]);
+        assert!(reader.is_empty());
+        assert!(matches!(reader.read_u8(), Err(StateError::UnexpectedEnd)));
+
+        let mut reader = StateReader::new(&data[data.len() - 6..]);
+        let mut buffer = [0; 3];
+        assert!(matches!(reader.read_vec_into(&mut buffer), Err(StateError::Invalid(_))));
+    }
+}
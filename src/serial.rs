@@ -0,0 +1,209 @@
+use std::sync::{Arc, Mutex};
+use crate::cpu::Cycles;
+use crate::interrupts::{Interrupt, Interrupts};
+use crate::savestate::{SaveState, StateWriter, StateReader, StateError};
+
+pub const SERIAL_DATA_ADDRESS: u16    = 0xFF01;
+pub const SERIAL_CONTROL_ADDRESS: u16 = 0xFF02;
+
+// 8192 Hz, or 262144 Hz with the CGB fast clock
+const CYCLES_PER_BIT: u32 = 512;
+const CYCLES_PER_BIT_FAST: u32 = 16;
+
+// Whatever is plugged into the link port
+pub trait SerialDevice: Send {
+    // Called once a whole byte has been shifted out, returns the byte that got shifted in
+    fn exchange(&mut self, byte: u8) -> u8;
+}
+
+// No cable connected, the data line stays high
+pub struct DisconnectedDevice;
+
+impl SerialDevice for DisconnectedDevice {
+    fn exchange(&mut self, _byte: u8) -> u8 {
+        0xFF
+    }
+}
+
+// Records every transmitted byte, test ROMs usually print their results this way
+pub struct CaptureDevice {
+    bytes: Arc<Mutex<Vec<u8>>>,
+}
+
+impl CaptureDevice {
+    pub fn new() -> Self {
+        Self {
+            bytes: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    // Shared handle to the captured bytes, still readable after the device is handed to the emulator
+    pub fn bytes(&self) -> Arc<Mutex<Vec<u8>>> {
+        self.bytes.clone()
+    }
+}
+
+impl Default for CaptureDevice {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SerialDevice for CaptureDevice {
+    fn exchange(&mut self, byte: u8) -> u8 {
+        self.bytes.lock().unwrap().push(byte);
+        0xFF
+    }
+}
+
+pub struct Serial {
+    data: u8,
+    control: u8,
+    cycles: u32,
+    bits: u8,
+    cgb_mode: bool,
+    device: Box<dyn SerialDevice>,
+}
+
+impl Serial {
+    pub fn new(cgb_mode: bool) -> Self {
+        Self {
+            data: 0,
+            control: 0,
+            cycles: 0,
+            bits: 0,
+            cgb_mode,
+            device: Box::new(DisconnectedDevice),
+        }
+    }
+
+    pub fn set_device(&mut self, device: Box<dyn SerialDevice>) {
+        self.device = device;
+    }
+
+    pub fn is_io_register(address: u16) -> bool {
+        address == SERIAL_DATA_ADDRESS || address == SERIAL_CONTROL_ADDRESS
+    }
+
+    pub fn get_register(&self, address: u16) -> u8 {
+        match address {
+            SERIAL_DATA_ADDRESS => self.data,
+            // The fast clock bit only exists on CGB
+            _ => match self.cgb_mode {
+                true => self.control | 0b0111_1100,
+                false => self.control | 0b0111_1110,
+            },
+        }
+    }
+
+    pub fn set_register(&mut self, address: u16, data: u8) {
+        match address {
+            SERIAL_DATA_ADDRESS => self.data = data,
+            _ => {
+                self.control = match self.cgb_mode {
+                    true => data & 0b1000_0011,
+                    false => data & 0b1000_0001,
+                };
+                self.cycles = 0;
+                self.bits = 0;
+            },
+        };
+    }
+
+    fn is_transferring(&self) -> bool {
+        self.control & 0b1000_0000 != 0
+    }
+
+    fn is_internal_clock(&self) -> bool {
+        self.control & 0b0000_0001 != 0
+    }
+
+    fn cycles_per_bit(&self) -> u32 {
+        match self.control & 0b0000_0010 != 0 {
+            true => CYCLES_PER_BIT_FAST,
+            false => CYCLES_PER_BIT,
+        }
+    }
+
+    // The serial clock comes from the CPU clock, so it runs twice as fast in double speed mode
+    pub fn do_cycles(&mut self, interrupts: &mut Interrupts, cycles: Cycles, double_speed: bool) {
+        // With an external clock the other side drives the transfer, and there is nobody on the other side
+        if !self.is_transferring() || !self.is_internal_clock() {
+            return;
+        }
+        self.cycles += match double_speed {
+            true => cycles.0 as u32 * 2,
+            false => cycles.0 as u32,
+        };
+        let cycles_per_bit = self.cycles_per_bit();
+        while self.cycles >= cycles_per_bit && self.is_transferring() {
+            self.cycles -= cycles_per_bit;
+            self.bits += 1;
+            if self.bits == 8 {
+                self.data = self.device.exchange(self.data);
+                self.control &= 0b0111_1111;
+                self.cycles = 0;
+                self.bits = 0;
+                interrupts.request(Interrupt::Serial);
+            }
+        }
+    }
+}
+
+impl SaveState for Serial {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.data);
+        state.write_u8(self.control);
+        state.write_u32(self.cycles);
+        state.write_u8(self.bits);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.data = state.read_u8()?;
+        self.control = state.read_u8()?;
+        self.cycles = state.read_u32()?;
+        self.bits = state.read_u8()? & 0b111;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interrupts::INTERRUPT_FLAG_ADDRESS;
+
+    #[test]
+    fn test_internal_clock_transfer() {
+        let mut interrupts = Interrupts::new();
+        let mut serial = Serial::new(false);
+        let capture = CaptureDevice::new();
+        let bytes = capture.bytes();
+        serial.set_device(Box::new(capture));
+
+        serial.set_register(SERIAL_DATA_ADDRESS, b'A');
+        serial.set_register(SERIAL_CONTROL_ADDRESS, 0x81);
+        serial.do_cycles(&mut interrupts, Cycles((CYCLES_PER_BIT * 8 - 4) as f32), false);
+        assert!(bytes.lock().unwrap().is_empty());
+        assert_eq!(serial.get_register(SERIAL_CONTROL_ADDRESS), 0xFF);
+
+        serial.do_cycles(&mut interrupts, Cycles(4.0), false);
+        assert_eq!(*bytes.lock().unwrap(), vec![b'A']);
+        assert_eq!(serial.get_register(SERIAL_DATA_ADDRESS), 0xFF);
+        assert_eq!(serial.get_register(SERIAL_CONTROL_ADDRESS), 0x7F);
+        assert!(Interrupt::Serial.get(interrupts.read(INTERRUPT_FLAG_ADDRESS)));
+    }
+
+    #[test]
+    fn test_cgb_fast_clock() {
+        let mut interrupts = Interrupts::new();
+        let mut serial = Serial::new(true);
+        serial.set_register(SERIAL_CONTROL_ADDRESS, 0x83);
+        serial.do_cycles(&mut interrupts, Cycles((CYCLES_PER_BIT_FAST * 8) as f32), false);
+        assert_eq!(serial.get_register(SERIAL_CONTROL_ADDRESS), 0x7F);
+
+        // External clock, nothing happens
+        serial.set_register(SERIAL_CONTROL_ADDRESS, 0x80);
+        serial.do_cycles(&mut interrupts, Cycles((CYCLES_PER_BIT * 16) as f32), false);
+        assert_eq!(serial.get_register(SERIAL_CONTROL_ADDRESS), 0xFC);
+    }
+}
@@ -1,9 +1,7 @@
-use std::env;
 use std::ops::RangeInclusive;
-use std::sync::{Arc, Mutex};
-use cpal::{Stream, StreamConfig, Device, Sample, SampleRate};
-use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
 use crate::cpu::Cycles;
+use crate::config::Config;
+use crate::savestate::{SaveState, StateWriter, StateReader, StateError};
 use crate::utils::join_bytes;
 
 pub const NR10_ADDRESS: u16 = 0xFF10;
@@ -35,6 +33,9 @@ pub const NR52_ADDRESS: u16 = 0xFF26;
 pub const WAVE_PATTERN_RAM: RangeInclusive<u16> = 0xFF30..=0xFF3F;
 
 pub const SAMPLE_RATE: u32 = 48000;
+pub const CHANNELS: usize = 2;
+
+const CPU_FREQUENCY: usize = 4194304;
 
 const WAVE_DUTY_PATTERNS: [[u8; 8]; 4] = [
     [0, 0, 0, 0, 0, 0, 0, 1],
@@ -43,150 +44,979 @@ const WAVE_DUTY_PATTERNS: [[u8; 8]; 4] = [
     [1, 1, 1, 1, 1, 1, 0, 0],
 ];
 
-struct ChannelTwo {
-    #[allow(dead_code)]
-    stream: Stream,
-    buffer: Arc<Mutex<Vec<f32>>>,
-    frequency_timer: u16,
+const NOISE_DIVISORS: [u32; 8] = [8, 16, 32, 48, 64, 80, 96, 112];
+
+// Bits that always read back as 1, from NR10 (0xFF10) to the end of the wave RAM (0xFF3F)
+const READ_MASKS: [u8; 48] = [
+    0x80, 0x3F, 0x00, 0xFF, 0xBF, // NR10 - NR14
+    0xFF, 0x3F, 0x00, 0xFF, 0xBF, // unused, NR21 - NR24
+    0x7F, 0xFF, 0x9F, 0xFF, 0xBF, // NR30 - NR34
+    0xFF, 0xFF, 0x00, 0x00, 0xBF, // unused, NR41 - NR44
+    0x00, 0x00, 0x70,             // NR50 - NR52
+    0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, // unused
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // wave RAM
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+];
+
+struct LengthCounter {
+    enabled: bool,
+    counter: u16,
+    max: u16,
+}
+
+impl LengthCounter {
+    pub fn new(max: u16) -> Self {
+        Self {
+            enabled: false,
+            counter: 0,
+            max,
+        }
+    }
+
+    pub fn load(&mut self, length: u8) {
+        self.counter = self.max - length as u16;
+    }
+
+    pub fn trigger(&mut self) {
+        if self.counter == 0 {
+            self.counter = self.max;
+        }
+    }
+
+    // Returns true when the counter expires and the channel has to be disabled
+    pub fn clock(&mut self) -> bool {
+        if !self.enabled || self.counter == 0 {
+            return false;
+        }
+        self.counter -= 1;
+        self.counter == 0
+    }
+}
+
+struct Envelope {
+    initial_volume: u8,
+    increase: bool,
+    period: u8,
+    timer: u8,
+    volume: u8,
+}
+
+impl Envelope {
+    pub fn new() -> Self {
+        Self {
+            initial_volume: 0,
+            increase: false,
+            period: 0,
+            timer: 0,
+            volume: 0,
+        }
+    }
+
+    pub fn set_register(&mut self, data: u8) {
+        self.initial_volume = data >> 4;
+        self.increase = data & 0b1000 != 0;
+        self.period = data & 0b111;
+    }
+
+    pub fn dac_enabled(&self) -> bool {
+        self.initial_volume != 0 || self.increase
+    }
+
+    pub fn trigger(&mut self) {
+        self.timer = self.period;
+        self.volume = self.initial_volume;
+    }
+
+    pub fn clock(&mut self) {
+        if self.period == 0 {
+            return;
+        }
+        self.timer = self.timer.saturating_sub(1);
+        if self.timer > 0 {
+            return;
+        }
+        self.timer = self.period;
+        if self.increase && self.volume < 15 {
+            self.volume += 1;
+        } else if !self.increase && self.volume > 0 {
+            self.volume -= 1;
+        }
+    }
+}
+
+enum SweepChange {
+    Unchanged,
+    Frequency(u16),
+    Overflow,
+}
+
+struct Sweep {
+    enabled: bool,
+    period: u8,
+    negate: bool,
+    shift: u8,
+    timer: u8,
+    shadow_frequency: u16,
+}
+
+impl Sweep {
+    pub fn new() -> Self {
+        Self {
+            enabled: false,
+            period: 0,
+            negate: false,
+            shift: 0,
+            timer: 0,
+            shadow_frequency: 0,
+        }
+    }
+
+    pub fn set_register(&mut self, data: u8) {
+        self.period = (data >> 4) & 0b111;
+        self.negate = data & 0b1000 != 0;
+        self.shift = data & 0b111;
+    }
+
+    fn reload_timer(&mut self) {
+        self.timer = match self.period {
+            0 => 8,
+            period => period,
+        };
+    }
+
+    fn next_frequency(&self) -> u16 {
+        let change = self.shadow_frequency >> self.shift;
+        match self.negate {
+            true => self.shadow_frequency.wrapping_sub(change),
+            false => self.shadow_frequency + change,
+        }
+    }
+
+    // Returns false if the new frequency overflowed and the channel has to be disabled
+    pub fn trigger(&mut self, frequency: u16) -> bool {
+        self.shadow_frequency = frequency;
+        self.reload_timer();
+        self.enabled = self.period != 0 || self.shift != 0;
+        self.shift == 0 || self.next_frequency() <= 2047
+    }
+
+    pub fn clock(&mut self) -> SweepChange {
+        self.timer = self.timer.saturating_sub(1);
+        if self.timer > 0 {
+            return SweepChange::Unchanged;
+        }
+        self.reload_timer();
+        if !self.enabled || self.period == 0 {
+            return SweepChange::Unchanged;
+        }
+        let frequency = self.next_frequency();
+        if frequency > 2047 {
+            return SweepChange::Overflow;
+        }
+        if self.shift == 0 {
+            return SweepChange::Unchanged;
+        }
+        self.shadow_frequency = frequency;
+        // The overflow check runs a second time with the new frequency
+        if self.next_frequency() > 2047 {
+            return SweepChange::Overflow;
+        }
+        SweepChange::Frequency(frequency)
+    }
+}
+
+struct SquareChannel {
+    enabled: bool,
+    duty: u8,
     duty_position: usize,
-    sample_timer: usize,
-    buffer_pos: usize,
-}
-
-impl ChannelTwo {
-    pub fn new(device: &Device, config: &StreamConfig) -> Self {
-        let mut count: usize = 0;
-        let buffer = Arc::new(Mutex::new(vec![0.0; SAMPLE_RATE as usize]));
-        let buffer_clone = buffer.clone();
-        let stream = device.build_output_stream(&config, move |data: &mut [f32], _| {
-            let b = buffer_clone.lock().unwrap();
-            for sample in data.iter_mut() {
-                *sample = Sample::from(&b[count]);
-                count += 1;
-                if count >= b.len() {
-                    count = 0;
-                }
-            }
-        }, |err| eprintln!("An error occurred on the channel two: {}", err)).unwrap();
-        stream.play().unwrap();
+    frequency: u16,
+    frequency_timer: u16,
+    length: LengthCounter,
+    envelope: Envelope,
+}
 
+impl SquareChannel {
+    pub fn new() -> Self {
         Self {
-            stream,
-            frequency_timer: 0,
+            enabled: false,
+            duty: 0,
             duty_position: 0,
-            sample_timer: 0,
-            buffer_pos: 0,
-            buffer,
+            frequency: 0,
+            frequency_timer: 0,
+            length: LengthCounter::new(64),
+            envelope: Envelope::new(),
         }
     }
 
-    pub fn update_buffer(&mut self, duty_pattern: u8) {
-        let sample = match WAVE_DUTY_PATTERNS[duty_pattern as usize][self.duty_position as usize] {
-            0 => -1.0,
-            1 => 1.0,
-            _ => unreachable!(),
-        };
-        let clone = self.buffer.clone();
-        let mut buffer = clone.lock().unwrap();
-        buffer[self.buffer_pos] = sample;
+    pub fn trigger(&mut self) {
+        self.enabled = self.envelope.dac_enabled();
+        self.length.trigger();
+        self.frequency_timer = (2048 - self.frequency) * 4;
+        self.envelope.trigger();
+    }
 
-        self.buffer_pos += 1;
-        if self.buffer_pos >= buffer.len() {
-            self.buffer_pos = 0;
+    pub fn output(&self) -> Option<u8> {
+        if !self.envelope.dac_enabled() {
+            return None;
+        }
+        if !self.enabled {
+            return Some(0);
         }
+        Some(WAVE_DUTY_PATTERNS[self.duty as usize][self.duty_position] * self.envelope.volume)
     }
 
-    pub fn cycle(&mut self, duty: u8, frequency: u16) {
+    pub fn cycle(&mut self) {
         self.frequency_timer = self.frequency_timer.saturating_sub(1);
         if self.frequency_timer == 0 {
-            self.frequency_timer = (2048 - frequency) * 4;
-            self.duty_position += 1;
-            if self.duty_position > 7 {
-                self.duty_position = 0;
-            }
-        }
-        self.sample_timer = self.sample_timer.saturating_add(SAMPLE_RATE as usize);
-        if self.sample_timer >= 4194304 {
-            self.update_buffer(duty);
-            self.sample_timer -= 4194304;
+            self.frequency_timer = (2048 - self.frequency) * 4;
+            self.duty_position = (self.duty_position + 1) % 8;
         }
     }
 }
 
-pub struct Sound {
-    io_registers: [u8; 48],
-    channel_two: Option<ChannelTwo>,
+struct WaveChannel {
+    enabled: bool,
+    dac_enabled: bool,
+    volume_code: u8,
+    frequency: u16,
+    frequency_timer: u16,
+    position: usize,
+    length: LengthCounter,
 }
 
+impl WaveChannel {
+    pub fn new() -> Self {
+        Self {
+            enabled: false,
+            dac_enabled: false,
+            volume_code: 0,
+            frequency: 0,
+            frequency_timer: 0,
+            position: 0,
+            length: LengthCounter::new(256),
+        }
+    }
 
-/* Sine Wave
-let channel_two = device.build_output_stream(&config, move |data: &mut [f32], _: &cpal::OutputCallbackInfo| {
-    for sample in data.iter_mut() {
-        let y: f32 = ((sound.count / (sample_rate as f32)) * 440.0 * 2.0 * 3.14159).sin().clamp(-1.0, 1.0);
-        *sample = Sample::from(&y);
-        sound.count += 1.0;
+    pub fn trigger(&mut self) {
+        self.enabled = self.dac_enabled;
+        self.length.trigger();
+        self.frequency_timer = (2048 - self.frequency) * 2;
+        self.position = 0;
     }
-}, err_fn).unwrap();
-channel_two.play().unwrap();
-sound.channel_two = Some(channel_two); */
 
-impl Sound {
+    pub fn output(&self, wave_ram: &[u8]) -> Option<u8> {
+        if !self.dac_enabled {
+            return None;
+        }
+        if !self.enabled {
+            return Some(0);
+        }
+        let byte = wave_ram[self.position / 2];
+        let sample = match self.position % 2 {
+            0 => byte >> 4,
+            _ => byte & 0x0F,
+        };
+        Some(match self.volume_code {
+            0 => 0,
+            code => sample >> (code - 1),
+        })
+    }
+
+    pub fn cycle(&mut self) {
+        self.frequency_timer = self.frequency_timer.saturating_sub(1);
+        if self.frequency_timer == 0 {
+            self.frequency_timer = (2048 - self.frequency) * 2;
+            self.position = (self.position + 1) % 32;
+        }
+    }
+}
+
+struct NoiseChannel {
+    enabled: bool,
+    clock_shift: u8,
+    width_mode: bool,
+    divisor_code: u8,
+    frequency_timer: u32,
+    lfsr: u16,
+    length: LengthCounter,
+    envelope: Envelope,
+}
+
+impl NoiseChannel {
     pub fn new() -> Self {
-        if !env::var("SOUND_ENABLE").is_err() {
-            let host = cpal::default_host();
-            let device = host.default_output_device().expect("no output device available");
-            let mut supported_configs_range = device.supported_output_configs()
-                .expect("error while querying configs");
-            let supported_config = supported_configs_range.next()
-                .expect("no supported config?!")
-                .with_sample_rate(SampleRate(SAMPLE_RATE));
-            // let err_fn = |err| eprintln!("an error occurred on the output audio stream: {}", err);
-            let config: StreamConfig = supported_config.into();
-            return Self {
-                io_registers: [0; 48],
-                channel_two: Some(ChannelTwo::new(&device, &config)),
-            };
+        Self {
+            enabled: false,
+            clock_shift: 0,
+            width_mode: false,
+            divisor_code: 0,
+            frequency_timer: 0,
+            lfsr: 0x7FFF,
+            length: LengthCounter::new(64),
+            envelope: Envelope::new(),
+        }
+    }
+
+    pub fn set_register(&mut self, data: u8) {
+        self.clock_shift = data >> 4;
+        self.width_mode = data & 0b1000 != 0;
+        self.divisor_code = data & 0b111;
+    }
+
+    // Up to 112 << 15 cycles, more than a u16 can hold
+    fn period(&self) -> u32 {
+        NOISE_DIVISORS[self.divisor_code as usize] << self.clock_shift
+    }
+
+    pub fn trigger(&mut self) {
+        self.enabled = self.envelope.dac_enabled();
+        self.length.trigger();
+        self.frequency_timer = self.period();
+        self.envelope.trigger();
+        self.lfsr = 0x7FFF;
+    }
+
+    pub fn output(&self) -> Option<u8> {
+        if !self.envelope.dac_enabled() {
+            return None;
+        }
+        if !self.enabled {
+            return Some(0);
+        }
+        Some((!self.lfsr & 1) as u8 * self.envelope.volume)
+    }
+
+    pub fn cycle(&mut self) {
+        // The LFSR isn't clocked at all with shifts 14 and 15
+        if self.clock_shift >= 14 {
+            return;
+        }
+        self.frequency_timer = self.frequency_timer.saturating_sub(1);
+        if self.frequency_timer > 0 {
+            return;
+        }
+        self.frequency_timer = self.period();
+        let xor = (self.lfsr & 1) ^ ((self.lfsr >> 1) & 1);
+        self.lfsr = (self.lfsr >> 1) | (xor << 14);
+        if self.width_mode {
+            self.lfsr = (self.lfsr & !(1 << 6)) | (xor << 6);
+        }
+    }
+}
+
+// Removes the DC offset like the capacitor in the real hardware
+struct HighPassFilter {
+    capacitor: f32,
+    charge_factor: f32,
+}
+
+impl HighPassFilter {
+    pub fn new(sample_rate: u32) -> Self {
+        Self {
+            capacitor: 0.0,
+            charge_factor: 0.999958_f32.powf(CPU_FREQUENCY as f32 / sample_rate as f32),
+        }
+    }
+
+    pub fn filter(&mut self, input: f32, dacs_enabled: bool) -> f32 {
+        if !dacs_enabled {
+            return 0.0;
         }
+        let output = input - self.capacitor;
+        self.capacitor = input - output * self.charge_factor;
+        output
+    }
+}
 
+pub struct Sound {
+    io_registers: [u8; 48],
+    enabled: bool,
+    power: bool,
+    channel_one: SquareChannel,
+    sweep: Sweep,
+    channel_two: SquareChannel,
+    channel_three: WaveChannel,
+    channel_four: NoiseChannel,
+    frame_sequencer_step: u8,
+    prev_div_bit: bool,
+    sample_rate: u32,
+    // Samples generated per second, the sample rate nudged by the dynamic rate control
+    sample_step: usize,
+    sample_timer: usize,
+    left_filter: HighPassFilter,
+    right_filter: HighPassFilter,
+    samples: Vec<f32>,
+}
+
+impl Sound {
+    pub fn new(config: &Config) -> Self {
         Self {
             io_registers: [0; 48],
-            channel_two: None,
+            enabled: config.sound_enabled,
+            power: false,
+            channel_one: SquareChannel::new(),
+            sweep: Sweep::new(),
+            channel_two: SquareChannel::new(),
+            channel_three: WaveChannel::new(),
+            channel_four: NoiseChannel::new(),
+            frame_sequencer_step: 0,
+            prev_div_bit: false,
+            sample_rate: SAMPLE_RATE,
+            sample_step: SAMPLE_RATE as usize,
+            sample_timer: 0,
+            left_filter: HighPassFilter::new(SAMPLE_RATE),
+            right_filter: HighPassFilter::new(SAMPLE_RATE),
+            samples: Vec::new(),
         }
     }
 
-    pub fn channel_two_duty(&self) -> u8 {
-        (self.get_register(NR21_ADDRESS) >> 6) & 0b11
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
     }
 
-    pub fn channel_two_frequency(&self) -> u16 {
-        join_bytes(self.get_register(NR24_ADDRESS), self.get_register(NR23_ADDRESS)) & 0x7F
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.sample_rate = sample_rate;
+        self.sample_step = sample_rate as usize;
+        self.left_filter = HighPassFilter::new(sample_rate);
+        self.right_filter = HighPassFilter::new(sample_rate);
     }
 
     pub fn is_io_register(address: u16) -> bool {
         address >= 0xFF10 && address <= 0xFF3F
     }
 
-    pub fn get_register(&self, address: u16) -> u8 {
+    fn register(&self, address: u16) -> u8 {
         self.io_registers[(address - 0xFF10) as usize]
     }
 
+    pub fn get_register(&self, address: u16) -> u8 {
+        let mask = READ_MASKS[(address - 0xFF10) as usize];
+        if address == NR52_ADDRESS {
+            return mask |
+                ((self.power as u8) << 7) |
+                ((self.channel_four.enabled as u8) << 3) |
+                ((self.channel_three.enabled as u8) << 2) |
+                ((self.channel_two.enabled as u8) << 1) |
+                (self.channel_one.enabled as u8);
+        }
+        self.register(address) | mask
+    }
+
     pub fn set_register(&mut self, address: u16, data: u8) {
+        if address == NR52_ADDRESS {
+            self.set_power(data & 0b1000_0000 != 0);
+            return;
+        }
+        if WAVE_PATTERN_RAM.contains(&address) {
+            self.io_registers[(address - 0xFF10) as usize] = data;
+            return;
+        }
+        // Only the wave RAM and NR52 are writable while the APU is off
+        if !self.power {
+            return;
+        }
         self.io_registers[(address - 0xFF10) as usize] = data;
+
+        match address {
+            NR10_ADDRESS => self.sweep.set_register(data),
+            NR11_ADDRESS => {
+                self.channel_one.duty = data >> 6;
+                self.channel_one.length.load(data & 0x3F);
+            },
+            NR12_ADDRESS => {
+                self.channel_one.envelope.set_register(data);
+                if !self.channel_one.envelope.dac_enabled() {
+                    self.channel_one.enabled = false;
+                }
+            },
+            NR13_ADDRESS | NR14_ADDRESS => {
+                self.channel_one.frequency = self.frequency(NR13_ADDRESS, NR14_ADDRESS);
+                if address == NR14_ADDRESS {
+                    self.channel_one.length.enabled = data & 0b0100_0000 != 0;
+                    if data & 0b1000_0000 != 0 {
+                        self.channel_one.trigger();
+                        if !self.sweep.trigger(self.channel_one.frequency) {
+                            self.channel_one.enabled = false;
+                        }
+                    }
+                }
+            },
+            NR21_ADDRESS => {
+                self.channel_two.duty = data >> 6;
+                self.channel_two.length.load(data & 0x3F);
+            },
+            NR22_ADDRESS => {
+                self.channel_two.envelope.set_register(data);
+                if !self.channel_two.envelope.dac_enabled() {
+                    self.channel_two.enabled = false;
+                }
+            },
+            NR23_ADDRESS | NR24_ADDRESS => {
+                self.channel_two.frequency = self.frequency(NR23_ADDRESS, NR24_ADDRESS);
+                if address == NR24_ADDRESS {
+                    self.channel_two.length.enabled = data & 0b0100_0000 != 0;
+                    if data & 0b1000_0000 != 0 {
+                        self.channel_two.trigger();
+                    }
+                }
+            },
+            NR30_ADDRESS => {
+                self.channel_three.dac_enabled = data & 0b1000_0000 != 0;
+                if !self.channel_three.dac_enabled {
+                    self.channel_three.enabled = false;
+                }
+            },
+            NR31_ADDRESS => self.channel_three.length.load(data),
+            NR32_ADDRESS => self.channel_three.volume_code = (data >> 5) & 0b11,
+            NR33_ADDRESS | NR34_ADDRESS => {
+                self.channel_three.frequency = self.frequency(NR33_ADDRESS, NR34_ADDRESS);
+                if address == NR34_ADDRESS {
+                    self.channel_three.length.enabled = data & 0b0100_0000 != 0;
+                    if data & 0b1000_0000 != 0 {
+                        self.channel_three.trigger();
+                    }
+                }
+            },
+            NR41_ADDRESS => self.channel_four.length.load(data & 0x3F),
+            NR42_ADDRESS => {
+                self.channel_four.envelope.set_register(data);
+                if !self.channel_four.envelope.dac_enabled() {
+                    self.channel_four.enabled = false;
+                }
+            },
+            NR43_ADDRESS => self.channel_four.set_register(data),
+            NR44_ADDRESS => {
+                self.channel_four.length.enabled = data & 0b0100_0000 != 0;
+                if data & 0b1000_0000 != 0 {
+                    self.channel_four.trigger();
+                }
+            },
+            _ => {},
+        };
     }
 
-    pub fn do_cycles(&mut self, cycles: Cycles) {
+    fn frequency(&self, low_address: u16, high_address: u16) -> u16 {
+        join_bytes(self.register(high_address), self.register(low_address)) & 0x7FF
+    }
+
+    fn set_power(&mut self, power: bool) {
+        if self.power && !power {
+            // Turning the APU off clears every register except the wave RAM
+            for register in self.io_registers[..(NR52_ADDRESS - 0xFF10) as usize].iter_mut() {
+                *register = 0;
+            }
+            self.channel_one = SquareChannel::new();
+            self.sweep = Sweep::new();
+            self.channel_two = SquareChannel::new();
+            self.channel_three = WaveChannel::new();
+            self.channel_four = NoiseChannel::new();
+        } else if !self.power && power {
+            self.frame_sequencer_step = 0;
+        }
+        self.power = power;
+    }
+
+    // 1.0 produces exactly `sample_rate` samples per emulated second
+    pub fn set_rate_adjustment(&mut self, ratio: f32) {
+        self.sample_step = (self.sample_rate as f32 * ratio) as usize;
+    }
+
+    pub fn take_samples(&mut self) -> Vec<f32> {
+        std::mem::take(&mut self.samples)
+    }
+
+    // `div` is the timer's internal counter, the frame sequencer steps
+    // every time bit 12 (bit 13 in double speed mode) goes from 1 to 0
+    // The channels always run so the emulated state doesn't depend on whether
+    // sound is enabled, only the sample generation is skipped
+    pub fn do_cycles(&mut self, cycles: Cycles, div: u16, double_speed: bool) {
+        let div_bit = match double_speed {
+            true => div & (1 << 13) != 0,
+            false => div & (1 << 12) != 0,
+        };
+        if self.prev_div_bit && !div_bit && self.power {
+            self.step_frame_sequencer();
+        }
+        self.prev_div_bit = div_bit;
+
         let mut count = 0.0;
         while count < cycles.0 {
             self.cycle();
             count += 1.0;
         }
+
+        // Nobody is draining the samples, keep at most one second of audio around
+        let max_samples = self.sample_rate as usize * CHANNELS;
+        if self.samples.len() > max_samples {
+            let excess = self.samples.len() - max_samples;
+            self.samples.drain(..excess);
+        }
+    }
+
+    fn step_frame_sequencer(&mut self) {
+        if self.frame_sequencer_step & 1 == 0 {
+            self.clock_lengths();
+        }
+        if self.frame_sequencer_step == 2 || self.frame_sequencer_step == 6 {
+            self.clock_sweep();
+        }
+        if self.frame_sequencer_step == 7 {
+            self.channel_one.envelope.clock();
+            self.channel_two.envelope.clock();
+            self.channel_four.envelope.clock();
+        }
+        self.frame_sequencer_step = (self.frame_sequencer_step + 1) % 8;
+    }
+
+    fn clock_lengths(&mut self) {
+        if self.channel_one.length.clock() {
+            self.channel_one.enabled = false;
+        }
+        if self.channel_two.length.clock() {
+            self.channel_two.enabled = false;
+        }
+        if self.channel_three.length.clock() {
+            self.channel_three.enabled = false;
+        }
+        if self.channel_four.length.clock() {
+            self.channel_four.enabled = false;
+        }
+    }
+
+    fn clock_sweep(&mut self) {
+        if !self.channel_one.enabled {
+            return;
+        }
+        match self.sweep.clock() {
+            SweepChange::Frequency(frequency) => {
+                self.channel_one.frequency = frequency;
+                let [high, low] = frequency.to_be_bytes();
+                self.io_registers[(NR13_ADDRESS - 0xFF10) as usize] = low;
+                let nr14 = self.register(NR14_ADDRESS);
+                self.io_registers[(NR14_ADDRESS - 0xFF10) as usize] = (nr14 & 0b1111_1000) | high;
+            },
+            SweepChange::Unchanged => {},
+            SweepChange::Overflow => self.channel_one.enabled = false,
+        };
     }
 
     fn cycle(&mut self) {
-        if self.channel_two.is_some() {
-            let duty = self.channel_two_duty();
-            let frequency = self.channel_two_frequency();
-            self.channel_two.as_mut().unwrap().cycle(duty, frequency);
+        if self.power {
+            self.channel_one.cycle();
+            self.channel_two.cycle();
+            self.channel_three.cycle();
+            self.channel_four.cycle();
+        }
+
+        if !self.enabled {
+            return;
+        }
+        self.sample_timer = self.sample_timer.saturating_add(self.sample_step);
+        if self.sample_timer >= CPU_FREQUENCY {
+            let (left, right) = self.mix();
+            self.samples.push(left);
+            self.samples.push(right);
+            self.sample_timer -= CPU_FREQUENCY;
         }
     }
+
+    fn mix(&mut self) -> (f32, f32) {
+        let wave_ram = &self.io_registers[(*WAVE_PATTERN_RAM.start() - 0xFF10) as usize..];
+        let outputs = [
+            self.channel_one.output(),
+            self.channel_two.output(),
+            self.channel_three.output(wave_ram),
+            self.channel_four.output(),
+        ];
+        let panning = self.register(NR51_ADDRESS);
+        let mut left = 0.0;
+        let mut right = 0.0;
+        let mut dacs_enabled = false;
+        for (index, output) in outputs.iter().enumerate() {
+            // The DAC maps 0..15 to 1.0..-1.0
+            let analog = match output {
+                Some(digital) => {
+                    dacs_enabled = true;
+                    1.0 - (*digital as f32 / 7.5)
+                },
+                None => 0.0,
+            };
+            if panning & (1 << (index + 4)) != 0 {
+                left += analog;
+            }
+            if panning & (1 << index) != 0 {
+                right += analog;
+            }
+        }
+        let master_volume = self.register(NR50_ADDRESS);
+        let left_volume = (((master_volume >> 4) & 0b111) + 1) as f32 / 8.0;
+        let right_volume = ((master_volume & 0b111) + 1) as f32 / 8.0;
+        let dacs_enabled = dacs_enabled && self.power;
+        (
+            self.left_filter.filter(left / 4.0 * left_volume, dacs_enabled),
+            self.right_filter.filter(right / 4.0 * right_volume, dacs_enabled),
+        )
+    }
+}
+
+impl SaveState for LengthCounter {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_bool(self.enabled);
+        state.write_u16(self.counter);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.enabled = state.read_bool()?;
+        self.counter = state.read_u16()?.min(self.max);
+        Ok(())
+    }
+}
+
+impl SaveState for Envelope {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_bytes(&[self.initial_volume, self.increase as u8, self.period, self.timer, self.volume]);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        let mut bytes = [0; 5];
+        state.read_bytes(&mut bytes)?;
+        self.initial_volume = bytes[0] & 0x0F;
+        self.increase = bytes[1] != 0;
+        self.period = bytes[2] & 0b111;
+        self.timer = bytes[3] & 0b111;
+        self.volume = bytes[4] & 0x0F;
+        Ok(())
+    }
+}
+
+impl SaveState for Sweep {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_bool(self.enabled);
+        state.write_bytes(&[self.period, self.negate as u8, self.shift, self.timer]);
+        state.write_u16(self.shadow_frequency);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.enabled = state.read_bool()?;
+        let mut bytes = [0; 4];
+        state.read_bytes(&mut bytes)?;
+        self.period = bytes[0] & 0b111;
+        self.negate = bytes[1] != 0;
+        self.shift = bytes[2] & 0b111;
+        self.timer = bytes[3];
+        self.shadow_frequency = state.read_u16()?;
+        Ok(())
+    }
+}
+
+impl SaveState for SquareChannel {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_bool(self.enabled);
+        state.write_u8(self.duty);
+        state.write_u8(self.duty_position as u8);
+        state.write_u16(self.frequency);
+        state.write_u16(self.frequency_timer);
+        self.length.save_state(state);
+        self.envelope.save_state(state);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.enabled = state.read_bool()?;
+        self.duty = state.read_u8()? & 0b11;
+        self.duty_position = (state.read_u8()? & 0b111) as usize;
+        self.frequency = state.read_u16()? & 0x7FF;
+        self.frequency_timer = state.read_u16()?;
+        self.length.load_state(state)?;
+        self.envelope.load_state(state)
+    }
+}
+
+impl SaveState for WaveChannel {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_bool(self.enabled);
+        state.write_bool(self.dac_enabled);
+        state.write_u8(self.volume_code);
+        state.write_u16(self.frequency);
+        state.write_u16(self.frequency_timer);
+        state.write_u8(self.position as u8);
+        self.length.save_state(state);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.enabled = state.read_bool()?;
+        self.dac_enabled = state.read_bool()?;
+        self.volume_code = state.read_u8()? & 0b11;
+        self.frequency = state.read_u16()? & 0x7FF;
+        self.frequency_timer = state.read_u16()?;
+        self.position = (state.read_u8()? % 32) as usize;
+        self.length.load_state(state)
+    }
+}
+
+impl SaveState for NoiseChannel {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_bool(self.enabled);
+        state.write_u8(self.clock_shift);
+        state.write_bool(self.width_mode);
+        state.write_u8(self.divisor_code);
+        state.write_u32(self.frequency_timer);
+        state.write_u16(self.lfsr);
+        self.length.save_state(state);
+        self.envelope.save_state(state);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.enabled = state.read_bool()?;
+        self.clock_shift = state.read_u8()? & 0x0F;
+        self.width_mode = state.read_bool()?;
+        self.divisor_code = state.read_u8()? & 0b111;
+        self.frequency_timer = state.read_u32()?;
+        self.lfsr = state.read_u16()? & 0x7FFF;
+        self.length.load_state(state)?;
+        self.envelope.load_state(state)
+    }
+}
+
+impl SaveState for Sound {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_bytes(&self.io_registers);
+        state.write_bool(self.power);
+        self.channel_one.save_state(state);
+        self.sweep.save_state(state);
+        self.channel_two.save_state(state);
+        self.channel_three.save_state(state);
+        self.channel_four.save_state(state);
+        state.write_u8(self.frame_sequencer_step);
+        state.write_bool(self.prev_div_bit);
+        state.write_u32(self.sample_timer as u32);
+        state.write_f32(self.left_filter.capacitor);
+        state.write_f32(self.right_filter.capacitor);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        state.read_bytes(&mut self.io_registers)?;
+        self.power = state.read_bool()?;
+        self.channel_one.load_state(state)?;
+        self.sweep.load_state(state)?;
+        self.channel_two.load_state(state)?;
+        self.channel_three.load_state(state)?;
+        self.channel_four.load_state(state)?;
+        self.frame_sequencer_step = state.read_u8()? & 0b111;
+        self.prev_div_bit = state.read_bool()?;
+        self.sample_timer = state.read_u32()? as usize;
+        self.left_filter.capacitor = state.read_f32()?;
+        self.right_filter.capacitor = state.read_f32()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sound() -> Sound {
+        let mut config = Config::new();
+        config.sound_enabled = true;
+        let mut sound = Sound::new(&config);
+        sound.set_register(NR52_ADDRESS, 0x80);
+        sound
+    }
+
+    #[test]
+    fn test_register_read_masks() {
+        let mut sound = sound();
+        sound.set_register(NR11_ADDRESS, 0x00);
+        assert_eq!(sound.get_register(NR11_ADDRESS), 0x3F);
+        assert_eq!(sound.get_register(NR13_ADDRESS), 0xFF);
+        assert_eq!(sound.get_register(NR52_ADDRESS), 0xF0);
+
+        sound.set_register(NR12_ADDRESS, 0xF0);
+        sound.set_register(NR14_ADDRESS, 0x80);
+        assert_eq!(sound.get_register(NR52_ADDRESS), 0xF1);
+
+        // Powering off clears the registers and ignores writes
+        sound.set_register(NR52_ADDRESS, 0x00);
+        sound.set_register(NR12_ADDRESS, 0xF0);
+        assert_eq!(sound.get_register(NR12_ADDRESS), 0x00);
+        assert_eq!(sound.get_register(NR52_ADDRESS), 0x70);
+    }
+
+    #[test]
+    fn test_length_counter_from_div() {
+        let mut sound = sound();
+        sound.set_register(NR22_ADDRESS, 0xF0);
+        // Length of 2 frame sequencer length clocks
+        sound.set_register(NR21_ADDRESS, 62);
+        sound.set_register(NR24_ADDRESS, 0xC0);
+        assert_eq!(sound.get_register(NR52_ADDRESS) & 0b10, 0b10);
+
+        let mut div: u16 = 0;
+        for _ in 0..4 {
+            div = div.wrapping_add(0x1000);
+            sound.do_cycles(Cycles(0.0), div, false);
+        }
+        // Bit 12 fell twice: step 0 clocked the length once, step 1 doesn't clock it
+        assert_eq!(sound.get_register(NR52_ADDRESS) & 0b10, 0b10);
+        for _ in 0..2 {
+            div = div.wrapping_add(0x1000);
+            sound.do_cycles(Cycles(0.0), div, false);
+        }
+        assert_eq!(sound.get_register(NR52_ADDRESS) & 0b10, 0);
+    }
+
+    #[test]
+    fn test_disabled_output_keeps_running() {
+        let mut sound = Sound::new(&Config::new());
+        sound.set_register(NR52_ADDRESS, 0x80);
+        sound.set_register(NR22_ADDRESS, 0xF0);
+        sound.set_register(NR21_ADDRESS, 63);
+        sound.set_register(NR24_ADDRESS, 0xC0);
+        assert_eq!(sound.get_register(NR52_ADDRESS) & 0b10, 0b10);
+
+        sound.do_cycles(Cycles(4.0), 0x1000, false);
+        sound.do_cycles(Cycles(4.0), 0x2000, false);
+        assert_eq!(sound.get_register(NR52_ADDRESS) & 0b10, 0);
+        assert!(sound.take_samples().is_empty());
+    }
+
+    // Cycles until the LFSR changes, up to `limit`
+    fn noise_period(shift_and_divisor: u8, limit: u32) -> Option<u32> {
+        let mut channel = NoiseChannel::new();
+        channel.set_register(shift_and_divisor);
+        channel.trigger();
+        (1..=limit).find(|_| {
+            channel.cycle();
+            channel.lfsr != 0x7FFF
+        })
+    }
+
+    #[test]
+    fn test_noise_long_periods() {
+        assert_eq!(noise_period(0x00, 100), Some(8));
+        // 16 << 12 and 112 << 13 don't fit 16 bits
+        assert_eq!(noise_period(0xC1, 1 << 20), Some(16 << 12));
+        assert_eq!(noise_period(0xB3, 1 << 20), Some(48 << 11));
+        assert_eq!(noise_period(0xD7, 1 << 20), Some(112 << 13));
+    }
+
+    #[test]
+    fn test_noise_stops_with_large_shifts() {
+        assert_eq!(noise_period(0xE0, 1 << 20), None);
+        assert_eq!(noise_period(0xF7, 1 << 20), None);
+    }
+
+    #[test]
+    fn test_stereo_samples() {
+        let mut sound = sound();
+        sound.do_cycles(Cycles(CPU_FREQUENCY as f32 / 100.0), 0, false);
+        let samples = sound.take_samples();
+        assert_eq!(samples.len(), (SAMPLE_RATE / 100) as usize * CHANNELS);
+    }
 }
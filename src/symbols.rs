@@ -0,0 +1,125 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io;
+use std::path::Path;
+
+fn area(address: u16) -> u8 {
+    match address {
+        0x0000..=0x3FFF => 0,
+        0x4000..=0x7FFF => 1,
+        0x8000..=0x9FFF => 2,
+        0xA000..=0xBFFF => 3,
+        0xC000..=0xDFFF => 4,
+        0xFF80..=0xFFFE => 5,
+        _ => 6,
+    }
+}
+
+// Labels from an RGBDS .sym file, one `bank:address name` per line
+#[derive(Debug, Clone, Default)]
+pub struct Symbols {
+    labels: BTreeMap<(u16, u16), String>,
+    addresses: HashMap<String, (u16, u16)>,
+}
+
+impl Symbols {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Comments start with `;`, lines that can't be parsed are skipped
+    pub fn parse(text: &str) -> Self {
+        let mut symbols = Self::new();
+        for line in text.lines() {
+            let line = match line.split_once(';') {
+                Some((line, _)) => line,
+                None => line,
+            };
+            let mut parts = line.split_whitespace();
+            let (location, name) = match (parts.next(), parts.next()) {
+                (Some(location), Some(name)) => (location, name),
+                _ => continue,
+            };
+            let (bank, address) = match location.split_once(':') {
+                Some((bank, address)) => (bank, address),
+                None => continue,
+            };
+            if let (Ok(bank), Ok(address)) = (u16::from_str_radix(bank, 16), u16::from_str_radix(address, 16)) {
+                symbols.insert(bank, address, name);
+            }
+        }
+        symbols
+    }
+
+    pub fn load(filename: &str) -> io::Result<Self> {
+        Ok(Self::parse(&fs::read_to_string(filename)?))
+    }
+
+    // `game.sym` next to `game.gb`, empty if there isn't one
+    pub fn for_rom(rom_filename: &str) -> Self {
+        let filename = Path::new(rom_filename).with_extension("sym");
+        match fs::read_to_string(&filename) {
+            Ok(text) => Self::parse(&text),
+            Err(err) => {
+                if err.kind() != io::ErrorKind::NotFound {
+                    eprintln!("Could not read {}: {}", filename.display(), err);
+                }
+                Self::new()
+            },
+        }
+    }
+
+    pub fn insert(&mut self, bank: u16, address: u16, name: &str) {
+        // Keep the first label when several point to the same place
+        self.labels.entry((bank, address)).or_insert_with(|| name.to_string());
+        self.addresses.insert(name.to_string(), (bank, address));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.labels.is_empty()
+    }
+
+    pub fn label(&self, bank: u16, address: u16) -> Option<&str> {
+        self.labels.get(&(bank, address)).map(|name| name.as_str())
+    }
+
+    // `Main.loop` at the label itself, `Main.loop+3` for an address after it in the same memory area
+    pub fn describe(&self, bank: u16, address: u16) -> Option<String> {
+        let ((label_bank, start), name) = self.labels.range(..=(bank, address)).next_back()?;
+        if *label_bank != bank || area(*start) != area(address) {
+            return None;
+        }
+        match address - start {
+            0 => Some(name.clone()),
+            offset => Some(format!("{}+{:X}", name, offset)),
+        }
+    }
+
+    // Bank and address of a label
+    pub fn address(&self, name: &str) -> Option<(u16, u16)> {
+        self.addresses.get(name).copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_symbols() {
+        let symbols = Symbols::parse("; File generated by rgblink\n00:0150 Main\n00:0158 Main.loop ; inner loop\n01:4000 BankedFunc\nbad line\n00:C000 wCounter\n");
+        assert_eq!(symbols.label(0, 0x0150), Some("Main"));
+        assert_eq!(symbols.label(1, 0x4000), Some("BankedFunc"));
+        assert_eq!(symbols.label(2, 0x4000), None);
+        assert_eq!(symbols.address("Main.loop"), Some((0, 0x0158)));
+        assert_eq!(symbols.address("wCounter"), Some((0, 0xC000)));
+        assert_eq!(symbols.address("line"), None);
+
+        assert_eq!(symbols.describe(0, 0x0158), Some("Main.loop".to_string()));
+        assert_eq!(symbols.describe(0, 0x015A), Some("Main.loop+2".to_string()));
+        assert_eq!(symbols.describe(1, 0x4010), Some("BankedFunc+10".to_string()));
+        assert_eq!(symbols.describe(2, 0x4010), None);
+        assert_eq!(symbols.describe(0, 0x0100), None);
+        assert_eq!(symbols.describe(0, 0xFF80), None);
+    }
+}
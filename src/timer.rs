@@ -1,5 +1,6 @@
 use crate::cpu::Cycles;
 use crate::interrupts::{Interrupt, Interrupts};
+use crate::savestate::{SaveState, StateWriter, StateReader, StateError};
 use crate::utils::{
     BitIndex,
     get_bit,
@@ -109,6 +110,24 @@ impl Timer {
     }
 }
 
+impl SaveState for Timer {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u16(self.divider);
+        state.write_bool(self.prev_result);
+        state.write_bool(self.is_enabled);
+        state.write_u8(self.control);
+        state.write_bytes(&self.io_registers);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.divider = state.read_u16()?;
+        self.prev_result = state.read_bool()?;
+        self.is_enabled = state.read_bool()?;
+        self.control = state.read_u8()?;
+        state.read_bytes(&mut self.io_registers)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
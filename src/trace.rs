@@ -0,0 +1,269 @@
+// Instruction traces in the Gameboy Doctor format, one line per executed instruction:
+// A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:00,C3,13,02
+use std::fs::File;
+use std::io::{self, BufRead, BufWriter, Write};
+use crate::bus::Bus;
+use crate::cpu::{CPU, Register};
+use crate::symbols::Symbols;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceOptions {
+    // `-` writes to stdout
+    pub filename: String,
+    // Only trace instructions between these addresses, both included
+    pub pc_range: Option<(u16, u16)>,
+    // Only trace instructions in this bank
+    pub bank: Option<u16>,
+    // Stop after this many lines
+    pub limit: Option<u64>,
+    // Append the bank, LY, the cycles into the current line and the closest label
+    // from the .sym file, e.g. `BANK:01 LY:90 DOT:0CC LABEL:Main.loop+3`
+    pub extra: bool,
+}
+
+impl TraceOptions {
+    pub fn new(filename: &str) -> Self {
+        Self {
+            filename: filename.to_string(),
+            pc_range: None,
+            bank: None,
+            limit: None,
+            extra: false,
+        }
+    }
+}
+
+pub struct Tracer {
+    output: BufWriter<Box<dyn Write + Send>>,
+    options: TraceOptions,
+    count: u64,
+    symbols: Symbols,
+}
+
+impl Tracer {
+    pub fn new(output: Box<dyn Write + Send>, options: TraceOptions) -> Self {
+        Self {
+            output: BufWriter::new(output),
+            options,
+            count: 0,
+            symbols: Symbols::new(),
+        }
+    }
+
+    pub fn create(options: TraceOptions) -> io::Result<Self> {
+        let output: Box<dyn Write + Send> = match options.filename.as_str() {
+            "-" => Box::new(io::stdout()),
+            filename => Box::new(File::create(filename)?),
+        };
+        Ok(Self::new(output, options))
+    }
+
+    pub fn set_symbols(&mut self, symbols: Symbols) {
+        self.symbols = symbols;
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.options.limit.is_some_and(|limit| self.count >= limit)
+    }
+
+    // Called right before the CPU executes the instruction at PC
+    pub fn trace(&mut self, cpu: &CPU, bus: &Bus) -> io::Result<()> {
+        if self.is_done() {
+            return Ok(());
+        }
+        let registers = cpu.registers();
+        let pc = registers.get(Register::PC);
+        if self.options.pc_range.is_some_and(|(start, end)| pc < start || pc > end) {
+            return Ok(());
+        }
+        let bank = bus.bank(pc);
+        if self.options.bank.is_some_and(|expected| expected != bank) {
+            return Ok(());
+        }
+        write!(self.output, "A:{:02X} F:{:02X} B:{:02X} C:{:02X} D:{:02X} E:{:02X} H:{:02X} L:{:02X} SP:{:04X} PC:{:04X} PCMEM:{:02X},{:02X},{:02X},{:02X}",
+            registers.get(Register::A),
+            registers.get(Register::F),
+            registers.get(Register::B),
+            registers.get(Register::C),
+            registers.get(Register::D),
+            registers.get(Register::E),
+            registers.get(Register::H),
+            registers.get(Register::L),
+            registers.get(Register::SP),
+            pc,
+            bus.peek(pc),
+            bus.peek(pc.wrapping_add(1)),
+            bus.peek(pc.wrapping_add(2)),
+            bus.peek(pc.wrapping_add(3)),
+        )?;
+        if self.options.extra {
+            write!(self.output, " BANK:{:02X} LY:{:02X} DOT:{:03X}", bank, bus.ppu.lcd_y(), bus.ppu.line_cycles())?;
+            if let Some(label) = self.symbols.describe(bank, pc) {
+                write!(self.output, " LABEL:{}", label)?;
+            }
+        }
+        writeln!(self.output)?;
+        self.count += 1;
+        if self.is_done() {
+            self.output.flush()?;
+        }
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.output.flush()
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Divergence {
+    // Starting from 1
+    pub line: usize,
+    pub previous: Option<String>,
+    // None when that file ended first
+    pub trace: Option<String>,
+    pub reference: Option<String>,
+    // Names of the fields that don't match
+    pub fields: Vec<String>,
+}
+
+fn fields(line: &str) -> impl Iterator<Item = (&str, &str)> {
+    line.split_whitespace().filter_map(|field| field.split_once(':'))
+}
+
+// Only the fields found in both lines are compared, so a trace with
+// the extra columns can still be checked against a plain one
+fn different_fields(trace: &str, reference: &str) -> Vec<String> {
+    let mut different = Vec::new();
+    let mut common = false;
+    for (name, value) in fields(trace) {
+        if let Some((_, expected)) = fields(reference).find(|(other, _)| *other == name) {
+            common = true;
+            if expected != value {
+                different.push(name.to_string());
+            }
+        }
+    }
+    // Nothing in common, compare them as plain text
+    if !common && trace.trim() != reference.trim() {
+        different.push("line".to_string());
+    }
+    different
+}
+
+// Finds the first line where the trace doesn't match the reference
+pub fn diff<A: BufRead, B: BufRead>(trace: A, reference: B) -> io::Result<Option<Divergence>> {
+    let mut trace = trace.lines();
+    let mut reference = reference.lines();
+    let mut previous = None;
+    let mut line = 0;
+    loop {
+        line += 1;
+        let (trace_line, reference_line) = match (trace.next().transpose()?, reference.next().transpose()?) {
+            (None, None) => return Ok(None),
+            (Some(trace_line), Some(reference_line)) => (trace_line, reference_line),
+            (trace_line, reference_line) => return Ok(Some(Divergence {
+                line,
+                previous,
+                trace: trace_line,
+                reference: reference_line,
+                fields: Vec::new(),
+            })),
+        };
+        let fields = different_fields(&trace_line, &reference_line);
+        if !fields.is_empty() {
+            return Ok(Some(Divergence {
+                line,
+                previous,
+                trace: Some(trace_line),
+                reference: Some(reference_line),
+                fields,
+            }));
+        }
+        previous = Some(trace_line);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use crate::config::Config;
+    use crate::emulator::Emulator;
+    use crate::rom::tests::rom_with_program;
+
+    #[derive(Clone)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(data)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn test_rom() -> Vec<u8> {
+        // NOP, NOP, JR -4
+        rom_with_program(&[0x00, 0x00, 0x18, 0xFC])
+    }
+
+    fn run_trace(options: TraceOptions) -> Vec<String> {
+        let buffer = SharedBuffer(Arc::new(Mutex::new(Vec::new())));
+        let mut emulator = Emulator::from_rom_bytes(test_rom(), Config::new()).unwrap();
+        emulator.set_symbols(Symbols::parse("00:0101 Loop\n"));
+        emulator.set_tracer(Some(Tracer::new(Box::new(buffer.clone()), options)));
+        let mut frame = vec![0; 144 * 160 * 4];
+        for _ in 0..9 {
+            emulator.step(&mut frame);
+        }
+        emulator.close();
+        let output = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        output.lines().map(|line| line.to_string()).collect()
+    }
+
+    #[test]
+    fn test_trace() {
+        let lines = run_trace(TraceOptions::new("-"));
+        assert_eq!(lines.len(), 9);
+        assert_eq!(lines[0], "A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:00,00,18,FC");
+        assert_eq!(lines[2], "A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0102 PCMEM:18,FC,00,00");
+
+        let lines = run_trace(TraceOptions {
+            pc_range: Some((0x0101, 0x0102)),
+            limit: Some(3),
+            extra: true,
+            ..TraceOptions::new("-")
+        });
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].contains("PC:0101"));
+        assert!(lines[1].contains("PC:0102"));
+        assert!(lines[2].contains("PC:0101"));
+        assert!(lines[0].ends_with("BANK:00 LY:00 DOT:004 LABEL:Loop"));
+        assert!(lines[1].ends_with("LABEL:Loop+1"));
+
+        assert!(run_trace(TraceOptions { bank: Some(1), ..TraceOptions::new("-") }).is_empty());
+    }
+
+    #[test]
+    fn test_diff() {
+        let reference = "A:01 F:B0 SP:FFFE PC:0100\nA:01 F:B0 SP:FFFE PC:0101\nA:02 F:00 SP:FFFE PC:0102\n";
+        assert_eq!(diff(reference.as_bytes(), reference.as_bytes()).unwrap(), None);
+
+        // The extra columns are ignored
+        let trace = "A:01 F:B0 SP:FFFE PC:0100 LY:00\nA:01 F:B0 SP:FFFE PC:0101 LY:00\nA:02 F:80 SP:FFFE PC:0103 LY:00\n";
+        assert_eq!(diff(trace.as_bytes(), reference.as_bytes()).unwrap(), Some(Divergence {
+            line: 3,
+            previous: Some("A:01 F:B0 SP:FFFE PC:0101 LY:00".to_string()),
+            trace: Some("A:02 F:80 SP:FFFE PC:0103 LY:00".to_string()),
+            reference: Some("A:02 F:00 SP:FFFE PC:0102".to_string()),
+            fields: vec!["F".to_string(), "PC".to_string()],
+        }));
+
+        let divergence = diff(&reference.as_bytes()[..26], reference.as_bytes()).unwrap().unwrap();
+        assert_eq!(divergence.line, 2);
+        assert_eq!(divergence.trace, None);
+    }
+}
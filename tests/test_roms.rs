@@ -0,0 +1,162 @@
+// Runs the test ROMs under `roms/` headlessly and checks their results.
+//
+// Blargg's ROMs print their results through the serial port and end with
+// "Passed" or "Failed". Mooneye's ROMs execute `LD B,B` when they are done,
+// with the Fibonacci sequence 3, 5, 8, 13, 21, 34 in B, C, D, E, H, L on success.
+// Mooneye's ROMs aren't included, drop them into `roms/mooneye` to run them too.
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use rmg_001::config::Config;
+use rmg_001::cpu::Register;
+use rmg_001::emulator::Emulator;
+use rmg_001::frames::CPU_FREQUENCY;
+use rmg_001::serial::CaptureDevice;
+
+const FRAME_BUFFER_SIZE: usize = 144 * 160 * 4;
+const LD_B_B: u8 = 0x40;
+const MOONEYE_PASS: [u8; 6] = [3, 5, 8, 13, 21, 34];
+const MOONEYE_FAIL: [u8; 6] = [0x42; 6];
+
+#[derive(Debug, PartialEq)]
+enum TestResult {
+    Passed,
+    Failed(String),
+    Timeout(String),
+}
+
+fn serial_output(bytes: &Arc<Mutex<Vec<u8>>>) -> String {
+    String::from_utf8_lossy(&bytes.lock().unwrap()).to_string()
+}
+
+fn mooneye_signature(emulator: &Emulator) -> [u8; 6] {
+    let registers = emulator.registers();
+    [Register::B, Register::C, Register::D, Register::E, Register::H, Register::L]
+        .map(|register| registers.get(register) as u8)
+}
+
+// Runs the ROM until it reports a result, or gives up after `seconds` of emulated time
+fn run_test_rom(path: &Path, seconds: u64) -> TestResult {
+    let mut emulator = Emulator::from_file(path.to_str().unwrap(), Config::new()).unwrap();
+    let capture = CaptureDevice::new();
+    let bytes = capture.bytes();
+    emulator.set_serial_device(Box::new(capture));
+
+    let mut frame_buffer = vec![0; FRAME_BUFFER_SIZE];
+    let budget = (CPU_FREQUENCY * seconds) as f64;
+    let mut cycles = 0.0;
+    let mut printed = 0;
+    while cycles < budget {
+        let pc = emulator.registers().get(Register::PC);
+        if emulator.peek(pc) == LD_B_B {
+            match mooneye_signature(&emulator) {
+                MOONEYE_PASS => return TestResult::Passed,
+                MOONEYE_FAIL => return TestResult::Failed(serial_output(&bytes)),
+                _ => {},
+            };
+        }
+        cycles += emulator.step(&mut frame_buffer).0 as f64;
+
+        // Only look at the output when something new came through
+        let length = bytes.lock().unwrap().len();
+        if length == printed {
+            continue;
+        }
+        printed = length;
+        let output = serial_output(&bytes);
+        if output.contains("Passed") {
+            return TestResult::Passed;
+        } else if output.contains("Failed") {
+            return TestResult::Failed(output);
+        }
+    }
+    TestResult::Timeout(serial_output(&bytes))
+}
+
+fn assert_passes(path: &str, seconds: u64) {
+    let result = run_test_rom(Path::new(path), seconds);
+    assert_eq!(result, TestResult::Passed, "{}", path);
+}
+
+#[test]
+fn blargg_cpu_instrs_01_special() {
+    assert_passes("roms/cpu_instrs_individual/01-special.gb", 10);
+}
+
+#[test]
+fn blargg_cpu_instrs_02_interrupts() {
+    assert_passes("roms/cpu_instrs_individual/02-interrupts.gb", 10);
+}
+
+#[test]
+fn blargg_cpu_instrs_03_op_sp_hl() {
+    assert_passes("roms/cpu_instrs_individual/03-op sp,hl.gb", 10);
+}
+
+#[test]
+fn blargg_cpu_instrs_04_op_r_imm() {
+    assert_passes("roms/cpu_instrs_individual/04-op r,imm.gb", 10);
+}
+
+#[test]
+fn blargg_cpu_instrs_05_op_rp() {
+    assert_passes("roms/cpu_instrs_individual/05-op rp.gb", 10);
+}
+
+#[test]
+fn blargg_cpu_instrs_06_ld_r_r() {
+    assert_passes("roms/cpu_instrs_individual/06-ld r,r.gb", 10);
+}
+
+#[test]
+fn blargg_cpu_instrs_07_jr_jp_call_ret_rst() {
+    assert_passes("roms/cpu_instrs_individual/07-jr,jp,call,ret,rst.gb", 10);
+}
+
+#[test]
+fn blargg_cpu_instrs_08_misc_instrs() {
+    assert_passes("roms/cpu_instrs_individual/08-misc instrs.gb", 10);
+}
+
+#[test]
+fn blargg_cpu_instrs_09_op_r_r() {
+    assert_passes("roms/cpu_instrs_individual/09-op r,r.gb", 20);
+}
+
+#[test]
+fn blargg_cpu_instrs_10_bit_ops() {
+    assert_passes("roms/cpu_instrs_individual/10-bit ops.gb", 20);
+}
+
+#[test]
+fn blargg_cpu_instrs_11_op_a_hl() {
+    assert_passes("roms/cpu_instrs_individual/11-op a,(hl).gb", 30);
+}
+
+fn find_roms(directory: &Path, roms: &mut Vec<PathBuf>) {
+    let entries = match fs::read_dir(directory) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.is_dir() {
+            find_roms(&path, roms);
+        } else if path.extension().is_some_and(|extension| extension == "gb") {
+            roms.push(path);
+        }
+    }
+}
+
+#[test]
+fn mooneye() {
+    let mut roms = Vec::new();
+    find_roms(Path::new("roms/mooneye"), &mut roms);
+    roms.sort();
+    let failed: Vec<String> = roms.iter()
+        .filter(|rom| run_test_rom(rom, 20) != TestResult::Passed)
+        .map(|rom| rom.display().to_string())
+        .collect();
+    assert!(failed.is_empty(), "{} of {} Mooneye ROMs failed:\n{}", failed.len(), roms.len(), failed.join("\n"));
+}